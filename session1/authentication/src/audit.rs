@@ -0,0 +1,278 @@
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::AuthError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditOutcome {
+    Success,
+    Failure,
+}
+
+impl fmt::Display for AuditOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return f.write_str(match self {
+            AuditOutcome::Success => "success",
+            AuditOutcome::Failure => "failure",
+        });
+    }
+}
+
+impl FromStr for AuditOutcome {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        return match value.to_lowercase().as_str() {
+            "success" => Ok(AuditOutcome::Success),
+            "failure" => Ok(AuditOutcome::Failure),
+            _ => Err(format!("unknown outcome {value}, expected success or failure")),
+        };
+    }
+}
+
+/// One line of the audit log: who (`actor`) did what (`event`) to whom (`target`).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEvent {
+    /// Unix seconds.
+    pub timestamp: u64,
+    pub actor: String,
+    pub event: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    pub outcome: AuditOutcome,
+    /// Context such as the granted role, or why the action failed. Never contains passwords or hashes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl AuditEvent {
+    pub fn success(actor: &str, event: &str, target: Option<&str>) -> Self {
+        return Self {
+            timestamp: crate::unix_now(),
+            actor: actor.to_string(),
+            event: event.to_string(),
+            target: target.map(str::to_string),
+            outcome: AuditOutcome::Success,
+            detail: None,
+        };
+    }
+
+    pub fn with_detail(self, detail: &str) -> Self {
+        return Self { detail: Some(detail.to_string()), ..self };
+    }
+
+    pub fn failure(actor: &str, event: &str, target: Option<&str>, detail: &str) -> Self {
+        return Self {
+            outcome: AuditOutcome::Failure,
+            detail: Some(detail.to_string()),
+            ..Self::success(actor, event, target)
+        };
+    }
+}
+
+/// Which events `AuditLog::read` returns, unset fields match everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuditFilter {
+    pub actor: Option<String>,
+    /// Matches the event name exactly, or every event under a prefix ending in `.` such as `user.`.
+    pub event: Option<String>,
+    pub target: Option<String>,
+    pub outcome: Option<AuditOutcome>,
+    pub since: Option<u64>,
+}
+
+impl AuditFilter {
+    pub fn matches(&self, event: &AuditEvent) -> bool {
+        let same_name = |expected: &Option<String>, actual: Option<&str>| {
+            return expected.as_ref().is_none_or(|expected| {
+                return actual.is_some_and(|actual| crate::normalize_username(actual) == crate::normalize_username(expected));
+            });
+        };
+        let event_matches = self.event.as_ref().is_none_or(|expected| {
+            return match expected.strip_suffix('.') {
+                Some(prefix) => event.event.starts_with(prefix) && event.event[prefix.len()..].starts_with('.'),
+                None => event.event == *expected,
+            };
+        });
+
+        return same_name(&self.actor, Some(&event.actor))
+            && same_name(&self.target, event.target.as_deref())
+            && event_matches
+            && self.outcome.is_none_or(|outcome| outcome == event.outcome)
+            && self.since.is_none_or(|since| event.timestamp >= since);
+    }
+}
+
+/// An append-only file with one JSON `AuditEvent` per line.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        return Self { path: path.into() };
+    }
+
+    /// `audit.jsonl` next to the user store.
+    pub fn next_to(store_path: &Path) -> Self {
+        return Self::new(store_path.with_file_name("audit.jsonl"));
+    }
+
+    pub fn path(&self) -> &Path {
+        return &self.path;
+    }
+
+    pub fn append(&self, event: &AuditEvent) -> Result<(), AuthError> {
+        let mut line = serde_json::to_string(event)?;
+        line.push('\n');
+
+        // A single write of the whole line, so concurrent writers do not interleave.
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        file.write_all(line.as_bytes())?;
+
+        return Ok(());
+    }
+
+    /// Events matching the filter, oldest first. A missing log has no events.
+    pub fn read(&self, filter: &AuditFilter) -> Result<Vec<AuditEvent>, AuthError> {
+        return Ok(self.read_from(0, filter)?.0);
+    }
+
+    /// Like `read`, but starts at a byte offset and also returns the offset after the last
+    /// complete line, to follow a growing log.
+    pub fn read_from(&self, offset: u64, filter: &AuditFilter) -> Result<(Vec<AuditEvent>, u64), AuthError> {
+        use std::io::{Seek, SeekFrom};
+
+        let file = match std::fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok((Vec::new(), offset)),
+            Err(error) => return Err(error.into()),
+        };
+
+        let mut reader = BufReader::new(file);
+        reader.seek(SeekFrom::Start(offset))?;
+
+        let mut events = Vec::new();
+        let mut offset = offset;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            // Stop before a line that is still being written.
+            if read == 0 || !line.ends_with('\n') {
+                break;
+            }
+            offset += read as u64;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let event: AuditEvent = serde_json::from_str(&line).map_err(|error| {
+                return AuthError::CorruptStore(format!("{}: {error}", self.path.display()));
+            })?;
+            if filter.matches(&event) {
+                events.push(event);
+            }
+        }
+
+        return Ok((events, offset));
+    }
+}
+
+static AUDIT_LOG: Lazy<RwLock<Option<AuditLog>>> = Lazy::new(|| {
+    return RwLock::new(None);
+});
+
+/// Sets the log that `login`, `save_users` and `audit` record to. Without one, nothing is recorded.
+pub fn install_audit_log(log: AuditLog) {
+    replace_audit_log(Some(log));
+}
+
+/// Swaps the installed log, returning the previous one so it can be put back.
+pub(crate) fn replace_audit_log(log: Option<AuditLog>) -> Option<AuditLog> {
+    return std::mem::replace(&mut *AUDIT_LOG.write().unwrap(), log);
+}
+
+/// Appends the event to the installed audit log, if any.
+pub fn audit(event: AuditEvent) -> Result<(), AuthError> {
+    return match AUDIT_LOG.read().unwrap().as_ref() {
+        Some(log) => log.append(&event),
+        None => Ok(()),
+    };
+}
+
+/// The operating system user running the process, the actor of administrative events.
+pub fn current_actor() -> String {
+    return std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(timestamp: u64, actor: &str, name: &str, target: &str, outcome: AuditOutcome) -> AuditEvent {
+        return AuditEvent {
+            timestamp,
+            actor: actor.to_string(),
+            event: name.to_string(),
+            target: Some(target.to_string()),
+            outcome,
+            detail: None,
+        };
+    }
+
+    #[test]
+    fn test_audit_log_round_trip_and_filters() {
+        let directory = tempfile::tempdir().unwrap();
+        let log = AuditLog::next_to(&directory.path().join("users.json"));
+        assert!(log.read(&AuditFilter::default()).unwrap().is_empty());
+
+        let events = [
+            event(10, "bob", "login", "bob", AuditOutcome::Success),
+            event(20, "root", "user.delete", "carol", AuditOutcome::Success),
+            event(30, "admin", "login", "admin", AuditOutcome::Failure),
+            event(40, "root", "user.add", "Dave", AuditOutcome::Failure),
+        ];
+        events.iter().for_each(|event| log.append(event).unwrap());
+
+        assert_eq!(log.read(&AuditFilter::default()).unwrap(), events);
+
+        let read = |filter: AuditFilter| log.read(&filter).unwrap().iter().map(|event| event.timestamp).collect::<Vec<_>>();
+        assert_eq!(read(AuditFilter { actor: Some("ROOT".to_string()), ..AuditFilter::default() }), vec![20, 40]);
+        assert_eq!(read(AuditFilter { event: Some("user.".to_string()), ..AuditFilter::default() }), vec![20, 40]);
+        assert_eq!(read(AuditFilter { event: Some("user".to_string()), ..AuditFilter::default() }), Vec::<u64>::new());
+        assert_eq!(read(AuditFilter { target: Some("dave".to_string()), ..AuditFilter::default() }), vec![40]);
+        assert_eq!(read(AuditFilter { outcome: Some(AuditOutcome::Failure), since: Some(35), ..AuditFilter::default() }), vec![40]);
+    }
+
+    #[test]
+    fn test_read_from_skips_partial_lines() {
+        let directory = tempfile::tempdir().unwrap();
+        let log = AuditLog::new(directory.path().join("audit.jsonl"));
+
+        log.append(&event(10, "bob", "login", "bob", AuditOutcome::Success)).unwrap();
+        let (events, offset) = log.read_from(0, &AuditFilter::default()).unwrap();
+        assert_eq!(events.len(), 1);
+
+        let mut file = OpenOptions::new().append(true).open(log.path()).unwrap();
+        file.write_all(br#"{"timestamp": 20, "actor": "#).unwrap();
+        assert_eq!(log.read_from(offset, &AuditFilter::default()).unwrap(), (vec![], offset));
+
+        file.write_all(b"\"bob\", \"event\": \"login\", \"outcome\": \"failure\"}\n").unwrap();
+        let (events, _) = log.read_from(offset, &AuditFilter::default()).unwrap();
+        assert_eq!(events[0].outcome, AuditOutcome::Failure);
+        assert_eq!(events[0].target, None);
+    }
+}
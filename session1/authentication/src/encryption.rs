@@ -0,0 +1,303 @@
+use std::fmt;
+use std::path::Path;
+use std::sync::Mutex;
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::RngCore;
+
+use crate::AuthError;
+
+/// Environment variables `StoreKey::from_env` reads, in order of precedence.
+pub const KEY_ENV: &str = "USERS_STORE_KEY";
+pub const KEY_FILE_ENV: &str = "USERS_STORE_KEY_FILE";
+pub const PASSPHRASE_ENV: &str = "USERS_STORE_PASSPHRASE";
+
+/// Encrypted files start with these bytes, the last one is the format version.
+const MAGIC: &[u8; 8] = b"USRSENC\x01";
+const KDF_RAW_KEY: u8 = 0;
+const KDF_ARGON2ID: u8 = 1;
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 12;
+const KEY_LENGTH: usize = 32;
+/// Limits on the Argon2 parameters read from a file header. The header is only authenticated
+/// after the key is derived, so a modified one must not make deriving take forever.
+const MAX_MEMORY_KIB: u32 = 1024 * 1024;
+const MAX_ITERATIONS: u32 = 64;
+const MAX_PARALLELISM: u32 = 16;
+
+enum Secret {
+    Key([u8; KEY_LENGTH]),
+    Passphrase(String),
+}
+
+/// The key of an encrypted user store, either a random 256-bit key or a passphrase that the
+/// key is derived from with Argon2id.
+///
+/// Encrypted files are `MAGIC`, the key derivation (with its parameters and salt), a random
+/// nonce and the ChaCha20-Poly1305 ciphertext. Everything before the ciphertext is
+/// authenticated too, so a modified header is detected like a modified body.
+pub struct StoreKey {
+    secret: Secret,
+    /// The last key derived from the passphrase, deriving is deliberately slow.
+    derived: Mutex<Option<Derived>>,
+}
+
+struct Derived {
+    salt: [u8; SALT_LENGTH],
+    params: Params,
+    key: [u8; KEY_LENGTH],
+}
+
+impl fmt::Debug for StoreKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.secret {
+            Secret::Key(_) => "key",
+            Secret::Passphrase(_) => "passphrase",
+        };
+
+        return write!(f, "StoreKey({kind})");
+    }
+}
+
+impl StoreKey {
+    pub fn from_bytes(key: [u8; KEY_LENGTH]) -> Self {
+        return Self { secret: Secret::Key(key), derived: Mutex::new(None) };
+    }
+
+    pub fn from_passphrase(passphrase: &str) -> Result<Self, AuthError> {
+        if passphrase.is_empty() {
+            return Err(AuthError::InvalidKey("the passphrase is empty".to_string()));
+        }
+
+        return Ok(Self { secret: Secret::Passphrase(passphrase.to_string()), derived: Mutex::new(None) });
+    }
+
+    /// A key written as 64 hex characters, e.g. by `openssl rand -hex 32`.
+    pub fn from_hex(hex: &str) -> Result<Self, AuthError> {
+        let key = data_encoding::HEXLOWER_PERMISSIVE
+            .decode(hex.trim().as_bytes())
+            .ok()
+            .and_then(|key| <[u8; KEY_LENGTH]>::try_from(key).ok())
+            .ok_or_else(|| AuthError::InvalidKey(format!("a key has to be {} hex characters", KEY_LENGTH * 2)))?;
+
+        return Ok(Self::from_bytes(key));
+    }
+
+    /// A key file holds the key as hex, or as exactly 32 raw bytes.
+    pub fn from_key_file(path: &Path) -> Result<Self, AuthError> {
+        let contents = std::fs::read(path)?;
+        if let Ok(key) = <[u8; KEY_LENGTH]>::try_from(contents.as_slice()) {
+            return Ok(Self::from_bytes(key));
+        }
+
+        return Self::from_hex(&String::from_utf8_lossy(&contents)).map_err(|error| {
+            return AuthError::InvalidKey(format!("{}: {error}", path.display()));
+        });
+    }
+
+    /// The key configured through `USERS_STORE_KEY`, `USERS_STORE_KEY_FILE` or
+    /// `USERS_STORE_PASSPHRASE`, `None` when neither is set.
+    pub fn from_env() -> Result<Option<Self>, AuthError> {
+        if let Ok(hex) = std::env::var(KEY_ENV) {
+            return Self::from_hex(&hex).map(Some);
+        }
+        if let Ok(path) = std::env::var(KEY_FILE_ENV) {
+            return Self::from_key_file(Path::new(&path)).map(Some);
+        }
+        if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+            return Self::from_passphrase(&passphrase).map(Some);
+        }
+
+        return Ok(None);
+    }
+
+    /// A new random key as hex, for `from_hex` and key files.
+    pub fn generate_hex() -> String {
+        let mut key = [0u8; KEY_LENGTH];
+        rand::rngs::OsRng.fill_bytes(&mut key);
+
+        return data_encoding::HEXLOWER.encode(&key);
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, AuthError> {
+        let mut header = MAGIC.to_vec();
+        let key = match &self.secret {
+            Secret::Key(key) => {
+                header.push(KDF_RAW_KEY);
+                *key
+            }
+            Secret::Passphrase(passphrase) => {
+                let salt = self.salt();
+                let params = default_params();
+                header.push(KDF_ARGON2ID);
+                header.extend_from_slice(&params.m_cost().to_le_bytes());
+                header.extend_from_slice(&params.t_cost().to_le_bytes());
+                header.extend_from_slice(&params.p_cost().to_le_bytes());
+                header.extend_from_slice(&salt);
+                self.derive(passphrase, &params, salt)?
+            }
+        };
+
+        let mut nonce = [0u8; NONCE_LENGTH];
+        rand::rngs::OsRng.fill_bytes(&mut nonce);
+        header.extend_from_slice(&nonce);
+
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad: &header })
+            .map_err(|_| AuthError::InvalidKey("encryption failed".to_string()))?;
+
+        header.extend_from_slice(&ciphertext);
+        return Ok(header);
+    }
+
+    /// Decrypts a file written by `encrypt`. `name` is only used in error messages.
+    pub fn decrypt(&self, name: &str, encrypted: &[u8]) -> Result<Vec<u8>, AuthError> {
+        let truncated = || AuthError::CorruptStore(format!("{name}: the encrypted file is truncated"));
+        if !is_encrypted(encrypted) {
+            return Err(AuthError::CorruptStore(format!("{name}: not an encrypted user store")));
+        }
+
+        let mut rest = &encrypted[MAGIC.len()..];
+        let mut take = |length: usize| -> Result<&[u8], AuthError> {
+            if rest.len() < length {
+                return Err(truncated());
+            }
+            let (taken, remaining) = rest.split_at(length);
+            rest = remaining;
+            return Ok(taken);
+        };
+
+        let key = match (take(1)?[0], &self.secret) {
+            (KDF_RAW_KEY, Secret::Key(key)) => *key,
+            (KDF_ARGON2ID, Secret::Passphrase(passphrase)) => {
+                let number = |bytes: &[u8]| u32::from_le_bytes(bytes.try_into().unwrap());
+                let (memory, iterations, parallelism) = (number(take(4)?), number(take(4)?), number(take(4)?));
+                let salt: [u8; SALT_LENGTH] = take(SALT_LENGTH)?.try_into().unwrap();
+                if memory > MAX_MEMORY_KIB || iterations > MAX_ITERATIONS || parallelism > MAX_PARALLELISM {
+                    return Err(AuthError::CorruptStore(format!(
+                        "{name}: the key derivation parameters are too large (memory {memory} KiB, {iterations} iterations, parallelism {parallelism})"
+                    )));
+                }
+                let params = Params::new(memory, iterations, parallelism, Some(KEY_LENGTH))
+                    .map_err(|error| AuthError::CorruptStore(format!("{name}: {error}")))?;
+                self.derive(passphrase, &params, salt)?
+            }
+            (KDF_RAW_KEY, Secret::Passphrase(_)) => {
+                return Err(AuthError::InvalidKey(format!("{name} was encrypted with a key, not a passphrase")));
+            }
+            (KDF_ARGON2ID, Secret::Key(_)) => {
+                return Err(AuthError::InvalidKey(format!("{name} was encrypted with a passphrase, not a key")));
+            }
+            (kdf, _) => return Err(AuthError::CorruptStore(format!("{name}: unknown key derivation {kdf}"))),
+        };
+        let nonce = take(NONCE_LENGTH)?;
+        let header = &encrypted[..encrypted.len() - rest.len()];
+
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+        return cipher
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: rest, aad: header })
+            .map_err(|_| AuthError::WrongKey(name.to_string()));
+    }
+
+    /// Reuses the salt of the last derivation, so saving does not derive the key again.
+    fn salt(&self) -> [u8; SALT_LENGTH] {
+        if let Some(derived) = self.derived.lock().unwrap().as_ref() {
+            return derived.salt;
+        }
+
+        let mut salt = [0u8; SALT_LENGTH];
+        rand::rngs::OsRng.fill_bytes(&mut salt);
+        return salt;
+    }
+
+    fn derive(&self, passphrase: &str, params: &Params, salt: [u8; SALT_LENGTH]) -> Result<[u8; KEY_LENGTH], AuthError> {
+        let mut derived = self.derived.lock().unwrap();
+        if let Some(cached) = derived.as_ref().filter(|cached| cached.salt == salt && cached.params == *params) {
+            return Ok(cached.key);
+        }
+
+        let mut key = [0u8; KEY_LENGTH];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone())
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(|error| AuthError::Hashing(error.to_string()))?;
+
+        *derived = Some(Derived { salt, params: params.clone(), key });
+        return Ok(key);
+    }
+}
+
+fn default_params() -> Params {
+    return Params::new(Params::DEFAULT_M_COST, Params::DEFAULT_T_COST, Params::DEFAULT_P_COST, Some(KEY_LENGTH))
+        .expect("the default Argon2 parameters are valid");
+}
+
+pub fn is_encrypted(contents: &[u8]) -> bool {
+    return contents.starts_with(MAGIC);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_with_key_and_passphrase() {
+        let key = StoreKey::from_hex(&StoreKey::generate_hex()).unwrap();
+        let encrypted = key.encrypt(b"{\"users\": {}}").unwrap();
+
+        assert!(is_encrypted(&encrypted));
+        assert_ne!(key.encrypt(b"{\"users\": {}}").unwrap(), encrypted);
+        assert_eq!(key.decrypt("users.json", &encrypted).unwrap(), b"{\"users\": {}}");
+
+        let passphrase = StoreKey::from_passphrase("correct horse").unwrap();
+        let encrypted = passphrase.encrypt(b"secret").unwrap();
+        let fresh = StoreKey::from_passphrase("correct horse").unwrap();
+        assert_eq!(fresh.decrypt("users.json", &encrypted).unwrap(), b"secret");
+    }
+
+    #[test]
+    fn test_wrong_keys_and_tampering_are_reported() {
+        let key = StoreKey::from_bytes([7; KEY_LENGTH]);
+        let mut encrypted = key.encrypt(b"secret").unwrap();
+
+        let other = StoreKey::from_bytes([8; KEY_LENGTH]);
+        assert!(matches!(other.decrypt("users.json", &encrypted), Err(AuthError::WrongKey(name)) if name == "users.json"));
+        let passphrase = StoreKey::from_passphrase("secret").unwrap();
+        assert!(matches!(passphrase.decrypt("users.json", &encrypted), Err(AuthError::InvalidKey(_))));
+
+        let last = encrypted.len() - 1;
+        encrypted[last] ^= 1;
+        assert!(matches!(key.decrypt("users.json", &encrypted), Err(AuthError::WrongKey(_))));
+        assert!(matches!(key.decrypt("users.json", &encrypted[..12]), Err(AuthError::CorruptStore(_))));
+
+        let passphrase_encrypted = passphrase.encrypt(b"secret").unwrap();
+        let wrong = StoreKey::from_passphrase("Secret").unwrap();
+        assert!(matches!(wrong.decrypt("users.json", &passphrase_encrypted), Err(AuthError::WrongKey(_))));
+
+        // The memory cost follows the magic number and the key derivation byte.
+        let mut expensive = passphrase_encrypted.clone();
+        expensive[MAGIC.len() + 1..MAGIC.len() + 5].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(passphrase.decrypt("users.json", &expensive), Err(AuthError::CorruptStore(_))));
+    }
+
+    #[test]
+    fn test_key_formats() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("users.key");
+
+        std::fs::write(&path, format!("{}\n", "AB".repeat(KEY_LENGTH))).unwrap();
+        let encrypted = StoreKey::from_key_file(&path).unwrap().encrypt(b"secret").unwrap();
+        assert_eq!(StoreKey::from_bytes([0xab; KEY_LENGTH]).decrypt("users.json", &encrypted).unwrap(), b"secret");
+
+        std::fs::write(&path, [1u8; KEY_LENGTH]).unwrap();
+        assert!(StoreKey::from_key_file(&path).is_ok());
+
+        std::fs::write(&path, "too short").unwrap();
+        assert!(matches!(StoreKey::from_key_file(&path), Err(AuthError::InvalidKey(_))));
+        assert!(matches!(StoreKey::from_hex("abcd"), Err(AuthError::InvalidKey(_))));
+        assert!(matches!(StoreKey::from_passphrase(""), Err(AuthError::InvalidKey(_))));
+        assert_eq!(format!("{:?}", StoreKey::from_bytes([1; KEY_LENGTH])), "StoreKey(key)");
+    }
+}
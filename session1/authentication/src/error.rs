@@ -0,0 +1,94 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("The user store is corrupt: {0}")]
+    CorruptStore(String),
+    #[error("Database error: {0}")]
+    Database(#[from] rusqlite::Error),
+    #[error("User {0} does not exist")]
+    UserNotFound(String),
+    #[error("User {0} already exists")]
+    UserExists(String),
+    #[error("Incorrect password")]
+    InvalidPassword,
+    #[error("Account is locked until {until} (unix time)")]
+    Locked { until: u64 },
+    #[error("Failed to hash the password: {0}")]
+    Hashing(String),
+    #[error("The session token is not valid")]
+    InvalidSession,
+    #[error("The session has expired")]
+    SessionExpired,
+    #[error("Role {0} is not defined")]
+    UnknownRole(String),
+    #[error("A one-time code is required to finish logging in")]
+    SecondFactorRequired,
+    #[error("The password is too weak: {0}")]
+    WeakPassword(String),
+    #[error("The user store was changed by someone else (generation {found}, expected {expected}), reload and try again")]
+    Conflict { expected: u64, found: u64 },
+    #[error("{0} is encrypted, set USERS_STORE_KEY, USERS_STORE_KEY_FILE or USERS_STORE_PASSPHRASE to open it")]
+    EncryptionKeyRequired(String),
+    #[error("{0} is not encrypted although a key is configured, encrypt it with `login_manager encrypt`")]
+    NotEncrypted(String),
+    #[error("Cannot decrypt {0}: the key or passphrase is wrong, or the file was modified")]
+    WrongKey(String),
+    #[error("Invalid encryption key: {0}")]
+    InvalidKey(String),
+    #[error("Invalid input: {0}")]
+    InvalidInput(String),
+    #[error("User {0} is disabled")]
+    Disabled(String),
+    #[error("Directory error: {0}")]
+    Directory(String),
+    #[error("Permission {0} is required")]
+    PermissionDenied(String),
+    #[error("The password was used recently, it has to differ from the current and the last {0} passwords")]
+    PasswordReused(usize),
+}
+
+impl AuthError {
+    /// Process exit code for the binaries, distinct per error kind.
+    pub fn exit_code(&self) -> i32 {
+        return match self {
+            AuthError::Io(_) => 2,
+            AuthError::CorruptStore(_) => 3,
+            AuthError::Database(_) => 4,
+            AuthError::UserNotFound(_) => 5,
+            AuthError::UserExists(_) => 6,
+            AuthError::InvalidPassword => 7,
+            AuthError::Hashing(_) => 8,
+            AuthError::Locked { .. } => 9,
+            AuthError::InvalidSession => 10,
+            AuthError::SessionExpired => 11,
+            AuthError::UnknownRole(_) => 12,
+            AuthError::SecondFactorRequired => 13,
+            AuthError::WeakPassword(_) => 14,
+            AuthError::PasswordReused(_) => 15,
+            AuthError::Conflict { .. } => 16,
+            AuthError::EncryptionKeyRequired(_) => 17,
+            AuthError::WrongKey(_) => 18,
+            AuthError::InvalidKey(_) => 19,
+            AuthError::InvalidInput(_) => 20,
+            AuthError::Disabled(_) => 21,
+            AuthError::Directory(_) => 22,
+            AuthError::PermissionDenied(_) => 23,
+            AuthError::NotEncrypted(_) => 24,
+        };
+    }
+}
+
+impl From<serde_json::Error> for AuthError {
+    fn from(error: serde_json::Error) -> Self {
+        return AuthError::CorruptStore(error.to_string());
+    }
+}
+
+impl From<argon2::password_hash::Error> for AuthError {
+    fn from(error: argon2::password_hash::Error) -> Self {
+        return AuthError::Hashing(error.to_string());
+    }
+}
@@ -0,0 +1,209 @@
+use std::path::{Path, PathBuf};
+
+use md5::{Digest, Md5};
+use sha1::Sha1;
+
+use crate::identity::{decide, IdentityProvider, RoleMapping};
+use crate::{normalize_username, verify_password, AuthError, LoginAction};
+
+/// Users from an Apache htpasswd file, with groups from an optional htgroup file. Both are read
+/// on every login, so edits apply immediately.
+///
+/// Supports bcrypt (`htpasswd -B`), Apache MD5 (`-m`), SHA-1 (`-s`) and Argon2 hashes.
+#[derive(Debug, Clone)]
+pub struct HtpasswdProvider {
+    path: PathBuf,
+    groups: Option<PathBuf>,
+    roles: RoleMapping,
+}
+
+impl HtpasswdProvider {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        return Self { path: path.into(), groups: None, roles: RoleMapping::new() };
+    }
+
+    /// An htgroup file with lines such as `admins: alice bob`.
+    pub fn with_groups(self, path: impl Into<PathBuf>) -> Self {
+        return Self { groups: Some(path.into()), ..self };
+    }
+
+    pub fn with_roles(self, roles: RoleMapping) -> Self {
+        return Self { roles, ..self };
+    }
+
+    fn find_hash(&self, username: &str) -> Result<Option<String>, AuthError> {
+        let username = normalize_username(username);
+
+        for line in read_lines(&self.path)? {
+            if let Some((name, hash)) = line.split_once(':') {
+                if normalize_username(name) == username {
+                    return Ok(Some(hash.trim().to_string()));
+                }
+            }
+        }
+
+        return Ok(None);
+    }
+
+    fn groups_of(&self, username: &str) -> Result<Vec<String>, AuthError> {
+        let Some(path) = &self.groups else {
+            return Ok(Vec::new());
+        };
+        let username = normalize_username(username);
+
+        let mut groups = Vec::new();
+        for line in read_lines(path)? {
+            if let Some((group, members)) = line.split_once(':') {
+                if members.split_whitespace().any(|member| normalize_username(member) == username) {
+                    groups.push(group.trim().to_string());
+                }
+            }
+        }
+
+        return Ok(groups);
+    }
+}
+
+impl IdentityProvider for HtpasswdProvider {
+    fn name(&self) -> &str {
+        return "htpasswd";
+    }
+
+    fn authenticate(&self, username: &str, password: &str) -> Result<Option<LoginAction>, AuthError> {
+        let Some(hash) = self.find_hash(username)? else {
+            return Ok(None);
+        };
+
+        let roles = match verify_htpasswd(password, &hash)? {
+            true => Some(self.roles.roles_for(&self.groups_of(username)?)),
+            false => None,
+        };
+
+        return decide(self.name(), username, roles);
+    }
+}
+
+/// Lines without comments and blank lines.
+fn read_lines(path: &Path) -> Result<Vec<String>, AuthError> {
+    let contents = std::fs::read_to_string(path)?;
+
+    return Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect());
+}
+
+fn verify_htpasswd(password: &str, hash: &str) -> Result<bool, AuthError> {
+    if hash.starts_with("$2y$") || hash.starts_with("$2a$") || hash.starts_with("$2b$") {
+        return bcrypt::verify(password, hash).map_err(|error| AuthError::Hashing(error.to_string()));
+    }
+
+    if let Some(encoded) = hash.strip_prefix("{SHA}") {
+        let digest = data_encoding::BASE64.encode(&Sha1::digest(password.as_bytes()));
+        return Ok(digest == encoded);
+    }
+
+    if let Some(salt_and_hash) = hash.strip_prefix("$apr1$") {
+        let salt = salt_and_hash.split('$').next().unwrap_or_default();
+        return Ok(apr1(password, salt) == hash);
+    }
+
+    if hash.starts_with("$argon2") {
+        return verify_password(password, hash);
+    }
+
+    return Err(AuthError::Hashing("unsupported htpasswd hash, use bcrypt (htpasswd -B)".to_string()));
+}
+
+/// Apache's variant of the MD5-based crypt, `$apr1$salt$hash`.
+fn apr1(password: &str, salt: &str) -> String {
+    const MAGIC: &str = "$apr1$";
+    let password = password.as_bytes();
+    let salt = &salt.as_bytes()[..salt.len().min(8)];
+
+    let alternate = Md5::new().chain_update(password).chain_update(salt).chain_update(password).finalize();
+
+    let mut context = Md5::new().chain_update(password).chain_update(MAGIC).chain_update(salt);
+    for chunk_start in (0..password.len()).step_by(16) {
+        context.update(&alternate[..(password.len() - chunk_start).min(16)]);
+    }
+    let mut length = password.len();
+    while length > 0 {
+        match length & 1 {
+            1 => context.update([0u8]),
+            _ => context.update(&password[..1]),
+        }
+        length >>= 1;
+    }
+    let mut digest = context.finalize();
+
+    for round in 0..1000 {
+        let mut context = Md5::new();
+        match round & 1 {
+            1 => context.update(password),
+            _ => context.update(digest),
+        }
+        if round % 3 != 0 {
+            context.update(salt);
+        }
+        if round % 7 != 0 {
+            context.update(password);
+        }
+        match round & 1 {
+            1 => context.update(digest),
+            _ => context.update(password),
+        }
+        digest = context.finalize();
+    }
+
+    const ALPHABET: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+    let mut encoded = String::new();
+    let mut push = |value: u32, characters: usize| {
+        let mut value = value;
+        for _ in 0..characters {
+            encoded.push(ALPHABET[(value & 0x3f) as usize] as char);
+            value >>= 6;
+        }
+    };
+    for (first, second, third) in [(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)] {
+        push((digest[first] as u32) << 16 | (digest[second] as u32) << 8 | digest[third] as u32, 4);
+    }
+    push(digest[11] as u32, 2);
+
+    return format!("{MAGIC}{}${encoded}", String::from_utf8_lossy(salt));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LoginRole;
+
+    #[test]
+    fn test_htpasswd_hashes() {
+        assert_eq!(apr1("myPassword", "r31....."), "$apr1$r31.....$HqJZimcKQFAMYayBlzkrA/");
+        assert!(verify_htpasswd("password", "{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=").unwrap());
+        assert!(!verify_htpasswd("Password", "{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=").unwrap());
+        assert!(verify_htpasswd("secret", &bcrypt::hash("secret", 4).unwrap()).unwrap());
+        assert!(verify_htpasswd("secret", "plaintext").is_err());
+    }
+
+    #[test]
+    fn test_htpasswd_provider() {
+        let directory = tempfile::tempdir().unwrap();
+        let passwords = directory.path().join(".htpasswd");
+        let groups = directory.path().join(".htgroup");
+        std::fs::write(&passwords, "# users\nalice:$apr1$r31.....$HqJZimcKQFAMYayBlzkrA/\nbob:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\n").unwrap();
+        std::fs::write(&groups, "admins: alice\nstaff: alice bob\n").unwrap();
+
+        let provider = HtpasswdProvider::new(&passwords)
+            .with_groups(&groups)
+            .with_roles(RoleMapping::new().map("admins", LoginRole::admin()).map("staff", LoginRole::user()));
+
+        assert_eq!(provider.authenticate("Alice", "myPassword").unwrap(), Some(LoginAction::Granted(vec![LoginRole::admin(), LoginRole::user()])));
+        assert_eq!(provider.authenticate("bob", "password").unwrap(), Some(LoginAction::Granted(vec![LoginRole::user()])));
+        assert_eq!(provider.authenticate("bob", "wrong").unwrap(), Some(LoginAction::Denied));
+        assert_eq!(provider.authenticate("carol", "password").unwrap(), None);
+    }
+}
@@ -0,0 +1,380 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::{
+    audit, login_at, normalize_username, unix_now, AuditEvent, AuthError, HtpasswdProvider, LdapProvider, LockoutPolicy, LoginAction,
+    LoginRole, UserStore,
+};
+
+/// A source of users that can check credentials, such as the user store, an htpasswd file or
+/// an LDAP directory.
+pub trait IdentityProvider {
+    /// Short name for audit details, e.g. `store` or `ldap`.
+    fn name(&self) -> &str;
+
+    /// `Ok(None)` when the provider does not know the user, store and network failures are `Err`.
+    fn authenticate(&self, username: &str, password: &str) -> Result<Option<LoginAction>, AuthError>;
+
+    /// Whether the provider decides alone for the users it knows, as the store does. Other
+    /// providers are then only asked about users it does not know.
+    fn is_authoritative(&self) -> bool {
+        return false;
+    }
+}
+
+/// The user store, with lockout and two-factor authentication. Logins are audited by the store
+/// functions themselves.
+pub struct StoreProvider<'a> {
+    store: &'a dyn UserStore,
+    policy: LockoutPolicy,
+}
+
+impl<'a> StoreProvider<'a> {
+    pub fn new(store: &'a dyn UserStore) -> Self {
+        return Self { store, policy: LockoutPolicy::default() };
+    }
+
+    pub fn with_policy(self, policy: LockoutPolicy) -> Self {
+        return Self { policy, ..self };
+    }
+}
+
+impl IdentityProvider for StoreProvider<'_> {
+    fn name(&self) -> &str {
+        return "store";
+    }
+
+    fn authenticate(&self, username: &str, password: &str) -> Result<Option<LoginAction>, AuthError> {
+        return login_at(self.store, username, password, &self.policy, unix_now());
+    }
+
+    fn is_authoritative(&self) -> bool {
+        return true;
+    }
+}
+
+/// Asks the authoritative providers first, and any answer or error from them is final: a user
+/// of the store who is denied, locked or disabled there cannot log in through another provider.
+///
+/// Only users they do not know are tried against the other providers, in order, until one
+/// grants the login or otherwise decides it. A wrong password or an unknown user falls through
+/// to the next provider, and so do failing providers, so an unreachable directory does not block
+/// the others. Without a decision the result is `Denied` if any provider rejected the password,
+/// then the first error, then `None`.
+///
+/// The store locks its own users out, external users are locked out by the chain after
+/// failures rejected by every provider. Their failures are kept as long as the chain.
+#[derive(Default)]
+pub struct ProviderChain<'a> {
+    providers: Vec<Box<dyn IdentityProvider + 'a>>,
+    policy: LockoutPolicy,
+    failures: Mutex<HashMap<String, ExternalFailures>>,
+}
+
+/// Failed logins of a user no authoritative provider knows.
+#[derive(Debug, Default)]
+struct ExternalFailures {
+    failed_attempts: u32,
+    locked_until: Option<u64>,
+}
+
+impl<'a> ProviderChain<'a> {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    pub fn with(mut self, provider: impl IdentityProvider + 'a) -> Self {
+        self.providers.push(Box::new(provider));
+        return self;
+    }
+
+    /// The lockout of external users.
+    pub fn with_policy(self, policy: LockoutPolicy) -> Self {
+        return Self { policy, ..self };
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.providers.is_empty();
+    }
+
+    fn authenticate_external(&self, username: &str, password: &str, now: u64) -> Result<Option<LoginAction>, AuthError> {
+        let key = normalize_username(username);
+        // Not held while the providers are asked, they may be slow to answer.
+        let failures = || self.failures.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        if let Some(until) = failures().get(&key).and_then(|failed| failed.locked_until).filter(|until| *until > now) {
+            audit(AuditEvent::failure(username, "login", Some(username), "chain: account locked"))?;
+            return Ok(Some(LoginAction::Locked { until }));
+        }
+
+        let mut denied = false;
+        let mut first_error = None;
+
+        for provider in self.providers.iter().filter(|provider| !provider.is_authoritative()) {
+            match provider.authenticate(username, password) {
+                Ok(None) => {}
+                Ok(Some(LoginAction::Denied)) => denied = true,
+                Ok(Some(action)) => {
+                    if matches!(action, LoginAction::Granted(_)) {
+                        failures().remove(&key);
+                    }
+                    return Ok(Some(action));
+                }
+                Err(error) => {
+                    first_error.get_or_insert(error);
+                }
+            }
+        }
+
+        if !denied {
+            return first_error.map_or(Ok(None), Err);
+        }
+
+        let mut failures = failures();
+        let failed = failures.entry(key).or_default();
+        failed.failed_attempts += 1;
+        let Some(seconds) = self.policy.lockout_duration(failed.failed_attempts) else {
+            return Ok(Some(LoginAction::Denied));
+        };
+        failed.locked_until = Some(now + seconds);
+        drop(failures);
+        audit(AuditEvent::failure(username, "login", Some(username), "chain: account locked"))?;
+
+        return Ok(Some(LoginAction::Locked { until: now + seconds }));
+    }
+
+    /// The store, followed by the providers configured in the environment:
+    ///
+    /// - `AUTH_HTPASSWD_FILE`, with groups from `AUTH_HTGROUP_FILE`
+    /// - `AUTH_LDAP_URL` and `AUTH_LDAP_USER_DN`, a DN with a `{username}` placeholder
+    ///
+    /// Groups map to roles with `AUTH_GROUP_ROLES`, e.g. `admins=admin;staff=user`. Users of
+    /// other groups get the `user` role.
+    pub fn from_env(store: &'a dyn UserStore) -> Result<Self, AuthError> {
+        let roles = match std::env::var("AUTH_GROUP_ROLES") {
+            Ok(mapping) => RoleMapping::parse(&mapping)?,
+            Err(_) => RoleMapping::new(),
+        };
+        let mut chain = Self::new().with(StoreProvider::new(store));
+
+        if let Ok(path) = std::env::var("AUTH_HTPASSWD_FILE") {
+            let mut provider = HtpasswdProvider::new(path).with_roles(roles.clone());
+            if let Ok(groups) = std::env::var("AUTH_HTGROUP_FILE") {
+                provider = provider.with_groups(PathBuf::from(groups));
+            }
+            chain = chain.with(provider);
+        }
+
+        if let Ok(url) = std::env::var("AUTH_LDAP_URL") {
+            let user_dn = std::env::var("AUTH_LDAP_USER_DN").map_err(|_| {
+                return AuthError::InvalidInput("AUTH_LDAP_USER_DN is required with AUTH_LDAP_URL".to_string());
+            })?;
+            chain = chain.with(LdapProvider::new(&url, &user_dn)?.with_roles(roles));
+        }
+
+        return Ok(chain);
+    }
+}
+
+impl IdentityProvider for ProviderChain<'_> {
+    fn name(&self) -> &str {
+        return "chain";
+    }
+
+    fn authenticate(&self, username: &str, password: &str) -> Result<Option<LoginAction>, AuthError> {
+        for provider in self.providers.iter().filter(|provider| provider.is_authoritative()) {
+            if let Some(action) = provider.authenticate(username, password)? {
+                return Ok(Some(action));
+            }
+        }
+
+        return self.authenticate_external(username, password, unix_now());
+    }
+}
+
+/// Maps the groups of an external user to roles. Groups are compared case-insensitively, either
+/// by name or, for LDAP, by the full DN or its first value such as `admins` in
+/// `cn=admins,ou=groups,dc=example,dc=com`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoleMapping {
+    groups: HashMap<String, LoginRole>,
+    default: Option<LoginRole>,
+}
+
+impl Default for RoleMapping {
+    fn default() -> Self {
+        return Self { groups: HashMap::new(), default: Some(LoginRole::user()) };
+    }
+}
+
+impl RoleMapping {
+    /// No groups mapped, every user gets the `user` role.
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    pub fn map(mut self, group: &str, role: LoginRole) -> Self {
+        self.groups.insert(group.to_lowercase(), role);
+        return self;
+    }
+
+    /// The role of users in none of the mapped groups. Without one they are denied.
+    pub fn with_default(self, default: Option<LoginRole>) -> Self {
+        return Self { default, ..self };
+    }
+
+    /// Parses `group=role` pairs separated by `;`, groups may be DNs.
+    pub fn parse(mapping: &str) -> Result<Self, AuthError> {
+        let mut roles = Self::new();
+
+        for pair in mapping.split(';').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let Some((group, role)) = pair.rsplit_once('=') else {
+                return Err(AuthError::InvalidInput(format!("{pair} is not a group=role pair")));
+            };
+            roles = roles.map(group.trim(), LoginRole::new(role.trim()));
+        }
+
+        return Ok(roles);
+    }
+
+    pub fn roles_for(&self, groups: &[String]) -> Vec<LoginRole> {
+        let mut roles: Vec<LoginRole> = Vec::new();
+
+        for group in groups {
+            let group = group.to_lowercase();
+            let first_value = group
+                .split(',')
+                .next()
+                .and_then(|rdn| rdn.split_once('='))
+                .map(|(_, value)| value.trim().to_string());
+
+            let role = self.groups.get(&group).or_else(|| first_value.and_then(|value| self.groups.get(&value)));
+            if let Some(role) = role.filter(|role| !roles.contains(role)) {
+                roles.push(role.clone());
+            }
+        }
+
+        if roles.is_empty() {
+            roles.extend(self.default.clone());
+        }
+
+        return roles;
+    }
+}
+
+/// What an external provider decides once the password was checked. Users whose groups map to
+/// no role are denied.
+pub(crate) fn decide(provider: &str, username: &str, roles: Option<Vec<LoginRole>>) -> Result<Option<LoginAction>, AuthError> {
+    let failure = |detail: &str| {
+        return audit(AuditEvent::failure(username, "login", Some(username), &format!("{provider}: {detail}")));
+    };
+
+    return match roles {
+        None => {
+            failure("invalid password")?;
+            Ok(Some(LoginAction::Denied))
+        }
+        Some(roles) if roles.is_empty() => {
+            failure("no role for the groups of the user")?;
+            Ok(Some(LoginAction::Denied))
+        }
+        Some(roles) => {
+            audit(AuditEvent::success(username, "login", Some(username)).with_detail(provider))?;
+            Ok(Some(LoginAction::Granted(roles)))
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{enable_totp, set_disabled, test_users, MemoryStore, TEST_PASSWORD};
+
+    struct Fixed(Result<Option<LoginAction>, ()>);
+
+    impl IdentityProvider for Fixed {
+        fn name(&self) -> &str {
+            return "fixed";
+        }
+
+        fn authenticate(&self, _: &str, _: &str) -> Result<Option<LoginAction>, AuthError> {
+            return self.0.clone().map_err(|_| AuthError::Directory("unreachable".to_string()));
+        }
+    }
+
+    /// Knows every user, with the password `secret`.
+    struct External;
+
+    impl IdentityProvider for External {
+        fn name(&self) -> &str {
+            return "external";
+        }
+
+        fn authenticate(&self, username: &str, password: &str) -> Result<Option<LoginAction>, AuthError> {
+            return decide("external", username, (password == "secret").then(|| vec![LoginRole::user()]));
+        }
+    }
+
+    #[test]
+    fn test_chain_falls_through_until_decided() {
+        let store = MemoryStore::with_users(test_users());
+        let granted = Some(LoginAction::Granted(vec![LoginRole::admin()]));
+        let chain = ProviderChain::new()
+            .with(Fixed(Err(())))
+            .with(StoreProvider::new(&store))
+            .with(Fixed(Ok(granted.clone())));
+
+        assert_eq!(chain.authenticate("bob", TEST_PASSWORD).unwrap(), Some(LoginAction::Granted(vec![LoginRole::user()])));
+        assert_eq!(chain.authenticate("bob", "wrong").unwrap(), Some(LoginAction::Denied));
+        assert_eq!(chain.authenticate("carol", "wrong").unwrap(), granted);
+
+        let chain = ProviderChain::new().with(Fixed(Err(()))).with(StoreProvider::new(&store));
+        assert_eq!(chain.authenticate("bob", "wrong").unwrap(), Some(LoginAction::Denied));
+        assert!(matches!(chain.authenticate("carol", "wrong"), Err(AuthError::Directory(_))));
+        assert_eq!(ProviderChain::new().authenticate("bob", TEST_PASSWORD).unwrap(), None);
+    }
+
+    #[test]
+    fn test_chain_leaves_store_users_to_the_store() {
+        let store = MemoryStore::with_users(test_users());
+        let chain = ProviderChain::new().with(External).with(StoreProvider::new(&store));
+
+        set_disabled(&store, "bob", true).unwrap();
+        assert_eq!(chain.authenticate("bob", TEST_PASSWORD).unwrap(), Some(LoginAction::Disabled));
+        assert_eq!(chain.authenticate("bob", "secret").unwrap(), Some(LoginAction::Denied));
+
+        enable_totp(&store, "admin", "test").unwrap();
+        assert_eq!(chain.authenticate("admin", TEST_PASSWORD).unwrap(), Some(LoginAction::NeedsSecondFactor));
+        assert_eq!(chain.authenticate("admin", "secret").unwrap(), Some(LoginAction::Denied));
+
+        assert_eq!(chain.authenticate("carol", "secret").unwrap(), Some(LoginAction::Granted(vec![LoginRole::user()])));
+    }
+
+    #[test]
+    fn test_chain_locks_out_external_users() {
+        let store = MemoryStore::with_users(test_users());
+        let policy = LockoutPolicy { max_attempts: 2, ..LockoutPolicy::default() };
+        let chain = ProviderChain::new().with(StoreProvider::new(&store)).with(External).with_policy(policy);
+
+        assert_eq!(chain.authenticate("carol", "wrong").unwrap(), Some(LoginAction::Denied));
+        assert_eq!(chain.authenticate("Carol", "secret").unwrap(), Some(LoginAction::Granted(vec![LoginRole::user()])));
+
+        assert_eq!(chain.authenticate("carol", "wrong").unwrap(), Some(LoginAction::Denied));
+        assert!(matches!(chain.authenticate("carol", "wrong").unwrap(), Some(LoginAction::Locked { .. })));
+        assert!(matches!(chain.authenticate("carol", "secret").unwrap(), Some(LoginAction::Locked { .. })));
+        assert_eq!(chain.authenticate("dave", "secret").unwrap(), Some(LoginAction::Granted(vec![LoginRole::user()])));
+    }
+
+    #[test]
+    fn test_role_mapping() {
+        let mapping = RoleMapping::parse("Admins=admin; cn=staff,ou=groups,dc=example,dc=com=user").unwrap();
+        let groups = |groups: &[&str]| groups.iter().map(|group| group.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(mapping.roles_for(&groups(&["cn=admins,ou=groups,dc=example,dc=com", "admins"])), vec![LoginRole::admin()]);
+        assert_eq!(mapping.roles_for(&groups(&["CN=Staff,OU=Groups,DC=Example,DC=Com"])), vec![LoginRole::user()]);
+        assert_eq!(mapping.roles_for(&[]), vec![LoginRole::user()]);
+        assert!(mapping.with_default(None).roles_for(&groups(&["cn=other"])).is_empty());
+        assert!(RoleMapping::parse("admins").is_err());
+    }
+}
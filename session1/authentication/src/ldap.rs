@@ -0,0 +1,264 @@
+use std::time::Duration;
+
+use ldap3::{dn_escape, LdapConn, LdapConnSettings, LdapError, Scope, SearchEntry};
+
+use crate::identity::{decide, IdentityProvider, RoleMapping};
+use crate::{normalize_username, AuthError, LoginAction};
+
+/// LDAP result code for a wrong password or a DN that does not exist.
+const INVALID_CREDENTIALS: u32 = 49;
+
+/// Checks passwords with a simple bind as the user, then reads the `memberOf` attribute of the
+/// user entry for `RoleMapping`. A directory cannot tell an unknown user from a wrong password,
+/// so this provider never returns `Ok(None)`.
+#[derive(Debug, Clone)]
+pub struct LdapProvider {
+    url: String,
+    user_dn: String,
+    roles: RoleMapping,
+    timeout: Duration,
+}
+
+impl LdapProvider {
+    /// `user_dn` is the DN of a user with a `{username}` placeholder, such as
+    /// `uid={username},ou=people,dc=example,dc=com`.
+    pub fn new(url: &str, user_dn: &str) -> Result<Self, AuthError> {
+        if !url.starts_with("ldap://") {
+            return Err(AuthError::InvalidInput(format!("{url} is not an ldap:// URL")));
+        }
+        if !user_dn.contains("{username}") {
+            return Err(AuthError::InvalidInput(format!("{user_dn} has no {{username}} placeholder")));
+        }
+
+        return Ok(Self {
+            url: url.to_string(),
+            user_dn: user_dn.to_string(),
+            roles: RoleMapping::new(),
+            timeout: Duration::from_secs(5),
+        });
+    }
+
+    pub fn with_roles(self, roles: RoleMapping) -> Self {
+        return Self { roles, ..self };
+    }
+
+    /// For connecting and for every request, 5 seconds by default.
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        return Self { timeout, ..self };
+    }
+
+    fn dn_of(&self, username: &str) -> String {
+        return self.user_dn.replace("{username}", &dn_escape(normalize_username(username)));
+    }
+
+    /// The groups of the user if the password is right.
+    fn bind(&self, username: &str, password: &str) -> Result<Option<Vec<String>>, LdapError> {
+        let settings = LdapConnSettings::new().set_conn_timeout(self.timeout);
+        let mut connection = LdapConn::with_settings(settings, &self.url)?;
+        connection.with_timeout(self.timeout);
+
+        let dn = self.dn_of(username);
+        let bound = connection.simple_bind(&dn, password)?;
+        if bound.rc == INVALID_CREDENTIALS {
+            connection.unbind()?;
+            return Ok(None);
+        }
+        bound.success()?;
+
+        // Timeouts only apply to the next request.
+        connection.with_timeout(self.timeout);
+        let (entries, _) = connection.search(&dn, Scope::Base, "(objectClass=*)", vec!["memberOf"])?.success()?;
+        connection.unbind()?;
+
+        let groups = entries
+            .into_iter()
+            .map(SearchEntry::construct)
+            .flat_map(|entry| entry.attrs.into_iter())
+            .filter(|(attribute, _)| attribute.eq_ignore_ascii_case("memberOf"))
+            .flat_map(|(_, values)| values)
+            .collect();
+
+        return Ok(Some(groups));
+    }
+}
+
+impl IdentityProvider for LdapProvider {
+    fn name(&self) -> &str {
+        return "ldap";
+    }
+
+    fn authenticate(&self, username: &str, password: &str) -> Result<Option<LoginAction>, AuthError> {
+        // A simple bind with an empty password is an anonymous bind, which servers accept.
+        if password.is_empty() || normalize_username(username).is_empty() {
+            return decide(self.name(), username, None);
+        }
+
+        let groups = self.bind(username, password).map_err(|error| {
+            return AuthError::Directory(format!("{}: {error}", self.url));
+        })?;
+
+        return decide(self.name(), username, groups.map(|groups| self.roles.roles_for(&groups)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::LoginRole;
+
+    /// Just enough of an LDAP server for simple binds and base searches of `memberOf`.
+    struct FakeDirectory {
+        /// Password and groups by lowercase DN.
+        entries: HashMap<String, (String, Vec<String>)>,
+    }
+
+    /// One BER element: tag, contents, and the bytes after it.
+    fn element(bytes: &[u8]) -> (u8, &[u8], &[u8]) {
+        let (tag, first_length) = (bytes[0], bytes[1] as usize);
+        let (length, header) = match first_length & 0x80 {
+            0 => (first_length, 2),
+            _ => {
+                let count = first_length & 0x7f;
+                (bytes[2..2 + count].iter().fold(0, |length, byte| length << 8 | *byte as usize), 2 + count)
+            }
+        };
+
+        return (tag, &bytes[header..header + length], &bytes[header + length..]);
+    }
+
+    fn encode(tag: u8, contents: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![tag];
+        match contents.len() {
+            length if length < 0x80 => encoded.push(length as u8),
+            length => {
+                let bytes: Vec<u8> = length.to_be_bytes().into_iter().skip_while(|byte| *byte == 0).collect();
+                encoded.push(0x80 | bytes.len() as u8);
+                encoded.extend(bytes);
+            }
+        }
+        encoded.extend_from_slice(contents);
+
+        return encoded;
+    }
+
+    fn read_message(stream: &mut TcpStream) -> Option<Vec<u8>> {
+        let mut header = [0u8; 2];
+        stream.read_exact(&mut header).ok()?;
+
+        let mut message = header.to_vec();
+        if header[1] & 0x80 != 0 {
+            let mut length = vec![0u8; (header[1] & 0x7f) as usize];
+            stream.read_exact(&mut length).ok()?;
+            message.extend(length);
+        }
+
+        let length = match header[1] & 0x80 {
+            0 => header[1] as usize,
+            _ => message[2..].iter().fold(0, |length, byte| length << 8 | *byte as usize),
+        };
+        let mut contents = vec![0u8; length];
+        stream.read_exact(&mut contents).ok()?;
+        message.extend(contents);
+
+        return Some(message);
+    }
+
+    fn result(message_id: &[u8], operation: u8, code: u8) -> Vec<u8> {
+        let result = [encode(0x0a, &[code]), encode(0x04, b""), encode(0x04, b"")].concat();
+        return encode(0x30, &[encode(0x02, message_id), encode(operation, &result)].concat());
+    }
+
+    impl FakeDirectory {
+        fn serve(&self, mut stream: TcpStream) {
+            let mut bound = None;
+
+            while let Some(message) = read_message(&mut stream) {
+                let (_, message, _) = element(&message);
+                let (_, message_id, rest) = element(message);
+                let (operation, request, _) = element(rest);
+
+                let response = match operation {
+                    // Bind: version, name, [0] simple password.
+                    0x60 => {
+                        let (_, _, rest) = element(request);
+                        let (_, name, rest) = element(rest);
+                        let (_, password, _) = element(rest);
+                        let dn = String::from_utf8_lossy(name).to_lowercase();
+
+                        let valid = self.entries.get(&dn).is_some_and(|(expected, _)| expected.as_bytes() == password);
+                        bound = valid.then_some(dn);
+                        result(message_id, 0x61, if valid { 0 } else { INVALID_CREDENTIALS as u8 })
+                    }
+                    // Search: only the base object matters here.
+                    0x63 => {
+                        let (_, base, _) = element(request);
+                        let base = String::from_utf8_lossy(base).to_lowercase();
+
+                        match self.entries.get(&base).filter(|_| bound.as_ref() == Some(&base)) {
+                            Some((_, groups)) => {
+                                let values: Vec<u8> = groups.iter().flat_map(|group| encode(0x04, group.as_bytes())).collect();
+                                let attribute = encode(0x30, &[encode(0x04, b"memberOf"), encode(0x31, &values)].concat());
+                                let entry = [encode(0x04, base.as_bytes()), encode(0x30, &attribute)].concat();
+                                let entry = encode(0x30, &[encode(0x02, message_id), encode(0x64, &entry)].concat());
+                                [entry, result(message_id, 0x65, 0)].concat()
+                            }
+                            // noSuchObject, or insufficientAccessRights when not bound as the user.
+                            None => result(message_id, 0x65, if bound.is_some() { 32 } else { 50 }),
+                        }
+                    }
+                    // Unbind.
+                    _ => return,
+                };
+
+                if stream.write_all(&response).is_err() {
+                    return;
+                }
+            }
+        }
+
+        /// Serves connections on a background thread, returns the `ldap://` URL.
+        fn start(self) -> String {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("ldap://{}", listener.local_addr().unwrap());
+
+            std::thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    self.serve(stream);
+                }
+            });
+
+            return url;
+        }
+    }
+
+    #[test]
+    fn test_ldap_provider_against_fake_directory() {
+        let entry = |password: &str, groups: &[&str]| (password.to_string(), groups.iter().map(|group| group.to_string()).collect());
+        let directory = FakeDirectory {
+            entries: HashMap::from([
+                ("uid=alice,ou=people,dc=example,dc=com".to_string(), entry("alice-secret", &["cn=Admins,ou=groups,dc=example,dc=com"])),
+                ("uid=bob,ou=people,dc=example,dc=com".to_string(), entry("bob-secret", &[])),
+            ]),
+        };
+        let url = directory.start();
+
+        let roles = RoleMapping::new().map("admins", LoginRole::admin()).with_default(None);
+        let provider = LdapProvider::new(&url, "uid={username},ou=people,dc=example,dc=com").unwrap().with_roles(roles);
+
+        assert_eq!(provider.authenticate("Alice", "alice-secret").unwrap(), Some(LoginAction::Granted(vec![LoginRole::admin()])));
+        assert_eq!(provider.authenticate("alice", "wrong").unwrap(), Some(LoginAction::Denied));
+        assert_eq!(provider.authenticate("alice", "").unwrap(), Some(LoginAction::Denied));
+        // Bob is in no mapped group and there is no default role.
+        assert_eq!(provider.authenticate("bob", "bob-secret").unwrap(), Some(LoginAction::Denied));
+        assert_eq!(provider.authenticate("mallory", "anything").unwrap(), Some(LoginAction::Denied));
+        assert_eq!(provider.dn_of("a,b"), "uid=a\\2cb,ou=people,dc=example,dc=com");
+
+        let unreachable = LdapProvider::new("ldap://127.0.0.1:1", "uid={username},dc=example").unwrap();
+        assert!(matches!(unreachable.authenticate("alice", "alice-secret"), Err(AuthError::Directory(_))));
+        assert!(LdapProvider::new(&url, "uid=alice").is_err());
+    }
+}
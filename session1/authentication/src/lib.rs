@@ -1,121 +1,603 @@
+#![allow(clippy::needless_return)]
+
 use std::collections::HashMap;
-use std::path::Path;
-use project_root::get_project_root;
+use std::time::SystemTime;
 
 use serde::{Deserialize, Serialize};
 
-pub fn hash_password(password: &str) -> String {
-    use sha2::Digest;
-    let mut hasher = sha2::Sha256::new();
-    hasher.update(password);
-
-    return format!("{:X}", hasher.finalize());
-}
+mod audit;
+mod encryption;
+mod error;
+mod htpasswd;
+mod identity;
+mod ldap;
+mod lockout;
+mod password;
+mod policy;
+mod roles;
+mod session;
+mod store;
+mod totp;
+mod username;
+
+pub use audit::{audit, current_actor, install_audit_log, AuditEvent, AuditFilter, AuditLog, AuditOutcome};
+pub use encryption::{is_encrypted, StoreKey};
+pub use error::AuthError;
+pub use htpasswd::HtpasswdProvider;
+pub use identity::{IdentityProvider, ProviderChain, RoleMapping, StoreProvider};
+pub use ldap::LdapProvider;
+pub use lockout::LockoutPolicy;
+pub use password::{hash_password, hash_password_with, needs_rehash, verify_password, HashParams};
+pub use policy::{install_password_policy, password_policy, PasswordPolicy};
+pub use roles::{install_roles, installed_roles, permissions, LoginRole, RoleRegistry};
+pub use session::{
+    issue_session, login_session, refresh_session, revoke_session, revoke_user_sessions, validate_session, Session,
+    DEFAULT_SESSION_TTL,
+};
+pub use store::{open_store, JsonFileStore, MemoryStore, SessionsChange, SqliteStore, UserStore, UsersChange};
+pub use totp::{otpauth_uri, totp_code, TotpConfig, TotpEnrollment};
+pub use username::normalize_username;
 
 pub fn greet_user(name: &str) -> String {
     return format!("Hello {name}");
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
-pub enum LoginAction {
-    Granted(LoginRole),
-    Denied,
+pub fn unix_now() -> u64 {
+    return SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
-pub enum LoginRole {
-    Admin,
-    User,
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum LoginAction {
+    Granted(Vec<LoginRole>),
     Denied,
+    /// Too many failed attempts, no password is checked before `until` (unix seconds).
+    Locked { until: u64 },
+    /// The password was right, finish with `login_second_factor` and a one-time or recovery code.
+    NeedsSecondFactor,
+    /// The password was right, but the account is disabled.
+    Disabled,
 }
 
-
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     pub username: String,
     pub password: String,
-    pub role: LoginRole,
+    /// Files written before roles existed have a single `"role": "Admin"` instead.
+    #[serde(default, alias = "role", deserialize_with = "roles::deserialize_roles")]
+    pub roles: Vec<LoginRole>,
+    #[serde(default)]
+    pub failed_attempts: u32,
+    #[serde(default)]
+    pub locked_until: Option<u64>,
+    #[serde(default)]
+    pub totp: Option<TotpConfig>,
+    /// Hashes of earlier passwords, newest first, see `PasswordPolicy::history`.
+    #[serde(default)]
+    pub password_history: Vec<String>,
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub email: Option<String>,
+    /// Unix seconds, `None` for users created before this was recorded.
+    #[serde(default)]
+    pub created_at: Option<u64>,
+    /// Last change of the password, profile, roles or status, not of login bookkeeping.
+    #[serde(default)]
+    pub updated_at: Option<u64>,
+    #[serde(default)]
+    pub last_login_at: Option<u64>,
+    /// Disabled users keep their data but cannot log in.
+    #[serde(default)]
+    pub disabled: bool,
 }
 
 impl User {
-    pub fn new(username: &str, password: &str, role: LoginRole) -> Self {
+    /// Creates a user whose password satisfies the installed `PasswordPolicy`.
+    pub fn new(username: &str, password: &str, role: LoginRole) -> Result<Self, AuthError> {
+        password_policy().check(password)?;
+        return Ok(Self::with_hash(username, hash_password(password)?, role));
+    }
+
+    /// Creates a user from an existing password hash, e.g. one exported from another store.
+    pub fn from_hash(username: &str, password_hash: &str, role: LoginRole) -> Result<Self, AuthError> {
+        // Fails for anything that is not an Argon2 or legacy SHA-256 hash.
+        verify_password("", password_hash)?;
+        return Ok(Self::with_hash(username, password_hash.to_string(), role));
+    }
+
+    fn with_hash(username: &str, password_hash: String, role: LoginRole) -> Self {
+        let now = unix_now();
+
         return Self {
             username: username.to_string(),
-            password: hash_password(password),
-            role,
+            password: password_hash,
+            roles: vec![role],
+            failed_attempts: 0,
+            locked_until: None,
+            totp: None,
+            password_history: Vec::new(),
+            display_name: None,
+            email: None,
+            created_at: Some(now),
+            updated_at: Some(now),
+            last_login_at: None,
+            disabled: false,
         };
     }
+
+    /// Sets the display name and email, empty values clear them.
+    pub fn with_profile(self, display_name: Option<&str>, email: Option<&str>) -> Result<Self, AuthError> {
+        let mut user = self;
+        user.set_profile(display_name, email)?;
+
+        return Ok(user);
+    }
+
+    /// Changes the given fields and leaves `None` ones as they are, empty values clear a field.
+    fn set_profile(&mut self, display_name: Option<&str>, email: Option<&str>) -> Result<(), AuthError> {
+        let cleared = |value: &str| Some(value.trim().to_string()).filter(|value| !value.is_empty());
+
+        if let Some(email) = email.and_then(cleared) {
+            validate_email(&email)?;
+        }
+        if let Some(display_name) = display_name {
+            self.display_name = cleared(display_name);
+        }
+        if let Some(email) = email {
+            self.email = cleared(email);
+        }
+
+        return Ok(());
+    }
+
+    /// The display name, or the username for users without one.
+    pub fn name(&self) -> &str {
+        return self.display_name.as_deref().unwrap_or(&self.username);
+    }
+
+    pub fn is_locked(&self, now: u64) -> bool {
+        return self.locked_until.is_some_and(|until| until > now);
+    }
+
+    pub fn has_role(&self, role: &LoginRole) -> bool {
+        return self.roles.contains(role);
+    }
+
+    /// Checks the permission against the installed role definitions, see `install_roles`.
+    pub fn can(&self, permission: &str) -> bool {
+        return roles::roles_allow(&self.roles, permission);
+    }
 }
 
+/// Only catches obvious mistakes, whether the address works is up to the mail server.
+fn validate_email(email: &str) -> Result<(), AuthError> {
+    let valid = match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty() && domain.contains('.') && !domain.starts_with('.') && !domain.ends_with('.') && !domain.contains('@')
+        }
+        None => false,
+    };
+
+    if !valid || email.chars().any(char::is_whitespace) {
+        return Err(AuthError::InvalidInput(format!("{email} is not an email address")));
+    }
 
-pub fn get_default_users() -> HashMap<String, User> {
+    return Ok(());
+}
+
+/// The users a new store starts with. Each gets a random password, which is printed once to
+/// stderr and cannot be recovered afterwards.
+pub fn get_default_users() -> Result<HashMap<String, User>, AuthError> {
+    let policy = password_policy();
     let mut users = HashMap::new();
 
-    users.insert("admin".to_string(), User::new("admin", "password", LoginRole::Admin));
-    users.insert("bob".to_string(), User::new("bob", "password", LoginRole::User));
+    for (username, role) in [("admin", LoginRole::admin()), ("bob", LoginRole::user())] {
+        let password = policy.generate();
+        eprintln!("Created default user {username} with password: {password}");
+        users.insert(username.to_string(), User::new(username, &password, role)?);
+    }
+
+    return Ok(users);
+}
 
-    return users;
+/// Saves to `users.json` in the project root, see `JsonFileStore::in_project_root`. The file is
+/// encrypted when a key is configured, see `StoreKey::from_env`.
+pub fn save_users(users: HashMap<String, User>) -> Result<(), AuthError> {
+    let result = JsonFileStore::in_project_root().with_key_from_env().and_then(|store| store.save(&users));
+    let event = match &result {
+        Ok(()) => AuditEvent::success(&current_actor(), "users.save", None),
+        Err(error) => AuditEvent::failure(&current_actor(), "users.save", None, &error.to_string()),
+    };
+    audit(event)?;
+
+    return result;
 }
 
-pub fn save_users(users: HashMap<String, User>) {
-    let project_root = get_project_root().unwrap();
-    let users_path = project_root.join(Path::new("users.json"));
+/// Loads `users.json` from the project root, see `JsonFileStore::in_project_root`. Encrypted
+/// files need a key, see `StoreKey::from_env`.
+pub fn get_users() -> Result<HashMap<String, User>, AuthError> {
+    return JsonFileStore::in_project_root().with_key_from_env()?.load();
+}
 
-    let users_json = serde_json::to_string(&users).unwrap();
-    std::fs::write(users_path, users_json).unwrap();
+pub fn get_admin_users(store: &dyn UserStore) -> Result<Vec<User>, AuthError> {
+    return Ok(store.load()?.into_iter().filter(|(_, user)| {
+        return user.has_role(&LoginRole::admin());
+    }).map(|(_, user)| user).collect());
 }
 
-pub fn get_users() -> HashMap<String, User> {
-    let project_root = get_project_root().unwrap();
-    let users_path = project_root.join(Path::new("users.json"));
+/// Checks the store first and then the providers configured in the environment, see
+/// `ProviderChain::from_env`. `Ok(None)` means no provider knows the user, store and hashing
+/// failures are `Err`.
+pub fn login(store: &dyn UserStore, username: &str, password: &str) -> Result<Option<LoginAction>, AuthError> {
+    return ProviderChain::from_env(store)?.authenticate(username, password);
+}
 
+/// `login` with an explicit lockout policy and clock.
+pub fn login_at(
+    store: &dyn UserStore,
+    username: &str,
+    password: &str,
+    policy: &LockoutPolicy,
+    now: u64,
+) -> Result<Option<LoginAction>, AuthError> {
+    return check_credentials(store, username, password, None, policy, now);
+}
 
-    return if users_path.exists() {
-        let users_json = std::fs::read_to_string(users_path).unwrap();
-        let users: HashMap<String, User> = serde_json::from_str(&users_json.as_str()).unwrap();
-        return users;
-    } else {
-        let users = get_default_users();
-        let users_json = serde_json::to_string(&users).unwrap();
-        std::fs::write(users_path, users_json).unwrap();
-        users
-    };
+/// Second step for users with two-factor authentication, after `login` returned
+/// `LoginAction::NeedsSecondFactor`. The password is checked again, so this step cannot be skipped to.
+pub fn login_second_factor(store: &dyn UserStore, username: &str, password: &str, code: &str) -> Result<Option<LoginAction>, AuthError> {
+    return login_second_factor_at(store, username, password, code, &LockoutPolicy::default(), unix_now());
 }
 
-fn get_admin_users() {
-    let users: Vec<User> = get_users().into_iter().filter(|(username, user)| {
-        return user.role == LoginRole::Admin;
-    }).map(|(username, user)| user).collect();
+pub fn login_second_factor_at(
+    store: &dyn UserStore,
+    username: &str,
+    password: &str,
+    code: &str,
+    policy: &LockoutPolicy,
+    now: u64,
+) -> Result<Option<LoginAction>, AuthError> {
+    return check_credentials(store, username, password, Some(code), policy, now);
 }
 
-pub fn login(username: &str, password: &str) -> Option<LoginAction> {
-    let users = get_users();
+/// The outcome of checking one login attempt against the current state of the user.
+struct Attempt {
+    action: LoginAction,
+    /// Why the attempt failed, for the audit log.
+    failure: Option<String>,
+    /// Whether the user has to be saved.
+    changed: bool,
+}
 
-    let hashed_password = hash_password(password);
+fn check_credentials(
+    store: &dyn UserStore,
+    username: &str,
+    password: &str,
+    code: Option<&str>,
+    policy: &LockoutPolicy,
+    now: u64,
+) -> Result<Option<LoginAction>, AuthError> {
+    let failure = |detail: &str| audit(AuditEvent::failure(username, "login", Some(username), detail));
+
+    // Checked and recorded in one update, so concurrent logins and password changes cannot undo
+    // each other's writes.
+    let key = normalize_username(username);
+    let mut attempt = None;
+    store.update(&mut |users| {
+        let Some(found_user) = users.get_mut(&key) else {
+            return Ok(false);
+        };
 
-    if let Some(found_user) = users.get(username) {
-        dbg!(password, &hashed_password, &found_user, &users);
+        let checked = attempt_login(found_user, password, code, policy, now)?;
+        let changed = checked.changed;
+        attempt = Some(checked);
+        return Ok(changed);
+    })?;
 
+    let Some(attempt) = attempt else {
+        failure("unknown user")?;
+        return Ok(None);
+    };
 
-        if found_user.password == hashed_password {
-            return Some(LoginAction::Granted(found_user.role.clone()));
+    match (&attempt.failure, &attempt.action) {
+        (Some(detail), _) => failure(detail)?,
+        (None, LoginAction::Granted(_)) => audit(AuditEvent::success(username, "login", Some(username)))?,
+        (None, _) => {}
+    }
+
+    return Ok(Some(attempt.action));
+}
+
+fn attempt_login(found_user: &mut User, password: &str, code: Option<&str>, policy: &LockoutPolicy, now: u64) -> Result<Attempt, AuthError> {
+    let unchanged = |action: LoginAction, failure: Option<&str>| Attempt { action, failure: failure.map(str::to_string), changed: false };
+
+    if let Some(until) = found_user.locked_until.filter(|until| *until > now) {
+        return Ok(unchanged(LoginAction::Locked { until }, Some("account locked")));
+    }
+
+    let mut verified = verify_password(password, &found_user.password)?;
+    // Only reported to someone who knows the password, like `NeedsSecondFactor`.
+    if verified && found_user.disabled {
+        return Ok(unchanged(LoginAction::Disabled, Some("account disabled")));
+    }
+
+    if verified {
+        if let Some(totp) = found_user.totp.as_mut() {
+            // Recorded once the second step succeeds or fails.
+            let Some(code) = code else {
+                return Ok(unchanged(LoginAction::NeedsSecondFactor, None));
+            };
+            verified = totp.verify(code, now);
+        }
+    }
+
+    if verified {
+        found_user.failed_attempts = 0;
+        found_user.locked_until = None;
+        found_user.last_login_at = Some(now);
+
+        // Transparently upgrade legacy SHA-256 (or outdated Argon2) hashes.
+        if needs_rehash(&found_user.password, &HashParams::default()) {
+            found_user.password = hash_password(password)?;
         }
 
-        println!("Denied!");
-        return Some(LoginAction::Denied);
+        return Ok(Attempt { action: LoginAction::Granted(found_user.roles.clone()), failure: None, changed: true });
+    }
+
+    found_user.failed_attempts += 1;
+    let lockout = policy.lockout_duration(found_user.failed_attempts);
+    found_user.locked_until = lockout.map(|seconds| now + seconds);
+
+    let reason = if code.is_some() { "invalid password or one-time code" } else { "invalid password" };
+    return Ok(match lockout {
+        Some(seconds) => Attempt { action: LoginAction::Locked { until: now + seconds }, failure: Some(format!("{reason}, account locked")), changed: true },
+        None => Attempt { action: LoginAction::Denied, failure: Some(reason.to_string()), changed: true },
+    });
+}
+
+/// Same as `login`, but every outcome other than a granted login is reported as an `AuthError`.
+pub fn authenticate(store: &dyn UserStore, username: &str, password: &str) -> Result<Vec<LoginRole>, AuthError> {
+    return authenticate_with(&ProviderChain::from_env(store)?, username, password);
+}
+
+/// `authenticate` against any identity provider.
+pub fn authenticate_with(provider: &dyn IdentityProvider, username: &str, password: &str) -> Result<Vec<LoginRole>, AuthError> {
+    return match provider.authenticate(username, password)? {
+        Some(LoginAction::Granted(roles)) => Ok(roles),
+        Some(LoginAction::Denied) => Err(AuthError::InvalidPassword),
+        Some(LoginAction::Locked { until }) => Err(AuthError::Locked { until }),
+        Some(LoginAction::NeedsSecondFactor) => Err(AuthError::SecondFactorRequired),
+        Some(LoginAction::Disabled) => Err(AuthError::Disabled(username.to_string())),
+        None => Err(AuthError::UserNotFound(username.to_string())),
     };
+}
+
+/// Runs `change` on the user inside one `store.update`, so it sees the current state of the
+/// user and nothing written in between is lost. `change` returns whether it changed the user.
+fn update_user(store: &dyn UserStore, username: &str, change: impl FnOnce(&mut User) -> Result<bool, AuthError>) -> Result<(), AuthError> {
+    let key = normalize_username(username);
+    let mut change = Some(change);
+    let mut found = false;
+
+    store.update(&mut |users| {
+        let Some(user) = users.get_mut(&key) else {
+            return Ok(false);
+        };
+
+        found = true;
+        let change = change.take().expect("update calls the change once");
+        return change(user);
+    })?;
+
+    if !found {
+        return Err(AuthError::UserNotFound(username.to_string()));
+    }
 
-    return None;
+    return Ok(());
 }
 
-pub fn read_line() -> String {
+/// Turns on two-factor authentication, replacing any previous secret and recovery codes.
+pub fn enable_totp(store: &dyn UserStore, username: &str, issuer: &str) -> Result<TotpEnrollment, AuthError> {
+    let mut enrollment = None;
+    update_user(store, username, |user| {
+        let enrolled = TotpConfig::enroll(issuer, &user.username);
+        user.totp = Some(enrolled.config.clone());
+        user.updated_at = Some(unix_now());
+        enrollment = Some(enrolled);
+        return Ok(true);
+    })?;
+
+    return Ok(enrollment.expect("the user was updated"));
+}
+
+pub fn disable_totp(store: &dyn UserStore, username: &str) -> Result<(), AuthError> {
+    return update_user(store, username, |user| {
+        user.totp = None;
+        user.updated_at = Some(unix_now());
+        return Ok(true);
+    });
+}
+
+/// Adds a new user, refusing names that collide with an existing user after normalization.
+pub fn create_user(store: &dyn UserStore, user: User) -> Result<(), AuthError> {
+    let key = normalize_username(&user.username);
+    let mut user = Some(user);
+
+    store.update(&mut |users| {
+        if let Some(existing) = users.get(&key) {
+            return Err(AuthError::UserExists(existing.username.clone()));
+        }
+
+        users.insert(key.clone(), user.take().expect("update calls the change once"));
+        return Ok(true);
+    })?;
+
+    return Ok(());
+}
+
+/// Changes the username of a user. The new name may only differ in spelling from the old one,
+/// or has to be free after normalization.
+pub fn rename_user(store: &dyn UserStore, username: &str, new_username: &str) -> Result<(), AuthError> {
+    let key = normalize_username(username);
+    let new_key = normalize_username(new_username);
+
+    // The check, the removal and the insert happen in one update, so a failure cannot lose the
+    // account and a user added under the new name in the meantime is not overwritten.
+    store.update(&mut |users| {
+        if key != new_key {
+            if let Some(existing) = users.get(&new_key) {
+                return Err(AuthError::UserExists(existing.username.clone()));
+            }
+        }
+        let Some(mut user) = users.remove(&key) else {
+            return Err(AuthError::UserNotFound(username.to_string()));
+        };
+
+        user.username = new_username.trim().to_string();
+        user.updated_at = Some(unix_now());
+        users.insert(new_key.clone(), user);
+        return Ok(true);
+    })?;
+
+    if key != new_key {
+        session::revoke_user_sessions(store, username)?;
+    }
+
+    return Ok(());
+}
+
+/// Changes the display name and email of a user, see `User::with_profile`.
+pub fn update_profile(store: &dyn UserStore, username: &str, display_name: Option<&str>, email: Option<&str>) -> Result<(), AuthError> {
+    return update_user(store, username, |user| {
+        user.set_profile(display_name, email)?;
+        user.updated_at = Some(unix_now());
+        return Ok(true);
+    });
+}
+
+/// Disabling a user also revokes their sessions, enabling clears any lockout.
+pub fn set_disabled(store: &dyn UserStore, username: &str, disabled: bool) -> Result<(), AuthError> {
+    update_user(store, username, |user| {
+        user.disabled = disabled;
+        if !disabled {
+            user.failed_attempts = 0;
+            user.locked_until = None;
+        }
+        user.updated_at = Some(unix_now());
+        return Ok(true);
+    })?;
+
+    if disabled {
+        session::revoke_user_sessions(store, username)?;
+    }
+
+    return Ok(());
+}
+
+/// Sets a new password that satisfies the installed `PasswordPolicy` and was not used recently,
+/// then revokes the sessions of the user.
+pub fn change_password(store: &dyn UserStore, username: &str, new_password: &str) -> Result<(), AuthError> {
+    update_user(store, username, |user| {
+        set_password_hash(user, hash_password(new_password)?, Some(new_password))?;
+        return Ok(true);
+    })?;
+
+    return session::revoke_user_sessions(store, username);
+}
+
+/// Replaces the password hash of a user, pushing the old one to the password history. With the
+/// plain `password`, it is checked against the policy and the history first, otherwise only a
+/// hash taken from the history is refused. Sessions of the user are left to the caller.
+pub fn set_password_hash(user: &mut User, password_hash: String, password: Option<&str>) -> Result<(), AuthError> {
+    let policy = password_policy();
+    let mut previous = vec![user.password.clone()];
+    previous.extend(user.password_history.iter().cloned());
+    match password {
+        Some(password) => policy.check_change(password, &previous)?,
+        None if previous.iter().take(policy.history + 1).any(|hash| *hash == password_hash) => {
+            return Err(AuthError::PasswordReused(policy.history));
+        }
+        None => {}
+    }
+
+    user.password = password_hash;
+    previous.truncate(policy.history);
+    user.password_history = previous;
+    user.updated_at = Some(unix_now());
+
+    return Ok(());
+}
+
+/// Clears the failed-attempt counter and any lockout.
+pub fn unlock_user(store: &dyn UserStore, username: &str) -> Result<(), AuthError> {
+    return update_user(store, username, |user| {
+        user.failed_attempts = 0;
+        user.locked_until = None;
+        return Ok(true);
+    });
+}
+
+/// Adds the role to the user, the role has to be defined in the registry.
+pub fn grant_role(store: &dyn UserStore, registry: &RoleRegistry, username: &str, role: LoginRole) -> Result<(), AuthError> {
+    if !registry.contains(&role) {
+        return Err(AuthError::UnknownRole(role.to_string()));
+    }
+
+    return update_user(store, username, |user| {
+        if user.has_role(&role) {
+            return Ok(false);
+        }
+
+        user.roles.push(role);
+        user.updated_at = Some(unix_now());
+        return Ok(true);
+    });
+}
+
+/// Also revokes the sessions of the user, they hold the roles from the login.
+pub fn revoke_role(store: &dyn UserStore, username: &str, role: &LoginRole) -> Result<(), AuthError> {
+    let mut revoked = false;
+    update_user(store, username, |user| {
+        if !user.has_role(role) {
+            return Ok(false);
+        }
+
+        user.roles.retain(|granted| granted != role);
+        user.updated_at = Some(unix_now());
+        revoked = true;
+        return Ok(true);
+    })?;
+
+    if revoked {
+        session::revoke_user_sessions(store, username)?;
+    }
+    return Ok(());
+}
+
+pub fn read_line() -> Result<String, AuthError> {
     let mut input = String::new();
 
-    std::io::stdin().read_line(&mut input).expect("Failed to read from stdin");
+    std::io::stdin().read_line(&mut input)?;
 
-    return input.trim().to_string();
+    return Ok(input.trim().to_string());
 }
 
+/// Password of the users returned by `test_users`, it satisfies the default policy.
+#[cfg(test)]
+pub(crate) const TEST_PASSWORD: &str = "Correct-Horse-7";
+
+/// The default users with a known password.
+#[cfg(test)]
+pub(crate) fn test_users() -> HashMap<String, User> {
+    let mut users = HashMap::new();
+    users.insert("admin".to_string(), User::new("admin", TEST_PASSWORD, LoginRole::admin()).unwrap());
+    users.insert("bob".to_string(), User::new("bob", TEST_PASSWORD, LoginRole::user()).unwrap());
+
+    return users;
+}
 
 #[cfg(test)]
 mod tests {
@@ -128,11 +610,288 @@ mod tests {
 
     #[test]
     fn test_login() {
-        assert_eq!(login("admin", "password"), Some(LoginAction::Granted(LoginRole::Admin)));
-        assert_eq!(login("Admin", "password"), Some(LoginAction::Granted(LoginRole::Admin)));
-        assert_eq!(login("bob", "password"), Some(LoginAction::Granted(LoginRole::User)));
+        let store = MemoryStore::with_users(test_users());
+
+        assert_eq!(login(&store, "admin", TEST_PASSWORD).unwrap(), Some(LoginAction::Granted(vec![LoginRole::admin()])));
+        assert_eq!(login(&store, "Admin", TEST_PASSWORD).unwrap(), Some(LoginAction::Granted(vec![LoginRole::admin()])));
+        assert_eq!(login(&store, "bob", TEST_PASSWORD).unwrap(), Some(LoginAction::Granted(vec![LoginRole::user()])));
+
+        assert_eq!(login(&store, "admin", "passwrd").unwrap(), Some(LoginAction::Denied));
+        assert_eq!(login(&store, "admin1", TEST_PASSWORD).unwrap(), None);
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let store = MemoryStore::with_users(test_users());
+
+        assert_eq!(authenticate(&store, "bob", TEST_PASSWORD).unwrap(), vec![LoginRole::user()]);
+        assert!(matches!(authenticate(&store, "bob", "passwrd"), Err(AuthError::InvalidPassword)));
+        assert!(matches!(authenticate(&store, "carol", TEST_PASSWORD), Err(AuthError::UserNotFound(name)) if name == "carol"));
+    }
+
+    #[test]
+    fn test_authenticate_with_provider_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let passwords = dir.path().join(".htpasswd");
+        std::fs::write(&passwords, "carol:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\n").unwrap();
+        let store = MemoryStore::with_users(test_users());
+        let chain = ProviderChain::new().with(StoreProvider::new(&store)).with(HtpasswdProvider::new(&passwords));
+
+        assert_eq!(authenticate_with(&chain, "bob", TEST_PASSWORD).unwrap(), vec![LoginRole::user()]);
+        assert_eq!(authenticate_with(&chain, "carol", "password").unwrap(), vec![LoginRole::user()]);
+        assert!(matches!(authenticate_with(&chain, "carol", "passwrd"), Err(AuthError::InvalidPassword)));
+        assert!(matches!(authenticate_with(&chain, "dave", "password"), Err(AuthError::UserNotFound(name)) if name == "dave"));
+    }
+
+    #[test]
+    fn test_login_upgrades_legacy_hash() {
+        let legacy_hash = "5E884898DA28047151D0E56F8DC6292773603D0D6AABBDD62A11EF721D1542D8";
+        let mut users = HashMap::new();
+        users.insert("bob".to_string(), User::from_hash("bob", legacy_hash, LoginRole::user()).unwrap());
+        let store = MemoryStore::with_users(users);
+
+        assert_eq!(login(&store, "bob", "password").unwrap(), Some(LoginAction::Granted(vec![LoginRole::user()])));
+
+        let upgraded = store.get("bob").unwrap().unwrap().password;
+        assert!(upgraded.starts_with("$argon2id$"));
+        assert_eq!(login(&store, "bob", "password").unwrap(), Some(LoginAction::Granted(vec![LoginRole::user()])));
+    }
+
+    #[test]
+    fn test_login_locks_out_after_failed_attempts() {
+        let store = MemoryStore::with_users(test_users());
+        let policy = LockoutPolicy { max_attempts: 2, lockout_seconds: 60, max_lockout_seconds: 600 };
+        let now = 1_000;
+
+        assert_eq!(login_at(&store, "bob", "wrong", &policy, now).unwrap(), Some(LoginAction::Denied));
+        assert_eq!(login_at(&store, "bob", "wrong", &policy, now).unwrap(), Some(LoginAction::Locked { until: 1_060 }));
+
+        // The correct password does not help while locked.
+        assert_eq!(login_at(&store, "bob", TEST_PASSWORD, &policy, now + 59).unwrap(), Some(LoginAction::Locked { until: 1_060 }));
+
+        // Failing again after the lockout expires doubles it.
+        assert_eq!(login_at(&store, "bob", "wrong", &policy, now + 60).unwrap(), Some(LoginAction::Locked { until: 1_180 }));
+        assert_eq!(store.get("bob").unwrap().unwrap().failed_attempts, 3);
+
+        assert_eq!(login_at(&store, "bob", TEST_PASSWORD, &policy, now + 180).unwrap(), Some(LoginAction::Granted(vec![LoginRole::user()])));
+        let bob = store.get("bob").unwrap().unwrap();
+        assert_eq!(bob.failed_attempts, 0);
+        assert_eq!(bob.locked_until, None);
+    }
+
+    #[test]
+    fn test_unlock_user() {
+        let store = MemoryStore::with_users(test_users());
+        let policy = LockoutPolicy { max_attempts: 1, ..LockoutPolicy::default() };
+        let now = unix_now();
+
+        assert!(matches!(login_at(&store, "bob", "wrong", &policy, now).unwrap(), Some(LoginAction::Locked { .. })));
+
+        unlock_user(&store, "bob").unwrap();
+        assert_eq!(login_at(&store, "bob", TEST_PASSWORD, &policy, now).unwrap(), Some(LoginAction::Granted(vec![LoginRole::user()])));
+        assert!(matches!(unlock_user(&store, "carol"), Err(AuthError::UserNotFound(_))));
+    }
+
+    #[test]
+    fn test_legacy_users_json_migrates_roles() {
+        let users_json = r#"{
+            "admin": {"username": "admin", "password": "5E884898DA28047151D0E56F8DC6292773603D0D6AABBDD62A11EF721D1542D8", "role": "Admin"},
+            "bob": {"username": "bob", "password": "5E884898DA28047151D0E56F8DC6292773603D0D6AABBDD62A11EF721D1542D8", "role": "User"}
+        }"#;
+        let users: HashMap<String, User> = serde_json::from_str(users_json).unwrap();
+
+        assert_eq!(users["admin"].roles, vec![LoginRole::admin()]);
+        assert_eq!(users["bob"].roles, vec![LoginRole::user()]);
+        assert!(users["admin"].can(permissions::USERS_WRITE));
+        assert!(!users["bob"].can(permissions::USERS_WRITE));
+        assert!(users["bob"].can(permissions::IMAGES_UPLOAD));
+        assert!(!users["bob"].disabled && users["bob"].created_at.is_none());
+
+        let saved = serde_json::to_value(&users["bob"]).unwrap();
+        assert_eq!(saved["roles"], serde_json::json!(["user"]));
+        assert!(saved.get("role").is_none());
+    }
+
+    #[test]
+    fn test_grant_and_revoke_roles() {
+        let store = MemoryStore::with_users(test_users());
+        let mut registry = RoleRegistry::builtin();
+        registry.define(LoginRole::new("auditor"), [permissions::USERS_READ]);
+
+        grant_role(&store, &registry, "bob", LoginRole::new("auditor")).unwrap();
+        grant_role(&store, &registry, "bob", LoginRole::new("auditor")).unwrap();
+        let bob = store.get("bob").unwrap().unwrap();
+        assert_eq!(bob.roles, vec![LoginRole::user(), LoginRole::new("auditor")]);
+        assert!(registry.allows(&bob.roles, permissions::USERS_READ));
+
+        revoke_role(&store, "bob", &LoginRole::user()).unwrap();
+        assert_eq!(store.get("bob").unwrap().unwrap().roles, vec![LoginRole::new("auditor")]);
+
+        // Sessions hold the roles from the login, so losing one ends them.
+        let (token, session) = issue_session(&store, "admin", vec![LoginRole::admin()], 60, unix_now()).unwrap();
+        assert!(session.can(permissions::USERS_WRITE));
+        let updated_at = store.get("admin").unwrap().unwrap().updated_at;
+        revoke_role(&store, "admin", &LoginRole::user()).unwrap();
+        assert_eq!(store.get("admin").unwrap().unwrap().updated_at, updated_at);
+        assert!(validate_session(&store, &token, unix_now()).unwrap().can(permissions::USERS_WRITE));
+        revoke_role(&store, "admin", &LoginRole::admin()).unwrap();
+        assert!(matches!(validate_session(&store, &token, unix_now()), Err(AuthError::InvalidSession)));
+
+        assert!(matches!(grant_role(&store, &registry, "bob", LoginRole::new("ghost")), Err(AuthError::UnknownRole(_))));
+        assert!(matches!(grant_role(&store, &registry, "carol", LoginRole::user()), Err(AuthError::UserNotFound(_))));
+    }
+
+    #[test]
+    fn test_create_user_rejects_normalized_collisions() {
+        let store = MemoryStore::with_users(test_users());
+
+        create_user(&store, User::new("Carol", TEST_PASSWORD, LoginRole::user()).unwrap()).unwrap();
+        assert_eq!(login(&store, "carol", TEST_PASSWORD).unwrap(), Some(LoginAction::Granted(vec![LoginRole::user()])));
+
+        let result = create_user(&store, User::new(" CAROL", TEST_PASSWORD, LoginRole::admin()).unwrap());
+        assert!(matches!(result, Err(AuthError::UserExists(name)) if name == "Carol"));
+        let result = create_user(&store, User::new("ａｄｍｉｎ", TEST_PASSWORD, LoginRole::user()).unwrap());
+        assert!(matches!(result, Err(AuthError::UserExists(name)) if name == "admin"));
+    }
+
+    #[test]
+    fn test_rename_user() {
+        let store = MemoryStore::with_users(test_users());
+
+        rename_user(&store, "bob", "Bob").unwrap();
+        assert_eq!(store.get("BOB").unwrap().unwrap().username, "Bob");
+
+        rename_user(&store, "bob", "Robert").unwrap();
+        assert!(store.get("bob").unwrap().is_none());
+        assert_eq!(login(&store, "robert", TEST_PASSWORD).unwrap(), Some(LoginAction::Granted(vec![LoginRole::user()])));
+
+        assert!(matches!(rename_user(&store, "robert", "Admin"), Err(AuthError::UserExists(_))));
+        // A refused rename leaves both accounts as they were.
+        assert!(store.get("robert").unwrap().is_some());
+        assert!(store.get("admin").unwrap().unwrap().has_role(&LoginRole::admin()));
+        assert!(matches!(rename_user(&store, "bob", "bobby"), Err(AuthError::UserNotFound(_))));
+    }
+
+    #[test]
+    fn test_disabled_users_cannot_log_in() {
+        let store = MemoryStore::with_users(test_users());
+        let (token, _) = session::login_session(&store, "bob", TEST_PASSWORD, DEFAULT_SESSION_TTL).unwrap();
+        assert!(store.get("bob").unwrap().unwrap().last_login_at.is_some());
+
+        set_disabled(&store, "bob", true).unwrap();
+        assert!(matches!(validate_session(&store, &token, unix_now()), Err(AuthError::InvalidSession)));
+        assert_eq!(login(&store, "bob", TEST_PASSWORD).unwrap(), Some(LoginAction::Disabled));
+        assert_eq!(login(&store, "bob", "wrong").unwrap(), Some(LoginAction::Denied));
+        assert!(matches!(authenticate(&store, "bob", TEST_PASSWORD), Err(AuthError::Disabled(_))));
+
+        set_disabled(&store, "bob", false).unwrap();
+        assert_eq!(login(&store, "bob", TEST_PASSWORD).unwrap(), Some(LoginAction::Granted(vec![LoginRole::user()])));
+    }
+
+    #[test]
+    fn test_update_profile() {
+        let store = MemoryStore::with_users(test_users());
+
+        update_profile(&store, "bob", Some(" Bob Builder "), Some("bob@example.com")).unwrap();
+        update_profile(&store, "bob", None, Some("")).unwrap();
+        let bob = store.get("bob").unwrap().unwrap();
+        assert_eq!((bob.name(), bob.email.as_deref()), ("Bob Builder", None));
+
+        for email in ["bob", "bob@localhost", "@example.com", "bob@example.com.", "bob smith@example.com"] {
+            assert!(matches!(update_profile(&store, "bob", None, Some(email)), Err(AuthError::InvalidInput(_))), "{email}");
+        }
+    }
+
+    #[test]
+    fn test_login_with_second_factor() {
+        let store = MemoryStore::with_users(test_users());
+        let policy = LockoutPolicy { max_attempts: 3, ..LockoutPolicy::default() };
+        let enrollment = enable_totp(&store, "admin", "Ardan Labs").unwrap();
+        let secret = data_encoding::BASE32_NOPAD.decode(enrollment.config.secret.as_bytes()).unwrap();
+        let now = 1_700_000_000;
+        let code = format!("{:06}", totp_code(&secret, now / 30));
+
+        assert_eq!(login_at(&store, "admin", TEST_PASSWORD, &policy, now).unwrap(), Some(LoginAction::NeedsSecondFactor));
+        assert_eq!(login_at(&store, "admin", "wrong", &policy, now).unwrap(), Some(LoginAction::Denied));
+        assert!(matches!(authenticate(&store, "admin", TEST_PASSWORD), Err(AuthError::SecondFactorRequired)));
+
+        // A valid code does not help with the wrong password.
+        assert_eq!(login_second_factor_at(&store, "admin", "wrong", &code, &policy, now).unwrap(), Some(LoginAction::Denied));
+        assert_eq!(
+            login_second_factor_at(&store, "admin", TEST_PASSWORD, &code, &policy, now).unwrap(),
+            Some(LoginAction::Granted(vec![LoginRole::admin()]))
+        );
+        assert_eq!(store.get("admin").unwrap().unwrap().failed_attempts, 0);
+
+        // Codes are single use, recovery codes work instead.
+        assert_eq!(login_second_factor_at(&store, "admin", TEST_PASSWORD, &code, &policy, now).unwrap(), Some(LoginAction::Denied));
+        assert_eq!(
+            login_second_factor_at(&store, "admin", TEST_PASSWORD, &enrollment.recovery_codes[0], &policy, now).unwrap(),
+            Some(LoginAction::Granted(vec![LoginRole::admin()]))
+        );
+
+        disable_totp(&store, "admin").unwrap();
+        assert_eq!(login_at(&store, "admin", TEST_PASSWORD, &policy, now).unwrap(), Some(LoginAction::Granted(vec![LoginRole::admin()])));
+    }
+
+    #[test]
+    fn test_change_password_enforces_policy() {
+        let store = MemoryStore::with_users(test_users());
+
+        assert!(matches!(change_password(&store, "bob", ""), Err(AuthError::WeakPassword(_))));
+        assert!(matches!(change_password(&store, "bob", TEST_PASSWORD), Err(AuthError::PasswordReused(_))));
+        assert!(matches!(change_password(&store, "carol", "Another-Horse-8"), Err(AuthError::UserNotFound(_))));
+
+        change_password(&store, "bob", "Another-Horse-8").unwrap();
+        change_password(&store, "bob", "Third-Horse-9").unwrap();
+        assert_eq!(login(&store, "bob", "Third-Horse-9").unwrap(), Some(LoginAction::Granted(vec![LoginRole::user()])));
+        assert_eq!(store.get("bob").unwrap().unwrap().password_history.len(), 2);
+        assert!(matches!(change_password(&store, "bob", TEST_PASSWORD), Err(AuthError::PasswordReused(_))));
+
+        assert!(matches!(User::new("carol", "password", LoginRole::user()), Err(AuthError::WeakPassword(_))));
+    }
+
+    #[test]
+    fn test_default_users_get_random_passwords() {
+        let users = get_default_users().unwrap();
+
+        assert_eq!(users.len(), 2);
+        assert!(!verify_password("password", &users["admin"].password).unwrap());
+        assert_ne!(users["admin"].password, users["bob"].password);
+    }
+
+    #[test]
+    fn test_login_is_audited() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("audit.jsonl");
+        let mut users = test_users();
+        users.insert("erin".to_string(), User::new("erin", TEST_PASSWORD, LoginRole::user()).unwrap());
+        let store = MemoryStore::with_users(users);
+
+        // The audit log is process-wide, put the previous one back before anything can panic.
+        let previous = audit::replace_audit_log(Some(AuditLog::new(&path)));
+        let first = login(&store, "erin", TEST_PASSWORD);
+        let second = login(&store, "Erin", "wrong");
+        audit::replace_audit_log(previous);
+        first.unwrap();
+        second.unwrap();
+
+        let filter = AuditFilter { target: Some("erin".to_string()), ..AuditFilter::default() };
+        let events = AuditLog::new(&path).read(&filter).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!((events[0].event.as_str(), events[0].outcome), ("login", AuditOutcome::Success));
+        assert_eq!((events[1].outcome, events[1].detail.as_deref()), (AuditOutcome::Failure, Some("invalid password")));
+
+        let audit_json = std::fs::read_to_string(&path).unwrap();
+        assert!(!audit_json.contains("$argon2id$"));
+    }
+
+    #[test]
+    fn test_get_admin_users() {
+        let store = MemoryStore::with_users(test_users());
+        let admins = get_admin_users(&store).unwrap();
 
-        assert_eq!(login("admin", "passwrd"), Some(LoginAction::Denied));
-        assert_eq!(login("admin1", "password"), None);
+        assert_eq!(admins.len(), 1);
+        assert_eq!(admins[0].username, "admin");
     }
 }
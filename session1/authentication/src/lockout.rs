@@ -0,0 +1,50 @@
+/// How many failed logins a user gets before the account is locked, and for how long.
+///
+/// Every failure past `max_attempts` doubles the lockout, up to `max_lockout_seconds`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockoutPolicy {
+    pub max_attempts: u32,
+    pub lockout_seconds: u64,
+    pub max_lockout_seconds: u64,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        return Self {
+            max_attempts: 5,
+            lockout_seconds: 60,
+            max_lockout_seconds: 60 * 60,
+        };
+    }
+}
+
+impl LockoutPolicy {
+    /// Lockout length after the given number of consecutive failures, `None` while below the threshold.
+    pub fn lockout_duration(&self, failed_attempts: u32) -> Option<u64> {
+        if failed_attempts < self.max_attempts {
+            return None;
+        }
+
+        let doublings = (failed_attempts - self.max_attempts).min(32);
+        let duration = self.lockout_seconds.saturating_mul(1 << doublings);
+
+        return Some(duration.min(self.max_lockout_seconds));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lockout_duration_backs_off_exponentially() {
+        let policy = LockoutPolicy { max_attempts: 3, lockout_seconds: 60, max_lockout_seconds: 300 };
+
+        assert_eq!(policy.lockout_duration(2), None);
+        assert_eq!(policy.lockout_duration(3), Some(60));
+        assert_eq!(policy.lockout_duration(4), Some(120));
+        assert_eq!(policy.lockout_duration(5), Some(240));
+        assert_eq!(policy.lockout_duration(6), Some(300));
+        assert_eq!(policy.lockout_duration(100), Some(300));
+    }
+}
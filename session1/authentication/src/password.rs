@@ -0,0 +1,136 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+
+use crate::AuthError;
+
+/// Cost parameters for the Argon2id password hash.
+///
+/// The values are stored inside the PHC string, so raising them later only affects new hashes;
+/// older hashes keep verifying and get flagged by `needs_rehash`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HashParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for HashParams {
+    fn default() -> Self {
+        return Self {
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        };
+    }
+}
+
+impl HashParams {
+    fn hasher(&self) -> Result<Argon2<'static>, AuthError> {
+        let params = Params::new(self.memory_kib, self.iterations, self.parallelism, None)
+            .map_err(|error| AuthError::Hashing(error.to_string()))?;
+
+        return Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params));
+    }
+}
+
+/// Hashes the password with Argon2id and a random salt, using the default cost parameters.
+pub fn hash_password(password: &str) -> Result<String, AuthError> {
+    return hash_password_with(password, &HashParams::default());
+}
+
+pub fn hash_password_with(password: &str, params: &HashParams) -> Result<String, AuthError> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = params.hasher()?.hash_password(password.as_bytes(), &salt)?;
+
+    return Ok(hash.to_string());
+}
+
+/// Checks the password against a stored PHC string, or against a legacy unsalted SHA-256 hash.
+/// A stored value that is neither is reported as a corrupt store.
+pub fn verify_password(password: &str, stored: &str) -> Result<bool, AuthError> {
+    if is_legacy_hash(stored) {
+        return Ok(legacy_sha256(password) == stored.to_uppercase());
+    }
+
+    let hash = PasswordHash::new(stored)
+        .map_err(|error| AuthError::CorruptStore(format!("Invalid password hash: {error}")))?;
+
+    return match Argon2::default().verify_password(password.as_bytes(), &hash) {
+        Ok(()) => Ok(true),
+        Err(argon2::password_hash::Error::Password) => Ok(false),
+        Err(error) => Err(error.into()),
+    };
+}
+
+/// Whether the stored hash should be replaced after the next successful verification.
+pub fn needs_rehash(stored: &str, params: &HashParams) -> bool {
+    if is_legacy_hash(stored) {
+        return true;
+    }
+
+    let Ok(hash) = PasswordHash::new(stored) else {
+        return false;
+    };
+    let Ok(stored_params) = Params::try_from(&hash) else {
+        return true;
+    };
+
+    return hash.algorithm != Algorithm::Argon2id.ident()
+        || stored_params.m_cost() != params.memory_kib
+        || stored_params.t_cost() != params.iterations
+        || stored_params.p_cost() != params.parallelism;
+}
+
+fn is_legacy_hash(stored: &str) -> bool {
+    return stored.len() == 64 && stored.chars().all(|c| c.is_ascii_hexdigit());
+}
+
+fn legacy_sha256(password: &str) -> String {
+    use sha2::Digest;
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(password);
+
+    return format!("{:X}", hasher.finalize());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_is_salted_phc_string() {
+        let first = hash_password("password").unwrap();
+        let second = hash_password("password").unwrap();
+
+        assert!(first.starts_with("$argon2id$"));
+        assert_ne!(first, second);
+        assert!(verify_password("password", &first).unwrap());
+        assert!(verify_password("password", &second).unwrap());
+        assert!(!verify_password("passwrd", &first).unwrap());
+    }
+
+    #[test]
+    fn test_legacy_hash_verifies_and_needs_rehash() {
+        let legacy = legacy_sha256("password");
+
+        assert!(verify_password("password", &legacy).unwrap());
+        assert!(!verify_password("passwrd", &legacy).unwrap());
+        assert!(needs_rehash(&legacy, &HashParams::default()));
+    }
+
+    #[test]
+    fn test_needs_rehash_on_changed_params() {
+        let cheap = HashParams { memory_kib: 8, iterations: 1, parallelism: 1 };
+        let hash = hash_password_with("password", &cheap).unwrap();
+
+        assert!(verify_password("password", &hash).unwrap());
+        assert!(!needs_rehash(&hash, &cheap));
+        assert!(needs_rehash(&hash, &HashParams::default()));
+    }
+
+    #[test]
+    fn test_malformed_hash_is_an_error() {
+        assert!(matches!(verify_password("password", "not a hash"), Err(AuthError::CorruptStore(_))));
+    }
+}
@@ -0,0 +1,184 @@
+use std::sync::RwLock;
+
+use once_cell::sync::Lazy;
+use rand::Rng;
+
+use crate::{verify_password, AuthError};
+
+/// Passwords that are rejected regardless of their length or character classes, compared
+/// case-insensitively.
+const COMMON_PASSWORDS: &[&str] = &[
+    "password", "password1", "password12", "password123", "password1234", "passw0rd", "p@ssw0rd", "p@ssword",
+    "123456", "1234567", "12345678", "123456789", "1234567890", "12345678910", "111111", "000000", "654321",
+    "123123", "123321", "qwerty", "qwerty123", "qwertyuiop", "1q2w3e4r", "1q2w3e4r5t", "asdfghjkl", "zxcvbnm",
+    "abc123", "abcd1234", "iloveyou", "letmein", "letmein123", "welcome", "welcome1", "welcome123", "admin",
+    "admin123", "administrator", "root", "toor", "changeme", "changeme123", "secret", "monkey", "dragon",
+    "football", "baseball", "superman", "batman", "trustno1", "sunshine", "princess", "shadow", "master",
+    "starwars", "whatever", "freedom", "qazwsx", "correcthorsebatterystaple",
+];
+
+/// Rules a new password has to satisfy, see `install_password_policy`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub require_lowercase: bool,
+    pub require_uppercase: bool,
+    pub require_digit: bool,
+    pub require_symbol: bool,
+    /// Reject passwords from the built-in list of common passwords.
+    pub reject_common: bool,
+    /// How many previous password hashes are kept per user and may not be reused.
+    pub history: usize,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        return Self {
+            min_length: 12,
+            require_lowercase: true,
+            require_uppercase: true,
+            require_digit: true,
+            require_symbol: false,
+            reject_common: true,
+            history: 5,
+        };
+    }
+}
+
+impl PasswordPolicy {
+    /// Accepts every password, for tests and tooling that import existing accounts.
+    pub fn permissive() -> Self {
+        return Self {
+            min_length: 0,
+            require_lowercase: false,
+            require_uppercase: false,
+            require_digit: false,
+            require_symbol: false,
+            reject_common: false,
+            history: 0,
+        };
+    }
+
+    /// Checks the password on its own, listing every rule it breaks.
+    pub fn check(&self, password: &str) -> Result<(), AuthError> {
+        let mut problems = Vec::new();
+
+        if password.chars().count() < self.min_length {
+            problems.push(format!("it must be at least {} characters long", self.min_length));
+        }
+        if self.require_lowercase && !password.chars().any(char::is_lowercase) {
+            problems.push("it must contain a lowercase letter".to_string());
+        }
+        if self.require_uppercase && !password.chars().any(char::is_uppercase) {
+            problems.push("it must contain an uppercase letter".to_string());
+        }
+        if self.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            problems.push("it must contain a digit".to_string());
+        }
+        if self.require_symbol && !password.chars().any(|c| !c.is_alphanumeric() && !c.is_whitespace()) {
+            problems.push("it must contain a symbol".to_string());
+        }
+        if self.reject_common && is_common(password) {
+            problems.push("it is a commonly used password".to_string());
+        }
+
+        if !problems.is_empty() {
+            return Err(AuthError::WeakPassword(problems.join(", ")));
+        }
+
+        return Ok(());
+    }
+
+    /// Checks the password and that it matches none of the given current and previous hashes.
+    pub fn check_change(&self, password: &str, previous_hashes: &[String]) -> Result<(), AuthError> {
+        self.check(password)?;
+
+        for hash in previous_hashes.iter().take(self.history + 1) {
+            if verify_password(password, hash)? {
+                return Err(AuthError::PasswordReused(self.history));
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// A random password that satisfies this policy.
+    pub fn generate(&self) -> String {
+        const ALPHABET: &[u8] = b"abcdefghijkmnpqrstuvwxyzABCDEFGHJKLMNPQRSTUVWXYZ23456789-_.!#%+";
+        let length = self.min_length.max(20);
+        let mut rng = rand::rngs::OsRng;
+
+        loop {
+            let password: String = (0..length).map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char).collect();
+            if self.check(&password).is_ok() {
+                return password;
+            }
+        }
+    }
+}
+
+fn is_common(password: &str) -> bool {
+    let password = password.to_lowercase();
+    return COMMON_PASSWORDS.iter().any(|common| *common == password);
+}
+
+static POLICY: Lazy<RwLock<PasswordPolicy>> = Lazy::new(|| {
+    return RwLock::new(PasswordPolicy::default());
+});
+
+/// Replaces the process-wide policy enforced by `User::new` and `change_password`.
+pub fn install_password_policy(policy: PasswordPolicy) {
+    *POLICY.write().unwrap() = policy;
+}
+
+pub fn password_policy() -> PasswordPolicy {
+    return POLICY.read().unwrap().clone();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy_lists_every_problem() {
+        let policy = PasswordPolicy::default();
+
+        assert!(policy.check("Tr0ub4dor&3-horse").is_ok());
+        let Err(AuthError::WeakPassword(problems)) = policy.check("short") else {
+            panic!("a short password was accepted");
+        };
+        assert_eq!(problems, "it must be at least 12 characters long, it must contain an uppercase letter, it must contain a digit");
+
+        assert!(matches!(policy.check("Password1234"), Err(AuthError::WeakPassword(problems)) if problems == "it is a commonly used password"));
+        assert!(matches!(policy.check(""), Err(AuthError::WeakPassword(_))));
+
+        let with_symbol = PasswordPolicy { require_symbol: true, ..PasswordPolicy::default() };
+        assert!(with_symbol.check("Tr0ub4dorHorse").is_err());
+        assert!(PasswordPolicy::permissive().check("").is_ok());
+    }
+
+    #[test]
+    fn test_policy_rejects_reused_passwords() {
+        let policy = PasswordPolicy { history: 1, ..PasswordPolicy::default() };
+        let hashes = vec![
+            crate::hash_password("Current-Passw0rd").unwrap(),
+            crate::hash_password("Previous-Passw0rd").unwrap(),
+            crate::hash_password("Ancient-Passw0rd").unwrap(),
+        ];
+
+        assert!(matches!(policy.check_change("Current-Passw0rd", &hashes), Err(AuthError::PasswordReused(1))));
+        assert!(matches!(policy.check_change("Previous-Passw0rd", &hashes), Err(AuthError::PasswordReused(1))));
+        // Only the last `history` hashes are remembered.
+        assert!(policy.check_change("Ancient-Passw0rd", &hashes).is_ok());
+    }
+
+    #[test]
+    fn test_generated_passwords_satisfy_the_policy() {
+        let policy = PasswordPolicy { require_symbol: true, min_length: 24, ..PasswordPolicy::default() };
+        let password = policy.generate();
+
+        assert_eq!(password.len(), 24);
+        assert!(policy.check(&password).is_ok());
+        assert_ne!(password, policy.generate());
+    }
+}
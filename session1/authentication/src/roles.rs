@@ -0,0 +1,205 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::Path;
+use std::sync::RwLock;
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::AuthError;
+
+pub mod permissions {
+    pub const ALL: &str = "*";
+    pub const USERS_READ: &str = "users:read";
+    pub const USERS_WRITE: &str = "users:write";
+    pub const ROLES_WRITE: &str = "roles:write";
+    pub const IMAGES_READ: &str = "images:read";
+    pub const IMAGES_UPLOAD: &str = "images:upload";
+}
+
+/// A role name. Names are case-insensitive, so the `Admin`/`User` values of the old
+/// `LoginRole` enum load as the built-in `admin`/`user` roles.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Serialize)]
+#[serde(transparent)]
+pub struct LoginRole(String);
+
+impl LoginRole {
+    pub fn new(name: &str) -> Self {
+        return Self(name.trim().to_lowercase());
+    }
+
+    pub fn admin() -> Self {
+        return Self::new("admin");
+    }
+
+    pub fn user() -> Self {
+        return Self::new("user");
+    }
+
+    pub fn name(&self) -> &str {
+        return &self.0;
+    }
+}
+
+impl fmt::Display for LoginRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return f.write_str(&self.0);
+    }
+}
+
+impl<'de> Deserialize<'de> for LoginRole {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        return Ok(LoginRole::new(&name));
+    }
+}
+
+/// Accepts both the legacy single `"role": "Admin"` and the current `"roles": ["admin"]`.
+pub(crate) fn deserialize_roles<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<LoginRole>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(LoginRole),
+        Many(Vec<LoginRole>),
+    }
+
+    return Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(role) => vec![role],
+        OneOrMany::Many(roles) => roles,
+    });
+}
+
+/// Role definitions, as stored in `roles.json`: role name to the permissions it grants.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RoleRegistry {
+    roles: BTreeMap<LoginRole, BTreeSet<String>>,
+}
+
+impl Default for RoleRegistry {
+    fn default() -> Self {
+        return Self::builtin();
+    }
+}
+
+impl RoleRegistry {
+    pub fn builtin() -> Self {
+        let mut registry = Self { roles: BTreeMap::new() };
+        registry.define(LoginRole::admin(), [permissions::ALL]);
+        registry.define(LoginRole::user(), [permissions::IMAGES_READ, permissions::IMAGES_UPLOAD]);
+
+        return registry;
+    }
+
+    /// Reads role definitions from a JSON file, a missing file means the built-in roles.
+    pub fn load(path: &Path) -> Result<Self, AuthError> {
+        if !path.exists() {
+            return Ok(Self::builtin());
+        }
+
+        let roles_json = std::fs::read_to_string(path)?;
+        return serde_json::from_str(&roles_json).map_err(|error| {
+            return AuthError::CorruptStore(format!("{}: {error}", path.display()));
+        });
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), AuthError> {
+        let roles_json = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, roles_json)?;
+
+        return Ok(());
+    }
+
+    /// Creates the role, or replaces the permissions of an existing one.
+    pub fn define<I, P>(&mut self, role: LoginRole, permissions: I)
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<String>,
+    {
+        self.roles.insert(role, permissions.into_iter().map(Into::into).collect());
+    }
+
+    pub fn remove(&mut self, role: &LoginRole) -> bool {
+        return self.roles.remove(role).is_some();
+    }
+
+    pub fn contains(&self, role: &LoginRole) -> bool {
+        return self.roles.contains_key(role);
+    }
+
+    pub fn roles(&self) -> impl Iterator<Item = (&LoginRole, &BTreeSet<String>)> {
+        return self.roles.iter();
+    }
+
+    /// Whether any of the roles grants the permission. `*` grants everything, unknown roles nothing.
+    pub fn allows(&self, roles: &[LoginRole], permission: &str) -> bool {
+        return roles
+            .iter()
+            .filter_map(|role| self.roles.get(role))
+            .any(|granted| granted.contains(permissions::ALL) || granted.contains(permission));
+    }
+}
+
+static ROLES: Lazy<RwLock<RoleRegistry>> = Lazy::new(|| {
+    return RwLock::new(RoleRegistry::builtin());
+});
+
+/// Replaces the process-wide role definitions used by `User::can` and `Session::can`.
+pub fn install_roles(registry: RoleRegistry) {
+    *ROLES.write().unwrap() = registry;
+}
+
+pub fn installed_roles() -> RoleRegistry {
+    return ROLES.read().unwrap().clone();
+}
+
+pub(crate) fn roles_allow(roles: &[LoginRole], permission: &str) -> bool {
+    return ROLES.read().unwrap().allows(roles, permission);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_roles() {
+        let registry = RoleRegistry::builtin();
+
+        assert!(registry.allows(&[LoginRole::admin()], permissions::USERS_WRITE));
+        assert!(registry.allows(&[LoginRole::user()], permissions::IMAGES_UPLOAD));
+        assert!(!registry.allows(&[LoginRole::user()], permissions::USERS_WRITE));
+        assert!(!registry.allows(&[LoginRole::new("unknown")], permissions::IMAGES_READ));
+        assert!(!registry.allows(&[], permissions::IMAGES_READ));
+    }
+
+    #[test]
+    fn test_permissions_combine_across_roles() {
+        let mut registry = RoleRegistry::builtin();
+        registry.define(LoginRole::new("auditor"), [permissions::USERS_READ]);
+        let roles = [LoginRole::user(), LoginRole::new("Auditor")];
+
+        assert!(registry.allows(&roles, permissions::USERS_READ));
+        assert!(registry.allows(&roles, permissions::IMAGES_UPLOAD));
+        assert!(!registry.allows(&roles, permissions::USERS_WRITE));
+    }
+
+    #[test]
+    fn test_registry_round_trips_through_a_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("roles.json");
+
+        assert_eq!(RoleRegistry::load(&path).unwrap(), RoleRegistry::builtin());
+
+        let mut registry = RoleRegistry::builtin();
+        registry.define(LoginRole::new("uploader"), [permissions::IMAGES_UPLOAD]);
+        registry.save(&path).unwrap();
+
+        assert_eq!(RoleRegistry::load(&path).unwrap(), registry);
+    }
+
+    #[test]
+    fn test_legacy_role_names_migrate() {
+        let roles: Vec<LoginRole> = serde_json::from_str(r#"["Admin", "User"]"#).unwrap();
+        assert_eq!(roles, vec![LoginRole::admin(), LoginRole::user()]);
+    }
+}
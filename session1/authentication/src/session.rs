@@ -0,0 +1,158 @@
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::{authenticate, AuthError, LoginRole, UserStore};
+
+pub const DEFAULT_SESSION_TTL: u64 = 60 * 60;
+
+/// What a session token proves. The token itself is never stored, only its SHA-256.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Session {
+    pub username: String,
+    pub roles: Vec<LoginRole>,
+    pub created_at: u64,
+    pub expires_at: u64,
+}
+
+impl Session {
+    /// Checks the permission against the installed role definitions, see `install_roles`.
+    pub fn can(&self, permission: &str) -> bool {
+        return crate::roles::roles_allow(&self.roles, permission);
+    }
+}
+
+pub(crate) fn hash_token(token: &str) -> String {
+    use sha2::Digest;
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(token);
+
+    return format!("{:x}", hasher.finalize());
+}
+
+fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+
+    return bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+}
+
+/// Creates a session for an already authenticated user and returns its opaque token.
+pub fn issue_session(
+    store: &dyn UserStore,
+    username: &str,
+    roles: Vec<LoginRole>,
+    ttl: u64,
+    now: u64,
+) -> Result<(String, Session), AuthError> {
+    let token = generate_token();
+    let session = Session {
+        username: username.to_string(),
+        roles,
+        created_at: now,
+        expires_at: now + ttl,
+    };
+
+    store.put_session(&hash_token(&token), &session)?;
+    return Ok((token, session));
+}
+
+/// Checks the credentials like `authenticate` and issues a session on success.
+pub fn login_session(
+    store: &dyn UserStore,
+    username: &str,
+    password: &str,
+    ttl: u64,
+) -> Result<(String, Session), AuthError> {
+    let roles = authenticate(store, username, password)?;
+    return issue_session(store, username, roles, ttl, crate::unix_now());
+}
+
+pub fn validate_session(store: &dyn UserStore, token: &str, now: u64) -> Result<Session, AuthError> {
+    let token_hash = hash_token(token);
+    let Some(session) = store.get_session(&token_hash)? else {
+        return Err(AuthError::InvalidSession);
+    };
+
+    if session.expires_at <= now {
+        store.delete_session(&token_hash)?;
+        return Err(AuthError::SessionExpired);
+    }
+
+    return Ok(session);
+}
+
+/// Extends a still valid session to expire `ttl` seconds from `now`.
+pub fn refresh_session(store: &dyn UserStore, token: &str, ttl: u64, now: u64) -> Result<Session, AuthError> {
+    let mut session = validate_session(store, token, now)?;
+    session.expires_at = now + ttl;
+    store.put_session(&hash_token(token), &session)?;
+
+    return Ok(session);
+}
+
+pub fn revoke_session(store: &dyn UserStore, token: &str) -> Result<(), AuthError> {
+    store.delete_session(&hash_token(token))?;
+    return Ok(());
+}
+
+/// Revokes every session of the user, e.g. after a password change or deletion.
+pub fn revoke_user_sessions(store: &dyn UserStore, username: &str) -> Result<(), AuthError> {
+    let username = crate::normalize_username(username);
+    store.update_sessions(&mut |sessions| {
+        let before = sessions.len();
+        sessions.retain(|_, session| crate::normalize_username(&session.username) != username);
+        return Ok(sessions.len() != before);
+    })?;
+
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_users, MemoryStore, TEST_PASSWORD};
+
+    #[test]
+    fn test_session_lifecycle() {
+        let store = MemoryStore::with_users(test_users());
+        let (token, session) = issue_session(&store, "bob", vec![LoginRole::user()], 60, 1_000).unwrap();
+
+        assert_eq!(session.expires_at, 1_060);
+        assert_eq!(validate_session(&store, &token, 1_030).unwrap(), session);
+
+        let refreshed = refresh_session(&store, &token, 60, 1_050).unwrap();
+        assert_eq!(refreshed.expires_at, 1_110);
+        assert!(validate_session(&store, &token, 1_100).is_ok());
+
+        assert!(matches!(validate_session(&store, &token, 1_110), Err(AuthError::SessionExpired)));
+        assert!(matches!(validate_session(&store, &token, 1_000), Err(AuthError::InvalidSession)));
+    }
+
+    #[test]
+    fn test_revoke_session() {
+        let store = MemoryStore::with_users(test_users());
+        let (token, _) = issue_session(&store, "bob", vec![LoginRole::user()], 60, 1_000).unwrap();
+        let (other_token, _) = issue_session(&store, "admin", vec![LoginRole::admin()], 60, 1_000).unwrap();
+
+        assert_ne!(token, other_token);
+        revoke_session(&store, &token).unwrap();
+        assert!(matches!(validate_session(&store, &token, 1_000), Err(AuthError::InvalidSession)));
+        assert!(validate_session(&store, &other_token, 1_000).is_ok());
+
+        revoke_user_sessions(&store, "admin").unwrap();
+        assert!(matches!(validate_session(&store, &other_token, 1_000), Err(AuthError::InvalidSession)));
+    }
+
+    #[test]
+    fn test_login_session() {
+        let store = MemoryStore::with_users(test_users());
+
+        let (token, session) = login_session(&store, "admin", TEST_PASSWORD, DEFAULT_SESSION_TTL).unwrap();
+        assert_eq!(session.roles, vec![LoginRole::admin()]);
+        assert!(session.can(crate::permissions::USERS_WRITE));
+        assert_eq!(validate_session(&store, &token, session.created_at).unwrap().username, "admin");
+
+        assert!(matches!(login_session(&store, "admin", "wrong", DEFAULT_SESSION_TTL), Err(AuthError::InvalidPassword)));
+        assert!(store.get_session(&hash_token("not a token")).unwrap().is_none());
+    }
+}
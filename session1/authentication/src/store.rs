@@ -0,0 +1,1038 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use project_root::get_project_root;
+use serde::{Deserialize, Serialize};
+
+use crate::{get_default_users, is_encrypted, normalize_username, AuthError, Session, StoreKey, User};
+
+/// A change applied by `UserStore::update`, returning whether anything changed.
+pub type UsersChange<'a> = dyn FnMut(&mut HashMap<String, User>) -> Result<bool, AuthError> + 'a;
+
+/// A change applied by `UserStore::update_sessions`, returning whether anything changed.
+pub type SessionsChange<'a> = dyn FnMut(&mut HashMap<String, Session>) -> Result<bool, AuthError> + 'a;
+
+/// Persistence for the user database and the sessions issued to its users. Only the `load*` and
+/// `save*` methods are required, the rest have read-modify-write defaults that backends can
+/// override with something cheaper, or make atomic.
+///
+/// Users are keyed by `normalize_username`, lookups accept any spelling of the name.
+pub trait UserStore {
+    fn load(&self) -> Result<HashMap<String, User>, AuthError>;
+
+    fn save(&self, users: &HashMap<String, User>) -> Result<(), AuthError>;
+
+    fn get(&self, username: &str) -> Result<Option<User>, AuthError> {
+        return Ok(self.load()?.remove(&normalize_username(username)));
+    }
+
+    /// Loads the users, lets `change` modify them and saves them if it returns `true`. Backends
+    /// shared between processes do this atomically, so concurrent updates are not lost.
+    fn update(&self, change: &mut UsersChange) -> Result<bool, AuthError> {
+        let mut users = self.load()?;
+        if !change(&mut users)? {
+            return Ok(false);
+        }
+
+        self.save(&users)?;
+        return Ok(true);
+    }
+
+    fn upsert(&self, user: User) -> Result<(), AuthError> {
+        let mut user = Some(user);
+        self.update(&mut |users| {
+            let user = user.take().expect("update calls the change once");
+            users.insert(normalize_username(&user.username), user);
+            return Ok(true);
+        })?;
+
+        return Ok(());
+    }
+
+    /// Returns `false` when there was no such user.
+    fn delete(&self, username: &str) -> Result<bool, AuthError> {
+        let key = normalize_username(username);
+        return self.update(&mut |users| Ok(users.remove(&key).is_some()));
+    }
+
+    /// Sessions keyed by the SHA-256 of their token.
+    fn load_sessions(&self) -> Result<HashMap<String, Session>, AuthError>;
+
+    fn save_sessions(&self, sessions: &HashMap<String, Session>) -> Result<(), AuthError>;
+
+    fn get_session(&self, token_hash: &str) -> Result<Option<Session>, AuthError> {
+        return Ok(self.load_sessions()?.remove(token_hash));
+    }
+
+    /// Like `update`, for the sessions.
+    fn update_sessions(&self, change: &mut SessionsChange) -> Result<bool, AuthError> {
+        let mut sessions = self.load_sessions()?;
+        if !change(&mut sessions)? {
+            return Ok(false);
+        }
+
+        self.save_sessions(&sessions)?;
+        return Ok(true);
+    }
+
+    fn put_session(&self, token_hash: &str, session: &Session) -> Result<(), AuthError> {
+        self.update_sessions(&mut |sessions| {
+            sessions.insert(token_hash.to_string(), session.clone());
+            return Ok(true);
+        })?;
+
+        return Ok(());
+    }
+
+    fn delete_session(&self, token_hash: &str) -> Result<bool, AuthError> {
+        return self.update_sessions(&mut |sessions| Ok(sessions.remove(token_hash).is_some()));
+    }
+}
+
+/// Re-keys users by their normalized name, files written before normalization may use any spelling.
+fn index_users(users: impl IntoIterator<Item = User>) -> Result<HashMap<String, User>, AuthError> {
+    let mut indexed: HashMap<String, User> = HashMap::new();
+
+    for user in users {
+        let key = normalize_username(&user.username);
+        if let Some(existing) = indexed.get(&key) {
+            return Err(AuthError::CorruptStore(format!(
+                "Users {} and {} collide after normalization",
+                existing.username, user.username
+            )));
+        }
+        indexed.insert(key, user);
+    }
+
+    return Ok(indexed);
+}
+
+/// Picks a backend from the file extension: `.db`, `.sqlite` and `.sqlite3` open SQLite,
+/// anything else is treated as a JSON file, encrypted with the key from `StoreKey::from_env` if set.
+pub fn open_store(path: &Path) -> Result<Box<dyn UserStore>, AuthError> {
+    let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("");
+
+    return match extension {
+        "db" | "sqlite" | "sqlite3" => Ok(Box::new(SqliteStore::open(path)?)),
+        _ => Ok(Box::new(JsonFileStore::new(path).with_key_from_env()?)),
+    };
+}
+
+/// `users.json`, as `{"generation": n, "users": {...}}`. Files written before the generation
+/// existed are a bare map of users and load as generation 0.
+#[derive(Serialize)]
+struct UsersFile<'a> {
+    generation: u64,
+    users: &'a HashMap<String, User>,
+}
+
+#[derive(Deserialize)]
+struct OwnedUsersFile {
+    generation: u64,
+    users: HashMap<String, User>,
+}
+
+/// Keeps users in a JSON file. Every write goes to a temporary file that is renamed over the
+/// old one, so a crash never leaves a truncated file, and the previous versions are kept as
+/// `users.json.1.bak`, `users.json.2.bak`, ...
+///
+/// Writers hold an advisory lock on `users.json.lock` for the whole read-modify-write cycle.
+/// Each write bumps the generation number, and `save` refuses to overwrite a generation other
+/// than the one `load` returned, so a stale copy cannot silently undo someone else's change.
+///
+/// With a `StoreKey` the users and sessions files are encrypted, and plaintext files are
+/// refused so they cannot be swapped in. `convert` encrypts an existing plaintext store.
+pub struct JsonFileStore {
+    path: PathBuf,
+    backups: usize,
+    /// The generation of the last load or save, `None` before the file was read.
+    generation: Mutex<Option<u64>>,
+    key: Option<StoreKey>,
+}
+
+impl JsonFileStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        return Self { path: path.into(), backups: 3, generation: Mutex::new(None), key: None };
+    }
+
+    /// `users.json` in the project root, falling back to the working directory outside a cargo project.
+    pub fn in_project_root() -> Self {
+        let root = get_project_root().unwrap_or_else(|_| PathBuf::from("."));
+        return Self::new(root.join("users.json"));
+    }
+
+    /// How many previous versions of the file to keep, 0 disables backups.
+    pub fn with_backups(self, backups: usize) -> Self {
+        return Self { backups, ..self };
+    }
+
+    pub fn with_key(self, key: StoreKey) -> Self {
+        return Self { key: Some(key), ..self };
+    }
+
+    /// Uses the key from `StoreKey::from_env`, if one is configured.
+    pub fn with_key_from_env(self) -> Result<Self, AuthError> {
+        return Ok(Self { key: StoreKey::from_env()?, ..self });
+    }
+
+    pub fn is_encrypted(&self) -> Result<bool, AuthError> {
+        return match std::fs::read(&self.path) {
+            Ok(contents) => Ok(is_encrypted(&contents)),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(error) => Err(error.into()),
+        };
+    }
+
+    /// Rewrites the users and sessions files encrypted with `key`, or in plaintext for `None`.
+    /// Backups in the old format are deleted, so no plaintext copy is left behind.
+    pub fn convert(&self, key: Option<StoreKey>) -> Result<(), AuthError> {
+        let _lock = self.lock()?;
+        let OwnedUsersFile { generation, users } = self.read_or_seed(true)?;
+        let sessions = self.read_sessions(true)?;
+
+        let converted = Self { path: self.path.clone(), backups: 0, generation: Mutex::new(None), key };
+        converted.write_file(generation, &users)?;
+        if self.sessions_path().exists() {
+            write_atomically(&self.sessions_path(), &converted.encode(serde_json::to_vec(&sessions)?)?)?;
+        }
+
+        for index in 1..=self.backups {
+            match std::fs::remove_file(self.backup_path(index)) {
+                Err(error) if error.kind() != std::io::ErrorKind::NotFound => return Err(error.into()),
+                _ => {}
+            }
+        }
+
+        *self.generation.lock().unwrap() = Some(generation + 1);
+        return Ok(());
+    }
+
+    pub fn path(&self) -> &Path {
+        return &self.path;
+    }
+
+    /// Sessions live next to the users file, `users.json` keeps them in `users.sessions.json`.
+    pub fn sessions_path(&self) -> PathBuf {
+        let stem = self.path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("users");
+        return self.path.with_file_name(format!("{stem}.sessions.json"));
+    }
+
+    pub fn backup_path(&self, index: usize) -> PathBuf {
+        return with_suffix(&self.path, &format!("{index}.bak"));
+    }
+
+    /// The generation of the file on disk, 0 when it does not exist yet.
+    pub fn generation(&self) -> Result<u64, AuthError> {
+        return Ok(self.read_file(false)?.map_or(0, |file| file.generation));
+    }
+
+    /// Blocks until no other store, in this or another process, is writing the files.
+    fn lock(&self) -> Result<File, AuthError> {
+        let lock = OpenOptions::new().create(true).truncate(false).write(true).open(with_suffix(&self.path, "lock"))?;
+        lock.lock()?;
+
+        return Ok(lock);
+    }
+
+    /// Decrypts the contents of one of the files. Plaintext is only accepted without a key, or
+    /// when `migrating` it to the key.
+    fn decode(&self, path: &Path, contents: Vec<u8>, migrating: bool) -> Result<Vec<u8>, AuthError> {
+        let name = path.display().to_string();
+        if !is_encrypted(&contents) {
+            if self.key.is_some() && !migrating {
+                return Err(AuthError::NotEncrypted(name));
+            }
+            return Ok(contents);
+        }
+
+        return match &self.key {
+            Some(key) => key.decrypt(&name, &contents),
+            None => Err(AuthError::EncryptionKeyRequired(name)),
+        };
+    }
+
+    /// Encrypts file contents when the store has a key.
+    fn encode(&self, contents: Vec<u8>) -> Result<Vec<u8>, AuthError> {
+        return match &self.key {
+            Some(key) => key.encrypt(&contents),
+            None => Ok(contents),
+        };
+    }
+
+    fn read_file(&self, migrating: bool) -> Result<Option<OwnedUsersFile>, AuthError> {
+        let contents = match std::fs::read(&self.path) {
+            Ok(contents) => self.decode(&self.path, contents, migrating)?,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+
+        let corrupt = |error: serde_json::Error| AuthError::CorruptStore(format!("{}: {error}", self.path.display()));
+        let value: serde_json::Value = serde_json::from_slice(&contents).map_err(corrupt)?;
+        let file = if value.get("generation").is_some_and(serde_json::Value::is_u64) {
+            serde_json::from_value(value).map_err(corrupt)?
+        } else {
+            OwnedUsersFile { generation: 0, users: serde_json::from_value(value).map_err(corrupt)? }
+        };
+
+        return Ok(Some(OwnedUsersFile { generation: file.generation, users: index_users(file.users.into_values())? }));
+    }
+
+    /// The users on disk, seeded with the default users when there is no file yet. Callers hold the lock.
+    fn read_or_seed(&self, migrating: bool) -> Result<OwnedUsersFile, AuthError> {
+        if let Some(file) = self.read_file(migrating)? {
+            return Ok(file);
+        }
+
+        let users = get_default_users()?;
+        self.write_file(0, &users)?;
+        return Ok(OwnedUsersFile { generation: 1, users });
+    }
+
+    /// Writes the generation after `current`. Callers hold the lock.
+    fn write_file(&self, current: u64, users: &HashMap<String, User>) -> Result<(), AuthError> {
+        let generation = current + 1;
+        let contents = self.encode(serde_json::to_vec(&UsersFile { generation, users })?)?;
+
+        self.rotate_backups()?;
+        write_atomically(&self.path, &contents)?;
+        *self.generation.lock().unwrap() = Some(generation);
+
+        return Ok(());
+    }
+
+    fn read_sessions(&self, migrating: bool) -> Result<HashMap<String, Session>, AuthError> {
+        let sessions_path = self.sessions_path();
+        let contents = match std::fs::read(&sessions_path) {
+            Ok(contents) => self.decode(&sessions_path, contents, migrating)?,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(error) => return Err(error.into()),
+        };
+
+        return serde_json::from_slice(&contents).map_err(|error| {
+            return AuthError::CorruptStore(format!("{}: {error}", sessions_path.display()));
+        });
+    }
+
+    fn rotate_backups(&self) -> Result<(), AuthError> {
+        if self.backups == 0 || !self.path.exists() {
+            return Ok(());
+        }
+
+        for index in (1..self.backups).rev() {
+            let backup = self.backup_path(index);
+            if backup.exists() {
+                std::fs::rename(&backup, self.backup_path(index + 1))?;
+            }
+        }
+        std::fs::copy(&self.path, self.backup_path(1))?;
+
+        // Backups of a store that was plaintext before are encrypted too, so no copy of the
+        // password hashes and TOTP secrets is left readable.
+        if let Some(key) = &self.key {
+            for index in 1..=self.backups {
+                let backup = self.backup_path(index);
+                match std::fs::read(&backup) {
+                    Ok(contents) if !is_encrypted(&contents) => write_atomically(&backup, &key.encrypt(&contents)?)?,
+                    Ok(_) => {}
+                    Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(error) => return Err(error.into()),
+                }
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+/// `users.json` with suffix `lock` is `users.json.lock`.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(suffix);
+
+    return path.with_file_name(file_name);
+}
+
+/// Replaces the file in one step, readers see either the old or the new contents.
+fn write_atomically(path: &Path, contents: &[u8]) -> Result<(), AuthError> {
+    let temporary = with_suffix(path, "tmp");
+    let mut file = File::create(&temporary)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    std::fs::rename(&temporary, path)?;
+
+    // Make the rename itself durable. Directories cannot be opened for syncing on every platform.
+    if cfg!(unix) {
+        let directory = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        File::open(directory)?.sync_all()?;
+    }
+
+    return Ok(());
+}
+
+impl UserStore for JsonFileStore {
+    fn load(&self) -> Result<HashMap<String, User>, AuthError> {
+        let file = match self.read_file(false)? {
+            Some(file) => file,
+            None => {
+                let _lock = self.lock()?;
+                self.read_or_seed(false)?
+            }
+        };
+
+        *self.generation.lock().unwrap() = Some(file.generation);
+        return Ok(file.users);
+    }
+
+    fn save(&self, users: &HashMap<String, User>) -> Result<(), AuthError> {
+        let _lock = self.lock()?;
+        let current = self.read_file(false)?.map_or(0, |file| file.generation);
+
+        if let Some(loaded) = *self.generation.lock().unwrap() {
+            if loaded != current {
+                return Err(AuthError::Conflict { expected: loaded, found: current });
+            }
+        }
+
+        return self.write_file(current, users);
+    }
+
+    fn update(&self, change: &mut UsersChange) -> Result<bool, AuthError> {
+        let _lock = self.lock()?;
+        let OwnedUsersFile { generation, mut users } = self.read_or_seed(false)?;
+        *self.generation.lock().unwrap() = Some(generation);
+
+        if !change(&mut users)? {
+            return Ok(false);
+        }
+
+        self.write_file(generation, &users)?;
+        return Ok(true);
+    }
+
+    fn load_sessions(&self) -> Result<HashMap<String, Session>, AuthError> {
+        return self.read_sessions(false);
+    }
+
+    fn save_sessions(&self, sessions: &HashMap<String, Session>) -> Result<(), AuthError> {
+        let contents = self.encode(serde_json::to_vec(sessions)?)?;
+        let _lock = self.lock()?;
+
+        return write_atomically(&self.sessions_path(), &contents);
+    }
+
+    fn update_sessions(&self, change: &mut SessionsChange) -> Result<bool, AuthError> {
+        let _lock = self.lock()?;
+        let mut sessions = self.read_sessions(false)?;
+        if !change(&mut sessions)? {
+            return Ok(false);
+        }
+
+        write_atomically(&self.sessions_path(), &self.encode(serde_json::to_vec(&sessions)?)?)?;
+        return Ok(true);
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    users: Mutex<HashMap<String, User>>,
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    pub fn with_users(users: HashMap<String, User>) -> Self {
+        let users = users.into_values().map(|user| (normalize_username(&user.username), user)).collect();
+        return Self { users: Mutex::new(users), ..Self::default() };
+    }
+}
+
+impl UserStore for MemoryStore {
+    fn load(&self) -> Result<HashMap<String, User>, AuthError> {
+        return Ok(self.users.lock().unwrap().clone());
+    }
+
+    fn save(&self, users: &HashMap<String, User>) -> Result<(), AuthError> {
+        *self.users.lock().unwrap() = users.clone();
+        return Ok(());
+    }
+
+    fn get(&self, username: &str) -> Result<Option<User>, AuthError> {
+        return Ok(self.users.lock().unwrap().get(&normalize_username(username)).cloned());
+    }
+
+    fn upsert(&self, user: User) -> Result<(), AuthError> {
+        self.users.lock().unwrap().insert(normalize_username(&user.username), user);
+        return Ok(());
+    }
+
+    fn delete(&self, username: &str) -> Result<bool, AuthError> {
+        return Ok(self.users.lock().unwrap().remove(&normalize_username(username)).is_some());
+    }
+
+    fn update(&self, change: &mut UsersChange) -> Result<bool, AuthError> {
+        let mut users = self.users.lock().unwrap();
+        let mut updated = users.clone();
+        if !change(&mut updated)? {
+            return Ok(false);
+        }
+
+        *users = updated;
+        return Ok(true);
+    }
+
+    fn load_sessions(&self) -> Result<HashMap<String, Session>, AuthError> {
+        return Ok(self.sessions.lock().unwrap().clone());
+    }
+
+    fn save_sessions(&self, sessions: &HashMap<String, Session>) -> Result<(), AuthError> {
+        *self.sessions.lock().unwrap() = sessions.clone();
+        return Ok(());
+    }
+
+    fn update_sessions(&self, change: &mut SessionsChange) -> Result<bool, AuthError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let mut updated = sessions.clone();
+        if !change(&mut updated)? {
+            return Ok(false);
+        }
+
+        *sessions = updated;
+        return Ok(true);
+    }
+}
+
+/// Users are kept as one JSON document per row, so new `User` fields need no schema migration.
+pub struct SqliteStore {
+    connection: Mutex<rusqlite::Connection>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> Result<Self, AuthError> {
+        return Self::from_connection(rusqlite::Connection::open(path)?);
+    }
+
+    pub fn open_in_memory() -> Result<Self, AuthError> {
+        return Self::from_connection(rusqlite::Connection::open_in_memory()?);
+    }
+
+    fn from_connection(connection: rusqlite::Connection) -> Result<Self, AuthError> {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS users
+            (
+                username TEXT PRIMARY KEY NOT NULL,
+                data     TEXT             NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS sessions
+            (
+                token_hash TEXT PRIMARY KEY NOT NULL,
+                data       TEXT             NOT NULL
+            );",
+        )?;
+
+        let store = Self { connection: Mutex::new(connection) };
+        store.normalize_keys()?;
+
+        return Ok(store);
+    }
+
+    /// Rows written before usernames were normalized may be keyed by any spelling of the name.
+    fn normalize_keys(&self) -> Result<(), AuthError> {
+        let keys: Vec<String> = {
+            let connection = self.connection.lock().unwrap();
+            let mut statement = connection.prepare("select username from users")?;
+            let rows = statement.query_map([], |row| row.get(0))?;
+            rows.collect::<Result<_, _>>()?
+        };
+
+        if keys.iter().any(|key| *key != normalize_username(key)) {
+            let users = self.load()?;
+            self.save(&users)?;
+        }
+
+        return Ok(());
+    }
+}
+
+fn decode_row<T: serde::de::DeserializeOwned>(key: &str, data: &str) -> Result<T, AuthError> {
+    return serde_json::from_str(data).map_err(|error| {
+        return AuthError::CorruptStore(format!("Row for {key}: {error}"));
+    });
+}
+
+fn load_rows(connection: &rusqlite::Connection) -> Result<HashMap<String, User>, AuthError> {
+    let mut statement = connection.prepare("select username, data from users")?;
+    let rows = statement.query_map([], |row| {
+        let username: String = row.get(0)?;
+        let data: String = row.get(1)?;
+        return Ok((username, data));
+    })?;
+
+    let mut users = Vec::new();
+    for row in rows {
+        let (username, data) = row?;
+        users.push(decode_row(&username, &data)?);
+    }
+
+    return index_users(users);
+}
+
+fn replace_rows(connection: &rusqlite::Connection, users: &HashMap<String, User>) -> Result<(), AuthError> {
+    connection.execute("delete from users", [])?;
+    for user in users.values() {
+        connection.execute(
+            "insert into users (username, data) values (?1, ?2)",
+            (normalize_username(&user.username), serde_json::to_string(user)?),
+        )?;
+    }
+
+    return Ok(());
+}
+
+impl UserStore for SqliteStore {
+    fn load(&self) -> Result<HashMap<String, User>, AuthError> {
+        return load_rows(&self.connection.lock().unwrap());
+    }
+
+    fn save(&self, users: &HashMap<String, User>) -> Result<(), AuthError> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        replace_rows(&transaction, users)?;
+
+        transaction.commit()?;
+        return Ok(());
+    }
+
+    fn update(&self, change: &mut UsersChange) -> Result<bool, AuthError> {
+        let mut connection = self.connection.lock().unwrap();
+        // Take the write lock up front, so other connections cannot slip in between load and save.
+        let transaction = connection.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+
+        let mut users = load_rows(&transaction)?;
+        if !change(&mut users)? {
+            return Ok(false);
+        }
+
+        replace_rows(&transaction, &users)?;
+        transaction.commit()?;
+        return Ok(true);
+    }
+
+    fn get(&self, username: &str) -> Result<Option<User>, AuthError> {
+        use rusqlite::OptionalExtension;
+
+        let connection = self.connection.lock().unwrap();
+        let data: Option<String> = connection
+            .query_row("select data from users where username = ?1", [normalize_username(username)], |row| row.get(0))
+            .optional()?;
+
+        return data.map(|data| decode_row(username, &data)).transpose();
+    }
+
+    fn upsert(&self, user: User) -> Result<(), AuthError> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "insert into users (username, data) values (?1, ?2)
+             on conflict (username) do update set data = excluded.data",
+            (normalize_username(&user.username), serde_json::to_string(&user)?),
+        )?;
+
+        return Ok(());
+    }
+
+    fn delete(&self, username: &str) -> Result<bool, AuthError> {
+        let connection = self.connection.lock().unwrap();
+        let deleted = connection.execute("delete from users where username = ?1", [normalize_username(username)])?;
+
+        return Ok(deleted > 0);
+    }
+
+    fn load_sessions(&self) -> Result<HashMap<String, Session>, AuthError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare("select token_hash, data from sessions")?;
+        let rows = statement.query_map([], |row| {
+            let token_hash: String = row.get(0)?;
+            let data: String = row.get(1)?;
+            return Ok((token_hash, data));
+        })?;
+
+        let mut sessions = HashMap::new();
+        for row in rows {
+            let (token_hash, data) = row?;
+            let session = decode_row(&token_hash, &data)?;
+            sessions.insert(token_hash, session);
+        }
+
+        return Ok(sessions);
+    }
+
+    fn save_sessions(&self, sessions: &HashMap<String, Session>) -> Result<(), AuthError> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+
+        transaction.execute("delete from sessions", [])?;
+        for (token_hash, session) in sessions {
+            transaction.execute(
+                "insert into sessions (token_hash, data) values (?1, ?2)",
+                (token_hash, serde_json::to_string(session)?),
+            )?;
+        }
+
+        transaction.commit()?;
+        return Ok(());
+    }
+
+    fn get_session(&self, token_hash: &str) -> Result<Option<Session>, AuthError> {
+        use rusqlite::OptionalExtension;
+
+        let connection = self.connection.lock().unwrap();
+        let data: Option<String> = connection
+            .query_row("select data from sessions where token_hash = ?1", [token_hash], |row| row.get(0))
+            .optional()?;
+
+        return data.map(|data| decode_row(token_hash, &data)).transpose();
+    }
+
+    fn put_session(&self, token_hash: &str, session: &Session) -> Result<(), AuthError> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "insert into sessions (token_hash, data) values (?1, ?2)
+             on conflict (token_hash) do update set data = excluded.data",
+            (token_hash, serde_json::to_string(session)?),
+        )?;
+
+        return Ok(());
+    }
+
+    fn delete_session(&self, token_hash: &str) -> Result<bool, AuthError> {
+        let connection = self.connection.lock().unwrap();
+        let deleted = connection.execute("delete from sessions where token_hash = ?1", [token_hash])?;
+
+        return Ok(deleted > 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LoginRole, TEST_PASSWORD};
+
+    fn exercise_store(store: &dyn UserStore) {
+        store.save(&HashMap::new()).unwrap();
+        assert!(store.load().unwrap().is_empty());
+
+        store.upsert(User::new("alice", TEST_PASSWORD, LoginRole::user()).unwrap()).unwrap();
+        store.upsert(User::new("carol", TEST_PASSWORD, LoginRole::admin()).unwrap()).unwrap();
+        assert_eq!(store.load().unwrap().len(), 2);
+        assert_eq!(store.get("alice").unwrap().unwrap().roles, vec![LoginRole::user()]);
+
+        store.upsert(User::new("alice", TEST_PASSWORD, LoginRole::admin()).unwrap()).unwrap();
+        assert_eq!(store.get("alice").unwrap().unwrap().roles, vec![LoginRole::admin()]);
+
+        store.upsert(User::new("Dave", TEST_PASSWORD, LoginRole::user()).unwrap()).unwrap();
+        assert_eq!(store.get(" DAVE ").unwrap().unwrap().username, "Dave");
+        assert!(store.load().unwrap().contains_key("dave"));
+        assert!(store.delete("dave").unwrap());
+
+        assert!(store.delete("alice").unwrap());
+        assert!(!store.delete("alice").unwrap());
+        assert!(store.get("alice").unwrap().is_none());
+        assert_eq!(store.load().unwrap().len(), 1);
+
+        let session = Session {
+            username: "carol".to_string(),
+            roles: vec![LoginRole::admin()],
+            created_at: 1_000,
+            expires_at: 2_000,
+        };
+        assert!(store.load_sessions().unwrap().is_empty());
+        store.put_session("hash", &session).unwrap();
+        assert_eq!(store.get_session("hash").unwrap(), Some(session));
+        assert_eq!(store.load_sessions().unwrap().len(), 1);
+        assert!(store.delete_session("hash").unwrap());
+        assert!(!store.delete_session("hash").unwrap());
+        assert!(store.get_session("hash").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_memory_store() {
+        exercise_store(&MemoryStore::new());
+    }
+
+    #[test]
+    fn test_sqlite_store() {
+        exercise_store(&SqliteStore::open_in_memory().unwrap());
+    }
+
+    #[test]
+    fn test_json_file_store() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("users.json");
+
+        exercise_store(&JsonFileStore::new(&path));
+        assert_eq!(JsonFileStore::new(&path).load().unwrap().len(), 1);
+        assert!(directory.path().join("users.sessions.json").exists());
+    }
+
+    #[test]
+    fn test_json_file_store_seeds_default_users() {
+        let directory = tempfile::tempdir().unwrap();
+        let store = JsonFileStore::new(directory.path().join("users.json"));
+
+        assert!(!store.path().exists());
+        assert!(store.get("admin").unwrap().is_some());
+        assert!(store.path().exists());
+    }
+
+    #[test]
+    fn test_json_file_store_reports_corruption() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("users.json");
+        std::fs::write(&path, "{ not json").unwrap();
+
+        let result = JsonFileStore::new(&path).load();
+        assert!(matches!(result, Err(AuthError::CorruptStore(_))));
+    }
+
+    #[test]
+    fn test_json_file_store_rekeys_legacy_names() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("users.json");
+        std::fs::write(&path, r#"{"Bob": {"username": "Bob", "password": "", "role": "User"}}"#).unwrap();
+
+        let store = JsonFileStore::new(&path);
+        assert_eq!(store.get("bob").unwrap().unwrap().username, "Bob");
+        assert_eq!(store.generation().unwrap(), 0);
+
+        // The first write moves the file to the current format.
+        store.upsert(User::new("alice", TEST_PASSWORD, LoginRole::user()).unwrap()).unwrap();
+        let saved: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(saved["generation"], 1);
+        assert_eq!(saved["users"]["bob"]["username"], "Bob");
+
+        std::fs::write(&path, r#"{
+            "Bob": {"username": "Bob", "password": "", "role": "User"},
+            "bob": {"username": "bob", "password": "", "role": "User"}
+        }"#).unwrap();
+        assert!(matches!(store.load(), Err(AuthError::CorruptStore(_))));
+    }
+
+    #[test]
+    fn test_json_file_store_concurrent_writers_lose_nothing() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("users.json");
+        JsonFileStore::new(&path).save(&HashMap::new()).unwrap();
+
+        // Hash up front, so the threads spend their time in the store.
+        let user = User::new("template", TEST_PASSWORD, LoginRole::user()).unwrap();
+        let threads: Vec<_> = ["alice", "carol"]
+            .into_iter()
+            .map(|prefix| {
+                let path = path.clone();
+                let user = user.clone();
+                return std::thread::spawn(move || {
+                    let store = JsonFileStore::new(path);
+                    for index in 0..20 {
+                        let user = User { username: format!("{prefix}{index}"), ..user.clone() };
+                        crate::create_user(&store, user).unwrap();
+                    }
+                });
+            })
+            .collect();
+        threads.into_iter().for_each(|thread| thread.join().unwrap());
+
+        let store = JsonFileStore::new(&path);
+        assert_eq!(store.load().unwrap().len(), 40);
+        assert_eq!(store.generation().unwrap(), 41);
+    }
+
+    #[test]
+    fn test_json_file_store_logins_race_password_changes() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("users.json");
+        JsonFileStore::new(&path).save(&crate::test_users()).unwrap();
+
+        // Failed logins write the user while the password changes, neither may undo the other.
+        let policy = crate::LockoutPolicy { max_attempts: u32::MAX, ..crate::LockoutPolicy::default() };
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                let path = path.clone();
+                return std::thread::spawn(move || {
+                    let store = JsonFileStore::new(path);
+                    for _ in 0..10 {
+                        let action = crate::login_at(&store, "bob", "wrong", &policy, crate::unix_now()).unwrap();
+                        assert_eq!(action, Some(crate::LoginAction::Denied));
+                    }
+                });
+            })
+            .collect();
+        let store = JsonFileStore::new(&path);
+        for password in ["Battery-Staple-8", "Battery-Staple-9"] {
+            crate::change_password(&store, "bob", password).unwrap();
+        }
+        threads.into_iter().for_each(|thread| thread.join().unwrap());
+
+        let bob = store.get("bob").unwrap().unwrap();
+        assert_eq!(bob.failed_attempts, 20);
+        assert_eq!(bob.password_history.len(), 2);
+        assert!(crate::verify_password("Battery-Staple-9", &bob.password).unwrap());
+    }
+
+    #[test]
+    fn test_json_file_store_detects_lost_updates() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("users.json");
+        let first = JsonFileStore::new(&path);
+        let second = JsonFileStore::new(&path);
+        first.save(&HashMap::new()).unwrap();
+
+        let mut stale = first.load().unwrap();
+        second.upsert(User::new("alice", TEST_PASSWORD, LoginRole::user()).unwrap()).unwrap();
+
+        stale.insert("carol".to_string(), User::new("carol", TEST_PASSWORD, LoginRole::user()).unwrap());
+        assert!(matches!(first.save(&stale), Err(AuthError::Conflict { expected: 1, found: 2 })));
+
+        // After reloading, the save goes through and keeps both users.
+        let mut users = first.load().unwrap();
+        users.insert("carol".to_string(), stale.remove("carol").unwrap());
+        first.save(&users).unwrap();
+        assert_eq!(second.load().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_json_file_store_rotates_backups() {
+        let directory = tempfile::tempdir().unwrap();
+        let store = JsonFileStore::new(directory.path().join("users.json")).with_backups(2);
+
+        for index in 0..4 {
+            let user = User { username: format!("user{index}"), ..User::new("user", TEST_PASSWORD, LoginRole::user()).unwrap() };
+            store.save(&HashMap::from([(format!("user{index}"), user)])).unwrap();
+        }
+
+        assert_eq!(store.generation().unwrap(), 4);
+        assert_eq!(JsonFileStore::new(store.backup_path(1)).generation().unwrap(), 3);
+        assert_eq!(JsonFileStore::new(store.backup_path(2)).generation().unwrap(), 2);
+        assert!(!store.backup_path(3).exists());
+        assert!(JsonFileStore::new(store.backup_path(2)).load().unwrap().contains_key("user1"));
+        assert!(!directory.path().join("users.json.tmp").exists());
+    }
+
+    #[test]
+    fn test_encrypted_json_file_store() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("users.json");
+        let key = || StoreKey::from_bytes([42; 32]);
+
+        let store = JsonFileStore::new(&path).with_key(key());
+        exercise_store(&store);
+        store.put_session("hash", &Session { username: "carol".to_string(), roles: vec![], created_at: 0, expires_at: 1 }).unwrap();
+        assert!(store.is_encrypted().unwrap());
+        let contents = std::fs::read(&path).unwrap();
+        assert!(!String::from_utf8_lossy(&contents).contains("carol"));
+        assert!(is_encrypted(&std::fs::read(store.sessions_path()).unwrap()));
+
+        let reopened = JsonFileStore::new(&path).with_key(key());
+        assert!(reopened.get("carol").unwrap().is_some());
+        assert!(reopened.get_session("hash").unwrap().is_some());
+
+        let name = path.display().to_string();
+        assert!(matches!(JsonFileStore::new(&path).load(), Err(AuthError::EncryptionKeyRequired(file)) if file == name));
+        let wrong = JsonFileStore::new(&path).with_key(StoreKey::from_bytes([7; 32]));
+        assert!(matches!(wrong.load(), Err(AuthError::WrongKey(file)) if file == name));
+
+        // Plaintext swapped in for either file is refused.
+        std::fs::write(store.sessions_path(), "{}").unwrap();
+        assert!(matches!(reopened.get_session("hash"), Err(AuthError::NotEncrypted(_))));
+        std::fs::write(&path, r#"{"generation": 9, "users": {}}"#).unwrap();
+        assert!(matches!(reopened.load(), Err(AuthError::NotEncrypted(file)) if file == name));
+        assert!(matches!(reopened.update(&mut |_| Ok(true)), Err(AuthError::NotEncrypted(_))));
+    }
+
+    #[test]
+    fn test_keyed_writes_encrypt_plaintext_backups() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("users.json");
+        let plaintext = JsonFileStore::new(&path);
+        plaintext.save(&HashMap::new()).unwrap();
+        plaintext.upsert(User::new("alice", TEST_PASSWORD, LoginRole::user()).unwrap()).unwrap();
+        let users = plaintext.load().unwrap();
+
+        let encrypted = JsonFileStore::new(&path).with_key(StoreKey::from_bytes([7; 32]));
+        encrypted.write_file(plaintext.generation().unwrap(), &users).unwrap();
+
+        // The plaintext file before and after the upsert.
+        assert!(!encrypted.backup_path(3).exists());
+        for index in 1..=2 {
+            let backup = encrypted.backup_path(index);
+            let contents = std::fs::read(&backup).unwrap();
+            assert!(is_encrypted(&contents), "{} is not encrypted", backup.display());
+            let decrypted = StoreKey::from_bytes([7; 32]).decrypt("backup", &contents).unwrap();
+            assert!(serde_json::from_slice::<serde_json::Value>(&decrypted).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_convert_between_plaintext_and_encrypted() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("users.json");
+        let plaintext = JsonFileStore::new(&path);
+        plaintext.save(&HashMap::new()).unwrap();
+        plaintext.upsert(User::new("alice", TEST_PASSWORD, LoginRole::user()).unwrap()).unwrap();
+        assert!(plaintext.backup_path(1).exists());
+
+        plaintext.convert(Some(StoreKey::from_passphrase("correct horse").unwrap())).unwrap();
+        assert!(plaintext.is_encrypted().unwrap());
+        assert!(!plaintext.backup_path(1).exists());
+
+        let encrypted = JsonFileStore::new(&path).with_key(StoreKey::from_passphrase("correct horse").unwrap());
+        assert!(encrypted.get("alice").unwrap().is_some());
+        assert_eq!(encrypted.generation().unwrap(), 3);
+
+        encrypted.convert(None).unwrap();
+        assert!(!plaintext.is_encrypted().unwrap());
+        assert!(JsonFileStore::new(&path).get("alice").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_sqlite_store_rekeys_legacy_names() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("users.db");
+        SqliteStore::open(&path).unwrap();
+        rusqlite::Connection::open(&path).unwrap().execute(
+            "insert into users (username, data) values ('Bob', ?1)",
+            [r#"{"username": "Bob", "password": "", "roles": ["user"]}"#],
+        ).unwrap();
+
+        let store = SqliteStore::open(&path).unwrap();
+        assert_eq!(store.get("bob").unwrap().unwrap().username, "Bob");
+        assert!(store.delete("BOB").unwrap());
+    }
+
+    #[test]
+    fn test_json_file_store_reports_io_errors() {
+        let directory = tempfile::tempdir().unwrap();
+        let store = JsonFileStore::new(directory.path().join("missing").join("users.json"));
+
+        assert!(matches!(store.save(&HashMap::new()), Err(AuthError::Io(_))));
+    }
+
+    #[test]
+    fn test_open_store_by_extension() {
+        let directory = tempfile::tempdir().unwrap();
+
+        let sqlite = open_store(&directory.path().join("users.db")).unwrap();
+        sqlite.upsert(User::new("alice", TEST_PASSWORD, LoginRole::user()).unwrap()).unwrap();
+        assert!(directory.path().join("users.db").exists());
+        assert!(!directory.path().join("users.json").exists());
+
+        let json = open_store(&directory.path().join("users.json")).unwrap();
+        assert!(json.get("admin").unwrap().is_some());
+        assert!(json.get("alice").unwrap().is_none());
+    }
+}
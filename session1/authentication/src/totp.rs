@@ -0,0 +1,190 @@
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+pub const TOTP_DIGITS: u32 = 6;
+pub const TOTP_PERIOD: u64 = 30;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// RFC 6238 time-based one-time password settings of a user.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TotpConfig {
+    /// Base32 shared secret, as shown to authenticator apps.
+    pub secret: String,
+    /// SHA-256 hashes of the unused recovery codes.
+    pub recovery_codes: Vec<String>,
+    /// Time step of the last accepted code, a code is never accepted twice.
+    #[serde(default)]
+    pub last_used_step: Option<u64>,
+}
+
+/// Everything the user has to see once when enabling two-factor authentication.
+#[derive(Debug, Clone)]
+pub struct TotpEnrollment {
+    pub config: TotpConfig,
+    pub uri: String,
+    pub recovery_codes: Vec<String>,
+}
+
+impl TotpConfig {
+    pub fn enroll(issuer: &str, username: &str) -> TotpEnrollment {
+        let mut secret = [0u8; 20];
+        rand::rngs::OsRng.fill(&mut secret);
+        let secret = data_encoding::BASE32_NOPAD.encode(&secret);
+
+        let recovery_codes: Vec<String> = (0..RECOVERY_CODE_COUNT).map(|_| generate_recovery_code()).collect();
+        let config = TotpConfig {
+            secret: secret.clone(),
+            recovery_codes: recovery_codes.iter().map(|code| hash_recovery_code(code)).collect(),
+            last_used_step: None,
+        };
+
+        return TotpEnrollment {
+            uri: otpauth_uri(issuer, username, &secret),
+            config,
+            recovery_codes,
+        };
+    }
+
+    /// Accepts a current one-time code (allowing one step of clock drift) or an unused recovery
+    /// code, recording it so it cannot be used again.
+    pub fn verify(&mut self, code: &str, now: u64) -> bool {
+        let code = code.trim();
+
+        if code.len() == TOTP_DIGITS as usize && code.chars().all(|c| c.is_ascii_digit()) {
+            let Ok(secret) = data_encoding::BASE32_NOPAD.decode(self.secret.as_bytes()) else {
+                return false;
+            };
+
+            let current_step = now / TOTP_PERIOD;
+            let accepted = (current_step.saturating_sub(1)..=current_step + 1)
+                .filter(|step| self.last_used_step.is_none_or(|last| *step > last))
+                .find(|step| format!("{:0width$}", totp_code(&secret, *step), width = TOTP_DIGITS as usize) == code);
+
+            if let Some(step) = accepted {
+                self.last_used_step = Some(step);
+                return true;
+            }
+
+            return false;
+        }
+
+        let hashed = hash_recovery_code(code);
+        if let Some(position) = self.recovery_codes.iter().position(|stored| *stored == hashed) {
+            self.recovery_codes.remove(position);
+            return true;
+        }
+
+        return false;
+    }
+}
+
+/// HOTP value (RFC 4226) for the given counter, truncated to `TOTP_DIGITS` digits.
+pub fn totp_code(secret: &[u8], step: u64) -> u32 {
+    let mut mac = Hmac::<sha1::Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([digest[offset], digest[offset + 1], digest[offset + 2], digest[offset + 3]]) & 0x7fff_ffff;
+
+    return binary % 10u32.pow(TOTP_DIGITS);
+}
+
+pub fn otpauth_uri(issuer: &str, username: &str, secret: &str) -> String {
+    let issuer = percent_encode(issuer);
+    let username = percent_encode(username);
+
+    return format!(
+        "otpauth://totp/{issuer}:{username}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={TOTP_DIGITS}&period={TOTP_PERIOD}"
+    );
+}
+
+fn percent_encode(value: &str) -> String {
+    return value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{byte:02X}"),
+        })
+        .collect();
+}
+
+fn generate_recovery_code() -> String {
+    const ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+    let mut rng = rand::rngs::OsRng;
+    let mut part = || -> String {
+        return (0..5).map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char).collect();
+    };
+
+    return format!("{}-{}", part(), part());
+}
+
+fn hash_recovery_code(code: &str) -> String {
+    return crate::session::hash_token(&code.trim().to_lowercase());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    fn config_with_secret(secret: &[u8]) -> TotpConfig {
+        return TotpConfig {
+            secret: data_encoding::BASE32_NOPAD.encode(secret),
+            recovery_codes: vec![],
+            last_used_step: None,
+        };
+    }
+
+    #[test]
+    fn test_rfc_6238_vectors() {
+        // RFC 6238 appendix B, SHA-1, truncated to the last six digits.
+        assert_eq!(totp_code(RFC_SECRET, 59 / TOTP_PERIOD), 287082);
+        assert_eq!(totp_code(RFC_SECRET, 1111111109 / TOTP_PERIOD), 81804);
+        assert_eq!(totp_code(RFC_SECRET, 1234567890 / TOTP_PERIOD), 5924);
+        assert_eq!(totp_code(RFC_SECRET, 2000000000 / TOTP_PERIOD), 279037);
+    }
+
+    #[test]
+    fn test_verify_with_fixed_clock() {
+        let mut config = config_with_secret(RFC_SECRET);
+
+        assert!(!config.verify("000000", 59));
+        assert!(config.verify("287082", 59));
+        // The same code cannot be replayed.
+        assert!(!config.verify("287082", 59));
+
+        // One step of drift is tolerated, more is not.
+        let mut config = config_with_secret(RFC_SECRET);
+        assert!(config.verify("081804", 1111111109 + TOTP_PERIOD));
+        let mut config = config_with_secret(RFC_SECRET);
+        assert!(!config.verify("081804", 1111111109 + 2 * TOTP_PERIOD));
+    }
+
+    #[test]
+    fn test_recovery_codes_work_once() {
+        let enrollment = TotpConfig::enroll("Ardan Labs", "bob");
+        let mut config = enrollment.config;
+        let code = enrollment.recovery_codes[3].clone();
+
+        assert_eq!(enrollment.recovery_codes.len(), RECOVERY_CODE_COUNT);
+        assert!(config.verify(&code.to_uppercase(), 0));
+        assert!(!config.verify(&code, 0));
+        assert_eq!(config.recovery_codes.len(), RECOVERY_CODE_COUNT - 1);
+    }
+
+    #[test]
+    fn test_otpauth_uri() {
+        let enrollment = TotpConfig::enroll("Ardan Labs", "bob@example.com");
+
+        assert_eq!(
+            enrollment.uri,
+            format!(
+                "otpauth://totp/Ardan%20Labs:bob%40example.com?secret={}&issuer=Ardan%20Labs&algorithm=SHA1&digits=6&period=30",
+                enrollment.config.secret
+            )
+        );
+    }
+}
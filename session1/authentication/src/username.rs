@@ -0,0 +1,22 @@
+use unicode_normalization::UnicodeNormalization;
+
+/// The lookup key for a username: trimmed, NFKC-normalized and case-folded, so `Admin`, ` admin `
+/// and the full-width `ａｄｍｉｎ` are the same account. `User::username` keeps the display form.
+pub fn normalize_username(username: &str) -> String {
+    let compatible: String = username.trim().nfkc().collect();
+    return compatible.to_lowercase().nfkc().collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_username() {
+        assert_eq!(normalize_username("admin"), "admin");
+        assert_eq!(normalize_username("  Admin\t"), "admin");
+        assert_eq!(normalize_username("ＡＤＭＩＮ"), "admin");
+        assert_eq!(normalize_username("Cafe\u{301}"), normalize_username("CAFÉ"));
+        assert_ne!(normalize_username("admin1"), normalize_username("admin"));
+    }
+}
@@ -1,36 +1,102 @@
-use authentication::{login, LoginAction, read_line};
+#![allow(clippy::needless_return)]
+
+mod shell;
+
+use std::path::PathBuf;
+
+use authentication::{
+    install_audit_log, install_roles, issue_session, login_second_factor, open_store, read_line, unix_now, AuditLog,
+    AuthError, IdentityProvider, JsonFileStore, LoginAction, ProviderChain, RoleRegistry, UserStore,
+};
+
+/// Seconds without a command before the shell logs out, unless set with `LOGIN_IDLE_TIMEOUT`.
+const DEFAULT_IDLE_TIMEOUT: u64 = 15 * 60;
+
+fn exit_with(error: AuthError) -> ! {
+    eprintln!("Login failed: {error}");
+    std::process::exit(error.exit_code());
+}
 
 fn main() {
+    // An explicit store path (users.json, users.db, ...) can be passed as the first argument.
+    let store_path = match std::env::args().nth(1) {
+        Some(path) => PathBuf::from(path),
+        None => JsonFileStore::in_project_root().path().to_path_buf(),
+    };
+    let store: Box<dyn UserStore> = open_store(&store_path).unwrap_or_else(|error| exit_with(error));
+    install_audit_log(AuditLog::next_to(&store_path));
+    install_roles(RoleRegistry::load(&store_path.with_file_name("roles.json")).unwrap_or_else(|error| exit_with(error)));
+    let idle_timeout = match std::env::var("LOGIN_IDLE_TIMEOUT") {
+        Ok(seconds) => seconds.trim().parse().unwrap_or_else(|_| {
+            exit_with(AuthError::InvalidInput(format!("LOGIN_IDLE_TIMEOUT is not a number of seconds: {seconds}")))
+        }),
+        Err(_) => DEFAULT_IDLE_TIMEOUT,
+    };
+    // The store, then htpasswd and LDAP if configured, see `ProviderChain::from_env`.
+    let providers = ProviderChain::from_env(store.as_ref()).unwrap_or_else(|error| exit_with(error));
+
     let mut tries = 0;
 
     loop {
         println!("Enter your username:");
-        let username = read_line();
+        let username = read_line().unwrap_or_else(|error| exit_with(error));
 
         println!("Enter your password:");
-        let password = read_line();
+        let password = read_line().unwrap_or_else(|error| exit_with(error));
+
+        let mut result = providers.authenticate(&username, &password);
+        let needs_second_factor = matches!(result, Ok(Some(LoginAction::NeedsSecondFactor)));
+        if needs_second_factor {
+            println!("Enter your authentication code (or a recovery code):");
+            let code = read_line().unwrap_or_else(|error| exit_with(error));
 
-        match login(&username, &password) {
-            None => {
+            result = login_second_factor(store.as_ref(), &username, &password, &code);
+        }
+
+        let last_error = match result {
+            Err(error) => exit_with(error),
+            Ok(None) => {
                 println!("Incorrect name");
+                AuthError::UserNotFound(username)
             }
-            Some(login_action) => {
+            Ok(Some(login_action)) => {
                 match login_action {
-                    LoginAction::Granted(role) => {
-                        println!("Welcome {role:?}");
+                    LoginAction::Granted(roles_granted) => {
+                        let roles = roles_granted.iter().map(|role| role.to_string()).collect::<Vec<_>>().join(", ");
+                        println!("Welcome {username} ({roles})");
+
+                        let (token, _) = issue_session(store.as_ref(), &username, roles_granted, idle_timeout, unix_now())
+                            .unwrap_or_else(|error| exit_with(error));
+                        shell::Shell::new(store.as_ref(), &username, token, idle_timeout)
+                            .run()
+                            .unwrap_or_else(|error| exit_with(error));
                         break;
                     }
-                    LoginAction::Denied => {
+                    LoginAction::Denied | LoginAction::NeedsSecondFactor if needs_second_factor => {
+                        println!("Incorrect authentication code");
+                        AuthError::InvalidPassword
+                    }
+                    LoginAction::Denied | LoginAction::NeedsSecondFactor => {
                         println!("Incorrect password");
+                        AuthError::InvalidPassword
+                    }
+                    LoginAction::Disabled => {
+                        println!("The account is disabled, ask an administrator to enable it");
+                        std::process::exit(AuthError::Disabled(username).exit_code());
+                    }
+                    LoginAction::Locked { until } => {
+                        let seconds = until.saturating_sub(unix_now());
+                        println!("Too many failed logins, the account is locked for {seconds} more seconds");
+                        std::process::exit(AuthError::Locked { until }.exit_code());
                     }
                 }
             }
-        }
+        };
 
         tries += 1;
         if tries >= 3 {
             println!("Too many failed logins!");
-            break;
+            std::process::exit(last_error.exit_code());
         }
     }
 }
@@ -0,0 +1,586 @@
+use authentication::{
+    audit, change_password, create_user, issue_session, normalize_username, permissions, refresh_session,
+    revoke_session, revoke_user_sessions, unix_now, verify_password, AuditEvent, AuthError, LoginRole, Session, User,
+    UserStore,
+};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+
+/// Name, required permission, usage and description of every shell command.
+const COMMANDS: &[(&str, Option<&str>, &str, &str)] = &[
+    ("help", None, "help", "Show the available commands"),
+    ("profile", None, "profile", "Show your profile"),
+    ("passwd", None, "passwd", "Change your password"),
+    ("logout", None, "logout", "Log out, also `exit` or Ctrl-D"),
+    ("list", Some(permissions::USERS_READ), "list", "List all users"),
+    ("show", Some(permissions::USERS_READ), "show <username>", "Show the profile of a user"),
+    ("add", Some(permissions::USERS_WRITE), "add <username> [--admin]", "Add a user, the password is prompted for"),
+    ("delete", Some(permissions::USERS_WRITE), "delete <username>", "Delete a user"),
+    ("change-password", Some(permissions::USERS_WRITE), "change-password <username>", "Set a new password for a user"),
+];
+
+/// Commands whose argument is a username, for completion.
+const USERNAME_COMMANDS: &[&str] = &["show", "delete", "change-password"];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Help,
+    Profile,
+    Passwd,
+    Logout,
+    List,
+    Show(String),
+    Add { username: String, admin: bool },
+    Delete(String),
+    ChangePassword(String),
+}
+
+impl Command {
+    /// `Ok(None)` for an empty line, `Err` with a message for the user.
+    pub fn parse(line: &str) -> Result<Option<Command>, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((name, arguments)) = words.split_first() else {
+            return Ok(None);
+        };
+
+        let command = match (*name, arguments) {
+            ("help", []) => Command::Help,
+            ("profile", []) => Command::Profile,
+            ("passwd", []) => Command::Passwd,
+            ("logout" | "exit", []) => Command::Logout,
+            ("list", []) => Command::List,
+            ("show", [username]) => Command::Show(username.to_string()),
+            ("add", [username]) => Command::Add { username: username.to_string(), admin: false },
+            ("add", [username, "--admin"]) => Command::Add { username: username.to_string(), admin: true },
+            ("delete", [username]) => Command::Delete(username.to_string()),
+            ("change-password", [username]) => Command::ChangePassword(username.to_string()),
+            _ => {
+                return match COMMANDS.iter().find(|(command, ..)| command == name) {
+                    Some((_, _, usage, _)) => Err(format!("Usage: {usage}")),
+                    None => Err(format!("Unknown command {name}, type help for the available commands")),
+                };
+            }
+        };
+
+        return Ok(Some(command));
+    }
+
+    fn name(&self) -> &'static str {
+        return match self {
+            Command::Help => "help",
+            Command::Profile => "profile",
+            Command::Passwd => "passwd",
+            Command::Logout => "logout",
+            Command::List => "list",
+            Command::Show(_) => "show",
+            Command::Add { .. } => "add",
+            Command::Delete(_) => "delete",
+            Command::ChangePassword(_) => "change-password",
+        };
+    }
+
+    fn permission(&self) -> Option<&'static str> {
+        return COMMANDS.iter().find(|(name, ..)| *name == self.name()).and_then(|(_, permission, ..)| *permission);
+    }
+
+    /// The audit event name and target of commands that manage users.
+    fn audit_event(&self) -> Option<(&'static str, &str)> {
+        return match self {
+            Command::Add { username, .. } => Some(("user.add", username)),
+            Command::Delete(username) => Some(("user.delete", username)),
+            Command::ChangePassword(username) => Some(("user.change_password", username)),
+            _ => None,
+        };
+    }
+}
+
+/// Completes command names and, after commands that take one, usernames.
+#[derive(Default)]
+struct ShellHelper {
+    commands: Vec<&'static str>,
+    usernames: Vec<String>,
+}
+
+impl Completer for ShellHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, _: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let line = &line[..pos];
+        let start = line.rfind(char::is_whitespace).map_or(0, |index| index + 1);
+        let (word, previous) = (&line[start..], line[..start].split_whitespace().collect::<Vec<_>>());
+
+        let candidates: Vec<String> = match previous.as_slice() {
+            [] => self.commands.iter().map(|command| command.to_string()).collect(),
+            [command] if USERNAME_COMMANDS.contains(command) && self.commands.contains(command) => self.usernames.clone(),
+            _ => Vec::new(),
+        };
+
+        let pairs = candidates
+            .into_iter()
+            .filter(|candidate| candidate.starts_with(word))
+            .map(|candidate| Pair { display: candidate.clone(), replacement: format!("{candidate} ") })
+            .collect();
+
+        return Ok((start, pairs));
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+/// Asks for a password without echoing it.
+pub type PasswordPrompt<'a> = dyn FnMut(&str) -> std::io::Result<String> + 'a;
+
+/// The prompt and completions for one line.
+struct Prompt {
+    prompt: String,
+    commands: Vec<&'static str>,
+    usernames: Vec<String>,
+}
+
+/// Reads lines on a thread of its own, so the shell can stop waiting at the prompt.
+struct LineReader {
+    prompts: mpsc::Sender<Prompt>,
+    lines: Receiver<rustyline::Result<String>>,
+    /// The terminal settings from before the editor switched to raw mode, put back when a read
+    /// is abandoned.
+    #[cfg(unix)]
+    terminal: Option<nix::sys::termios::Termios>,
+}
+
+impl LineReader {
+    fn editor() -> Result<Self, AuthError> {
+        let mut editor: Editor<ShellHelper, DefaultHistory> = Editor::new().map_err(readline_error)?;
+        editor.set_helper(Some(ShellHelper::default()));
+
+        let reader = Self::spawn(move |prompt| {
+            let helper = editor.helper_mut().expect("the helper is set above");
+            helper.commands = prompt.commands;
+            helper.usernames = prompt.usernames;
+
+            let line = editor.readline(&prompt.prompt)?;
+            editor.add_history_entry(line.as_str())?;
+            return Ok(line);
+        });
+
+        return Ok(Self {
+            #[cfg(unix)]
+            terminal: nix::sys::termios::tcgetattr(std::io::stdin()).ok(),
+            ..reader
+        });
+    }
+
+    fn spawn(mut read: impl FnMut(Prompt) -> rustyline::Result<String> + Send + 'static) -> Self {
+        let (prompts, requests) = mpsc::channel::<Prompt>();
+        let (replies, lines) = mpsc::channel();
+
+        std::thread::spawn(move || {
+            for prompt in requests {
+                if replies.send(read(prompt)).is_err() {
+                    return;
+                }
+            }
+        });
+
+        return Self {
+            prompts,
+            lines,
+            #[cfg(unix)]
+            terminal: None,
+        };
+    }
+
+    /// `None` when nothing was entered within `timeout`. The read is abandoned then, the
+    /// thread stays blocked on it until the process exits.
+    fn read(&self, prompt: Prompt, timeout: Duration) -> Option<rustyline::Result<String>> {
+        let closed = || ReadlineError::Io(std::io::Error::other("the prompt thread ended"));
+        if self.prompts.send(prompt).is_err() {
+            return Some(Err(closed()));
+        }
+
+        return match self.lines.recv_timeout(timeout) {
+            Ok(line) => Some(line),
+            Err(RecvTimeoutError::Timeout) => {
+                #[cfg(unix)]
+                if let Some(terminal) = &self.terminal {
+                    let _ = nix::sys::termios::tcsetattr(std::io::stdin(), nix::sys::termios::SetArg::TCSANOW, terminal);
+                    // The editor also turned on bracketed paste.
+                    print!("\x1b[?2004l");
+                }
+                None
+            }
+            Err(RecvTimeoutError::Disconnected) => Some(Err(closed())),
+        };
+    }
+}
+
+/// The shell of a logged in user. Every command refreshes the session, which expires after
+/// `idle_timeout` seconds without one. The prompt gives up at that deadline and revokes the
+/// session, and a session revoked elsewhere (e.g. the user was disabled) ends the shell at the
+/// next command.
+pub struct Shell<'a> {
+    store: &'a dyn UserStore,
+    username: String,
+    token: String,
+    idle_timeout: u64,
+    prompt_password: Box<PasswordPrompt<'a>>,
+    reader: Option<LineReader>,
+}
+
+impl<'a> Shell<'a> {
+    pub fn new(store: &'a dyn UserStore, username: &str, token: String, idle_timeout: u64) -> Self {
+        return Self {
+            store,
+            username: username.to_string(),
+            token,
+            idle_timeout,
+            prompt_password: Box::new(|prompt| rpassword::prompt_password(prompt)),
+            reader: None,
+        };
+    }
+
+    #[cfg(test)]
+    fn with_password_prompt(self, prompt_password: impl FnMut(&str) -> std::io::Result<String> + 'a) -> Self {
+        return Self { prompt_password: Box::new(prompt_password), ..self };
+    }
+
+    #[cfg(test)]
+    fn with_line_reader(self, read: impl FnMut(&str) -> rustyline::Result<String> + Send + 'static) -> Self {
+        let mut read = read;
+        return Self { reader: Some(LineReader::spawn(move |prompt| read(&prompt.prompt))), ..self };
+    }
+
+    pub fn run(mut self) -> Result<(), AuthError> {
+        let reader = match self.reader.take() {
+            Some(reader) => reader,
+            None => LineReader::editor()?,
+        };
+        println!("Type help for the available commands.");
+
+        loop {
+            let session = self.refresh(unix_now())?;
+            let prompt = Prompt {
+                prompt: format!("{}> ", self.username),
+                commands: allowed_commands(&session).map(|(name, ..)| *name).collect(),
+                usernames: match session.can(permissions::USERS_READ) {
+                    true => self.usernames()?,
+                    false => Vec::new(),
+                },
+            };
+
+            // Waits until the session expires at the latest.
+            let idle = Duration::from_secs(session.expires_at.saturating_sub(unix_now()));
+            let line = match reader.read(prompt, idle) {
+                Some(Ok(line)) => line,
+                Some(Err(ReadlineError::Interrupted)) => continue,
+                Some(Err(ReadlineError::Eof)) => return self.logout(),
+                Some(Err(error)) => return Err(readline_error(error)),
+                None => {
+                    println!();
+                    println!("Logged out after {} seconds without activity.", self.idle_timeout);
+                    return revoke_session(self.store, &self.token);
+                }
+            };
+
+            // Also catches time the timer missed, e.g. while the machine was asleep.
+            let session = match self.refresh(unix_now()) {
+                Ok(session) => session,
+                Err(AuthError::SessionExpired) => {
+                    println!("Logged out after {} seconds without activity.", self.idle_timeout);
+                    return Ok(());
+                }
+                Err(AuthError::InvalidSession) => {
+                    println!("Your session was ended, log in again.");
+                    return Ok(());
+                }
+                Err(error) => return Err(error),
+            };
+
+            match Command::parse(&line) {
+                Ok(Some(Command::Logout)) => return self.logout(),
+                Ok(Some(command)) => {
+                    if let Err(error) = self.execute(&session, command) {
+                        println!("Error: {error}");
+                    }
+                }
+                Ok(None) => {}
+                Err(message) => println!("{message}"),
+            }
+        }
+    }
+
+    fn refresh(&self, now: u64) -> Result<Session, AuthError> {
+        return refresh_session(self.store, &self.token, self.idle_timeout, now);
+    }
+
+    fn logout(&self) -> Result<(), AuthError> {
+        println!("Bye {}", self.username);
+        return revoke_session(self.store, &self.token);
+    }
+
+    fn usernames(&self) -> Result<Vec<String>, AuthError> {
+        let mut usernames: Vec<String> = self.store.load()?.into_values().map(|user| user.username).collect();
+        usernames.sort();
+
+        return Ok(usernames);
+    }
+
+    fn new_password(&mut self) -> Result<String, AuthError> {
+        let password = (self.prompt_password)("New password: ")?;
+        if (self.prompt_password)("Repeat the new password: ")? != password {
+            return Err(AuthError::InvalidInput("the passwords do not match".to_string()));
+        }
+
+        return Ok(password);
+    }
+
+    /// Changing the own password revokes every session of the user, this one continues with a
+    /// new token.
+    fn change_password(&mut self, session: &Session, username: &str) -> Result<(), AuthError> {
+        let password = self.new_password()?;
+        change_password(self.store, username, &password)?;
+
+        if normalize_username(username) == normalize_username(&self.username) {
+            let (token, _) = issue_session(self.store, &self.username, session.roles.clone(), self.idle_timeout, unix_now())?;
+            self.token = token;
+        }
+
+        println!("The password of {username} was changed.");
+        return Ok(());
+    }
+
+    pub fn execute(&mut self, session: &Session, command: Command) -> Result<(), AuthError> {
+        if let Some(permission) = command.permission().filter(|permission| !session.can(permission)) {
+            return Err(AuthError::PermissionDenied(permission.to_string()));
+        }
+
+        let Some((event, target)) = command.audit_event() else {
+            return self.run_command(session, command);
+        };
+
+        let (event, target) = (event, target.to_string());
+        let result = self.run_command(session, command);
+        let record = match &result {
+            Ok(()) => AuditEvent::success(&self.username, event, Some(&target)),
+            Err(error) => AuditEvent::failure(&self.username, event, Some(&target), &error.to_string()),
+        };
+        audit(record)?;
+
+        return result;
+    }
+
+    fn run_command(&mut self, session: &Session, command: Command) -> Result<(), AuthError> {
+        match command {
+            Command::Help => {
+                allowed_commands(session).for_each(|(_, _, usage, description)| println!("  {usage:<32}{description}"));
+            }
+            Command::Profile => match self.store.get(&self.username)? {
+                Some(user) => print_user(&user),
+                None => {
+                    println!("Username:       {}", self.username);
+                    println!("Roles:          {}", format_roles(&session.roles));
+                    println!("This account is managed outside of the user store.");
+                }
+            },
+            Command::Passwd => {
+                let Some(user) = self.store.get(&self.username)? else {
+                    return Err(AuthError::InvalidInput("the password of this account is managed elsewhere".to_string()));
+                };
+                if !verify_password(&(self.prompt_password)("Current password: ")?, &user.password)? {
+                    return Err(AuthError::InvalidPassword);
+                }
+                let username = self.username.clone();
+                self.change_password(session, &username)?;
+            }
+            Command::Logout => {}
+            Command::List => {
+                let mut users: Vec<User> = self.store.load()?.into_values().collect();
+                users.sort_by(|first, second| first.username.cmp(&second.username));
+
+                println!("{:<20}{:<24}{:<16}Status", "Username", "Name", "Roles");
+                println!("{:-<70}", "");
+                users.iter().for_each(|user| {
+                    let status = if user.disabled { "disabled" } else if user.is_locked(unix_now()) { "locked" } else { "active" };
+                    println!("{:<20}{:<24}{:<16}{status}", user.username, user.name(), format_roles(&user.roles));
+                });
+            }
+            Command::Show(username) => match self.store.get(&username)? {
+                Some(user) => print_user(&user),
+                None => return Err(AuthError::UserNotFound(username)),
+            },
+            Command::Add { username, admin } => {
+                let role = if admin { LoginRole::admin() } else { LoginRole::user() };
+                let password = self.new_password()?;
+                create_user(self.store, User::new(&username, &password, role)?)?;
+                println!("Added {username}.");
+            }
+            Command::Delete(username) => {
+                if normalize_username(&username) == normalize_username(&self.username) {
+                    return Err(AuthError::InvalidInput("you cannot delete your own account while logged in".to_string()));
+                }
+                if !self.store.delete(&username)? {
+                    return Err(AuthError::UserNotFound(username));
+                }
+                revoke_user_sessions(self.store, &username)?;
+                println!("Deleted {username}.");
+            }
+            Command::ChangePassword(username) => {
+                self.change_password(session, &username)?;
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+fn allowed_commands(session: &Session) -> impl Iterator<Item = &'static (&'static str, Option<&'static str>, &'static str, &'static str)> + '_ {
+    return COMMANDS.iter().filter(|(_, permission, ..)| permission.is_none_or(|permission| session.can(permission)));
+}
+
+fn readline_error(error: ReadlineError) -> AuthError {
+    return match error {
+        ReadlineError::Io(error) => AuthError::Io(error),
+        error => AuthError::Io(std::io::Error::other(error.to_string())),
+    };
+}
+
+fn format_roles(roles: &[LoginRole]) -> String {
+    return roles.iter().map(|role| role.to_string()).collect::<Vec<_>>().join(", ");
+}
+
+fn print_user(user: &User) {
+    let time = |time: Option<u64>, missing: &str| time.map_or(missing.to_string(), |time| time.to_string());
+
+    println!("Username:       {}", user.username);
+    println!("Display name:   {}", user.display_name.as_deref().unwrap_or("-"));
+    println!("Email:          {}", user.email.as_deref().unwrap_or("-"));
+    println!("Roles:          {}", format_roles(&user.roles));
+    println!("Two-factor:     {}", if user.totp.is_some() { "on" } else { "off" });
+    println!("Created:        {}", time(user.created_at, "unknown"));
+    println!("Last login:     {}", time(user.last_login_at, "never"));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use authentication::{validate_session, MemoryStore};
+
+    const PASSWORD: &str = "Correct-Horse-7";
+
+    fn store() -> MemoryStore {
+        let admin = User::new("admin", PASSWORD, LoginRole::admin()).unwrap();
+        let bob = User::new("bob", PASSWORD, LoginRole::user()).unwrap();
+        return MemoryStore::with_users(HashMap::from([("admin".to_string(), admin), ("bob".to_string(), bob)]));
+    }
+
+    fn login<'a>(store: &'a MemoryStore, username: &str, role: LoginRole, answers: &'a [&'a str]) -> (Shell<'a>, Session) {
+        let (token, session) = issue_session(store, username, vec![role], 60, unix_now()).unwrap();
+        let mut answers = answers.iter();
+        let shell = Shell::new(store, username, token, 60)
+            .with_password_prompt(move |_| Ok(answers.next().expect("no more prompts expected").to_string()));
+
+        return (shell, session);
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(Command::parse("  ").unwrap(), None);
+        assert_eq!(Command::parse("add carol --admin").unwrap(), Some(Command::Add { username: "carol".to_string(), admin: true }));
+        assert_eq!(Command::parse("exit").unwrap(), Some(Command::Logout));
+        assert_eq!(Command::parse("delete").unwrap_err(), "Usage: delete <username>");
+        assert!(Command::parse("rm -rf").unwrap_err().starts_with("Unknown command rm"));
+    }
+
+    #[test]
+    fn test_users_cannot_manage_users() {
+        let store = store();
+        let (mut shell, session) = login(&store, "bob", LoginRole::user(), &[]);
+
+        assert!(matches!(shell.execute(&session, Command::List), Err(AuthError::PermissionDenied(_))));
+        assert!(matches!(shell.execute(&session, Command::Delete("admin".to_string())), Err(AuthError::PermissionDenied(_))));
+        assert!(store.get("admin").unwrap().is_some());
+        shell.execute(&session, Command::Profile).unwrap();
+    }
+
+    #[test]
+    fn test_admins_manage_users() {
+        let store = store();
+        let answers = ["Another-Horse-8", "Another-Horse-8", "Mismatch-Horse-9", "Other-Horse-10"];
+        let (mut shell, session) = login(&store, "admin", LoginRole::admin(), &answers);
+
+        shell.execute(&session, Command::Add { username: "carol".to_string(), admin: false }).unwrap();
+        assert!(authentication::authenticate(&store, "carol", "Another-Horse-8").is_ok());
+        assert!(matches!(shell.execute(&session, Command::ChangePassword("carol".to_string())), Err(AuthError::InvalidInput(_))));
+
+        shell.execute(&session, Command::Delete("carol".to_string())).unwrap();
+        assert!(store.get("carol").unwrap().is_none());
+        assert!(matches!(shell.execute(&session, Command::Delete("Admin".to_string())), Err(AuthError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn test_own_password_change_keeps_the_shell_logged_in() {
+        let store = store();
+        let (mut shell, session) = login(&store, "bob", LoginRole::user(), &[PASSWORD, "Another-Horse-8", "Another-Horse-8"]);
+        let old_token = shell.token.clone();
+
+        shell.execute(&session, Command::Passwd).unwrap();
+        assert!(validate_session(&store, &old_token, unix_now()).is_err());
+        assert!(shell.refresh(unix_now()).is_ok());
+        assert!(authentication::authenticate(&store, "bob", "Another-Horse-8").is_ok());
+    }
+
+    #[test]
+    fn test_idle_sessions_expire() {
+        let store = store();
+        let (shell, _) = login(&store, "bob", LoginRole::user(), &[]);
+        let now = unix_now();
+
+        assert!(shell.refresh(now + 30).is_ok());
+        assert!(shell.refresh(now + 60).is_ok());
+        assert!(matches!(shell.refresh(now + 121), Err(AuthError::SessionExpired)));
+
+        // Nobody answers the prompt, it gives up once the session expires and revokes it.
+        let (token, _) = issue_session(&store, "bob", vec![LoginRole::user()], 1, unix_now()).unwrap();
+        let shell = Shell::new(&store, "bob", token.clone(), 1).with_line_reader(|_| {
+            std::thread::sleep(Duration::from_secs(3600));
+            return Err(ReadlineError::Eof);
+        });
+        let started = std::time::Instant::now();
+        shell.run().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(3));
+        assert!(matches!(validate_session(&store, &token, unix_now()), Err(AuthError::InvalidSession)));
+    }
+
+    #[test]
+    fn test_completion() {
+        let helper = ShellHelper { commands: vec!["show", "list", "logout"], usernames: vec!["admin".to_string(), "bob".to_string()] };
+        let history = DefaultHistory::new();
+        let context = Context::new(&history);
+        let complete = |line: &str| {
+            let (start, pairs) = helper.complete(line, line.len(), &context).unwrap();
+            return (start, pairs.into_iter().map(|pair| pair.display).collect::<Vec<_>>());
+        };
+
+        assert_eq!(complete("l"), (0, vec!["list".to_string(), "logout".to_string()]));
+        assert_eq!(complete("show b"), (5, vec!["bob".to_string()]));
+        assert_eq!(complete("delete b"), (7, vec![]));
+        assert_eq!(complete("list "), (5, vec![]));
+    }
+}
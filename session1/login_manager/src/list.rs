@@ -0,0 +1,249 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use authentication::{normalize_username, AuthError, LoginRole, User};
+use clap::{Args, ValueEnum};
+use serde::Serialize;
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Csv,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum SortKey {
+    Username,
+    DisplayName,
+    Email,
+    Created,
+    Updated,
+    LastLogin,
+}
+
+#[derive(Args)]
+pub struct ListOptions {
+    #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+    pub output: OutputFormat,
+    /// Users without a value sort first, ties are sorted by username.
+    #[arg(long, value_enum, default_value_t = SortKey::Username)]
+    pub sort: SortKey,
+    #[arg(long)]
+    pub reverse: bool,
+    /// Only users whose username, display name or email contains this, ignoring case.
+    #[arg(long)]
+    pub search: Option<String>,
+    #[arg(long)]
+    pub role: Option<String>,
+    #[arg(long)]
+    pub disabled: Option<bool>,
+    #[arg(long)]
+    pub locked: Option<bool>,
+    /// Only users created at or after this unix time.
+    #[arg(long)]
+    pub created_since: Option<u64>,
+    /// Only users who have not logged in since this unix time, including those who never did.
+    #[arg(long)]
+    pub inactive_since: Option<u64>,
+}
+
+/// What `list` and `show` print of a user, never the password hash.
+#[derive(Debug, Serialize)]
+pub struct ListedUser {
+    pub username: String,
+    pub display_name: Option<String>,
+    pub email: Option<String>,
+    pub roles: String,
+    pub disabled: bool,
+    pub locked: bool,
+    pub two_factor: bool,
+    pub failed_attempts: u32,
+    pub created_at: Option<u64>,
+    pub updated_at: Option<u64>,
+    pub last_login_at: Option<u64>,
+}
+
+impl ListedUser {
+    pub fn new(user: User, now: u64) -> Self {
+        return Self {
+            locked: user.is_locked(now),
+            two_factor: user.totp.is_some(),
+            roles: user.roles.iter().map(LoginRole::to_string).collect::<Vec<_>>().join(","),
+            username: user.username,
+            display_name: user.display_name,
+            email: user.email,
+            disabled: user.disabled,
+            failed_attempts: user.failed_attempts,
+            created_at: user.created_at,
+            updated_at: user.updated_at,
+            last_login_at: user.last_login_at,
+        };
+    }
+
+    fn status(&self) -> &'static str {
+        return match (self.disabled, self.locked) {
+            (true, _) => "disabled",
+            (false, true) => "locked",
+            (false, false) => "active",
+        };
+    }
+}
+
+fn matches(user: &User, options: &ListOptions, now: u64) -> bool {
+    let search = options.search.as_ref().is_none_or(|search| {
+        let search = search.to_lowercase();
+        return [Some(&user.username), user.display_name.as_ref(), user.email.as_ref()]
+            .into_iter()
+            .flatten()
+            .any(|value| value.to_lowercase().contains(&search));
+    });
+
+    return search
+        && options.role.as_ref().is_none_or(|role| user.has_role(&LoginRole::new(role)))
+        && options.disabled.is_none_or(|disabled| user.disabled == disabled)
+        && options.locked.is_none_or(|locked| user.is_locked(now) == locked)
+        && options.created_since.is_none_or(|since| user.created_at.is_some_and(|created| created >= since))
+        && options.inactive_since.is_none_or(|since| user.last_login_at.is_none_or(|login| login < since));
+}
+
+fn compare(first: &ListedUser, second: &ListedUser, key: SortKey) -> Ordering {
+    let text = |value: &Option<String>| value.as_ref().map(|value| value.to_lowercase());
+
+    let ordering = match key {
+        SortKey::Username => Ordering::Equal,
+        SortKey::DisplayName => text(&first.display_name).cmp(&text(&second.display_name)),
+        SortKey::Email => text(&first.email).cmp(&text(&second.email)),
+        SortKey::Created => first.created_at.cmp(&second.created_at),
+        SortKey::Updated => first.updated_at.cmp(&second.updated_at),
+        SortKey::LastLogin => first.last_login_at.cmp(&second.last_login_at),
+    };
+
+    return ordering.then_with(|| normalize_username(&first.username).cmp(&normalize_username(&second.username)));
+}
+
+/// The users that match the filters of `options`, in its order.
+pub fn select_users(users: HashMap<String, User>, options: &ListOptions, now: u64) -> Vec<ListedUser> {
+    let mut selected: Vec<ListedUser> = users
+        .into_values()
+        .filter(|user| matches(user, options, now))
+        .map(|user| ListedUser::new(user, now))
+        .collect();
+
+    selected.sort_by(|first, second| compare(first, second, options.sort));
+    if options.reverse {
+        selected.reverse();
+    }
+
+    return selected;
+}
+
+fn format_time(time: Option<u64>, missing: &str) -> String {
+    return time.map_or(missing.to_string(), |time| time.to_string());
+}
+
+pub fn print_users(users: &[ListedUser], output: OutputFormat) -> Result<(), AuthError> {
+    match output {
+        OutputFormat::Table => {
+            println!("{:<20}{:<24}{:<16}{:<10}Last login", "Username", "Name", "Roles", "Status");
+            println!("{:-<80}", "");
+
+            users.iter().for_each(|user| {
+                println!(
+                    "{:<20}{:<24}{:<16}{:<10}{}",
+                    user.username,
+                    user.display_name.as_deref().unwrap_or("-"),
+                    user.roles,
+                    user.status(),
+                    format_time(user.last_login_at, "never")
+                )
+            });
+        }
+        OutputFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(users)?);
+        }
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(std::io::stdout());
+            for user in users {
+                writer.serialize(user).map_err(|error| AuthError::InvalidInput(error.to_string()))?;
+            }
+            writer.flush()?;
+        }
+    }
+
+    return Ok(());
+}
+
+/// One user, one field per line for the table format.
+pub fn print_user(user: ListedUser, output: OutputFormat) -> Result<(), AuthError> {
+    match output {
+        OutputFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(&user)?);
+            return Ok(());
+        }
+        OutputFormat::Csv => return print_users(&[user], output),
+        OutputFormat::Table => {}
+    }
+
+    let fields = [
+        ("Username", user.username.clone()),
+        ("Display name", user.display_name.clone().unwrap_or("-".to_string())),
+        ("Email", user.email.clone().unwrap_or("-".to_string())),
+        ("Roles", user.roles.clone()),
+        ("Status", user.status().to_string()),
+        ("Two-factor", if user.two_factor { "on" } else { "off" }.to_string()),
+        ("Failed logins", user.failed_attempts.to_string()),
+        ("Created", format_time(user.created_at, "unknown")),
+        ("Updated", format_time(user.updated_at, "unknown")),
+        ("Last login", format_time(user.last_login_at, "never")),
+    ];
+    fields.iter().for_each(|(name, value)| println!("{:<16}{value}", format!("{name}:")));
+
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        options: ListOptions,
+    }
+
+    fn user(username: &str, display_name: &str, created_at: u64, last_login_at: Option<u64>) -> (String, User) {
+        let mut user = User::new(username, "Correct-Horse-7", LoginRole::user())
+            .unwrap()
+            .with_profile(Some(display_name), None)
+            .unwrap();
+        user.created_at = Some(created_at);
+        user.last_login_at = last_login_at;
+
+        return (username.to_string(), user);
+    }
+
+    fn list(arguments: &str) -> Vec<String> {
+        let users = HashMap::from([
+            user("carol", "Carol Danvers", 300, None),
+            user("alice", "Alice Liddell", 100, Some(500)),
+            user("bob", "Robert", 200, Some(50)),
+        ]);
+        let options = Cli::parse_from(std::iter::once("list").chain(arguments.split_whitespace())).options;
+
+        return select_users(users, &options, 1_000).into_iter().map(|user| user.username).collect();
+    }
+
+    #[test]
+    fn test_list_sorts_and_filters() {
+        assert_eq!(list(""), ["alice", "bob", "carol"]);
+        assert_eq!(list("--sort display-name"), ["alice", "carol", "bob"]);
+        assert_eq!(list("--sort last-login --reverse"), ["alice", "bob", "carol"]);
+        assert_eq!(list("--search ROB"), ["bob"]);
+        assert_eq!(list("--created-since 200 --sort created"), ["bob", "carol"]);
+        assert_eq!(list("--inactive-since 100"), ["bob", "carol"]);
+        assert_eq!(list("--disabled true"), Vec::<String>::new());
+        assert_eq!(list("--role admin"), Vec::<String>::new());
+    }
+}
@@ -1,93 +1,560 @@
+#![allow(clippy::needless_return)]
+
+mod list;
+mod transfer;
+
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
 use clap::{Parser, Subcommand};
-use authentication::{get_users, LoginRole, save_users, User};
+use list::{ListOptions, ListedUser, OutputFormat};
+use transfer::DataFormat;
+use authentication::{
+    audit, current_actor, install_audit_log, open_store, AuditEvent, AuditFilter, AuditLog, AuditOutcome, AuthError,
+    JsonFileStore, LoginRole, RoleRegistry, StoreKey, User, UserStore,
+};
 
 #[derive(Parser)]
 #[command()]
 struct Args {
+    /// Path to the user store, `.db`/`.sqlite`/`.sqlite3` files use SQLite, anything else JSON.
+    /// Defaults to users.json in the project root.
+    #[arg(long, global = true)]
+    store: Option<PathBuf>,
+    /// Path to the role definitions. Defaults to roles.json next to the user store.
+    #[arg(long, global = true)]
+    roles: Option<PathBuf>,
+    /// Path to the audit log. Defaults to audit.jsonl next to the user store.
+    #[arg(long, global = true)]
+    audit_log: Option<PathBuf>,
     #[command(subcommand)]
     command: Option<Commands>,
 }
 
 #[derive(Subcommand)]
 enum Commands {
-    /// List all users.
-    List,
-    /// Add a user.
+    /// List users, by default all of them sorted by username.
+    List(ListOptions),
+    /// Show everything but the password of one user.
+    Show {
+        username: String,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        output: OutputFormat,
+    },
+    /// Add a user. Without a password argument it is prompted for, hidden.
     Add {
         username: String,
-        password: String,
+        /// Visible to other users in `ps` and kept in the shell history, prefer the prompt or --password-stdin.
+        password: Option<String>,
         #[arg(long)]
         admin: Option<bool>,
+        /// Read the password from the first line of stdin.
+        #[arg(long, conflicts_with = "password")]
+        password_stdin: bool,
+        #[arg(long)]
+        display_name: Option<String>,
+        #[arg(long)]
+        email: Option<String>,
+    },
+    /// Change the display name or email of a user, an empty value clears it.
+    Edit {
+        username: String,
+        #[arg(long)]
+        display_name: Option<String>,
+        #[arg(long)]
+        email: Option<String>,
+    },
+    /// Change the username. Sessions end unless only the spelling changes.
+    Rename {
+        username: String,
+        new_username: String,
+    },
+    /// Let a disabled user log in again.
+    Enable {
+        username: String,
+    },
+    /// Stop a user from logging in and end their sessions, without deleting them.
+    Disable {
+        username: String,
     },
     Delete {
         username: String
     },
+    /// Change the password of a user. Without a password argument it is prompted for, hidden.
     ChangePassword {
         username: String,
+        /// Visible to other users in `ps` and kept in the shell history, prefer the prompt or --password-stdin.
+        new_password: Option<String>,
+        /// Read the password from the first line of stdin.
+        #[arg(long, conflicts_with = "new_password")]
+        password_stdin: bool,
+    },
+    /// Add users in bulk from a CSV or JSON file with username, password or password_hash, and roles.
+    Import {
+        /// The file to read, `-` for stdin.
+        file: PathBuf,
+        /// Defaults to the file extension.
+        #[arg(long, value_enum)]
+        format: Option<DataFormat>,
+        /// Only report what would change.
+        #[arg(long)]
+        dry_run: bool,
+        /// Replace the password and roles of existing users instead of failing.
+        #[arg(long)]
+        update: bool,
+    },
+    /// Write all users, with password hashes, in a format `import` reads.
+    Export {
+        /// The file to write, stdout when missing.
+        file: Option<PathBuf>,
+        /// Defaults to the file extension, or JSON on stdout.
+        #[arg(long, value_enum)]
+        format: Option<DataFormat>,
+        /// Replace the file if it exists.
+        #[arg(long, requires = "file")]
+        force: bool,
+    },
+    /// Clear failed login attempts and any lockout.
+    Unlock {
+        username: String,
+    },
+    /// Turn on TOTP two-factor authentication and print the secret and recovery codes.
+    #[command(name = "enable-2fa")]
+    Enable2fa {
+        username: String,
+        /// Name shown next to the account in authenticator apps.
+        #[arg(long, default_value = "Ardan Labs")]
+        issuer: String,
+    },
+    /// Turn off two-factor authentication.
+    #[command(name = "disable-2fa")]
+    Disable2fa {
+        username: String,
+    },
+    /// Manage roles and their permissions.
+    #[command(subcommand)]
+    Role(RoleCommands),
+    /// Encrypt the user store with the key from USERS_STORE_KEY, USERS_STORE_KEY_FILE or
+    /// USERS_STORE_PASSPHRASE.
+    Encrypt {
+        /// Generate a new random key into this file and encrypt with it instead.
+        #[arg(long)]
+        generate_key: Option<PathBuf>,
+    },
+    /// Decrypt the user store back to plain JSON.
+    Decrypt,
+    /// Show the audit log, oldest first.
+    Audit {
+        #[arg(long)]
+        actor: Option<String>,
+        /// An event name such as `login`, or a prefix such as `user.` for all user management.
+        #[arg(long)]
+        event: Option<String>,
+        #[arg(long)]
+        target: Option<String>,
+        /// `success` or `failure`.
+        #[arg(long)]
+        outcome: Option<AuditOutcome>,
+        /// Only events at or after this unix time.
+        #[arg(long)]
+        since: Option<u64>,
+        /// Only the last N matching events.
+        #[arg(long, short = 'n')]
+        tail: Option<usize>,
+        /// Keep printing new events as they are written.
+        #[arg(long, short = 'f')]
+        follow: bool,
+    },
+}
 
-        new_password: String,
+#[derive(Subcommand)]
+enum RoleCommands {
+    /// List all roles with their permissions.
+    List,
+    /// Create a role, or replace the permissions of an existing one.
+    Create {
+        name: String,
+        /// Permissions such as `users:write` or `images:upload`, `*` grants everything.
+        permissions: Vec<String>,
+    },
+    /// Delete a role definition.
+    Delete {
+        name: String,
+    },
+    /// Give a role to a user.
+    Grant {
+        username: String,
+        role: String,
+    },
+    /// Take a role away from a user.
+    Revoke {
+        username: String,
+        role: String,
     },
 }
 
-fn list_users() {
-    println!("{:<20}{:<20}", "Username", "Password");
-    println!("{:-<40}", "");
+fn list_users(store: &dyn UserStore, options: ListOptions) -> Result<(), AuthError> {
+    let users = list::select_users(store.load()?, &options, authentication::unix_now());
+    return list::print_users(&users, options.output);
+}
+
+fn show_user(store: &dyn UserStore, username: String, output: OutputFormat) -> Result<(), AuthError> {
+    let Some(user) = store.get(&username)? else {
+        return Err(AuthError::UserNotFound(username));
+    };
+
+    return list::print_user(ListedUser::new(user, authentication::unix_now()), output);
+}
+
+/// The password from the argument, the first line of stdin, or a hidden prompt that asks twice.
+fn read_password(password: Option<String>, from_stdin: bool) -> Result<String, AuthError> {
+    if let Some(password) = password {
+        return Ok(password);
+    }
+
+    if from_stdin {
+        let mut password = String::new();
+        std::io::stdin().lock().read_line(&mut password)?;
+        return Ok(password.trim_end_matches(['\n', '\r']).to_string());
+    }
+
+    let password = rpassword::prompt_password("Password: ")?;
+    if rpassword::prompt_password("Repeat the password: ")? != password {
+        return Err(AuthError::InvalidInput("the passwords do not match".to_string()));
+    }
+
+    return Ok(password);
+}
+
+fn import_users(store: &dyn UserStore, roles_path: &Path, file: PathBuf, format: Option<DataFormat>, dry_run: bool, update: bool) -> Result<(), AuthError> {
+    let from_stdin = file.as_os_str() == "-";
+    let format = DataFormat::pick(format, (!from_stdin).then_some(file.as_path()))?;
+    let records = match from_stdin {
+        true => transfer::read_records(std::io::stdin().lock(), format)?,
+        false => transfer::read_records(std::fs::File::open(&file)?, format)?,
+    };
+
+    let registry = RoleRegistry::load(roles_path)?;
+    let report = transfer::import_records(store, &registry, &records, update, dry_run)?;
+    report.lines.iter().for_each(|line| println!("{line}"));
+
+    if report.errors > 0 {
+        return Err(AuthError::InvalidInput(format!(
+            "{} of {} records are invalid, nothing was imported",
+            report.errors,
+            records.len()
+        )));
+    }
+
+    let verb = if dry_run { "Would import" } else { "Imported" };
+    println!("{verb} {} new and {} existing users", report.added, report.updated);
+    return Ok(());
+}
+
+/// The file holds password hashes, so it is only readable by its owner.
+fn export_users(store: &dyn UserStore, file: Option<PathBuf>, format: Option<DataFormat>, force: bool) -> Result<(), AuthError> {
+    let records = transfer::export_records(store)?;
 
-    get_users()
-        .iter()
-        .for_each(|(_, user)| {
-            println!("{:<20}{:20?}", user.username, user.role)
-        });
+    return match file {
+        Some(file) => {
+            let format = DataFormat::pick(format, Some(&file))?;
+            if force {
+                match std::fs::remove_file(&file) {
+                    Err(error) if error.kind() != std::io::ErrorKind::NotFound => return Err(error.into()),
+                    _ => {}
+                }
+            }
+            let output = create_private(&file).map_err(|error| match error.kind() {
+                std::io::ErrorKind::AlreadyExists => {
+                    AuthError::InvalidInput(format!("{} already exists, pass --force to replace it", file.display()))
+                }
+                _ => error.into(),
+            })?;
+            transfer::write_records(output, format, &records)
+        }
+        None => transfer::write_records(std::io::stdout().lock(), format.unwrap_or(DataFormat::Json), &records),
+    };
 }
 
-fn add_user(username: String, password: String, admin: bool) {
-    let mut users = get_users();
+fn add_user(store: &dyn UserStore, username: String, password: String, admin: bool, display_name: Option<String>, email: Option<String>) -> Result<(), AuthError> {
     let role = if admin {
-        LoginRole::Admin
+        LoginRole::admin()
     } else {
-        LoginRole::User
+        LoginRole::user()
     };
 
-    let user = User::new(&username, &password, role);
-    users.insert(username, user);
-    save_users(users);
+    let user = User::new(&username, &password, role)?.with_profile(display_name.as_deref(), email.as_deref())?;
+    return authentication::create_user(store, user);
 }
 
-fn delete_user(username: String) {
-    let mut users = get_users();
-    if let None = users.remove(&username) {
-        println!("{username} does not exist");
-    } else {
-        save_users(users);
+fn delete_user(store: &dyn UserStore, username: String) -> Result<(), AuthError> {
+    if !store.delete(&username)? {
+        return Err(AuthError::UserNotFound(username));
     }
+
+    return authentication::revoke_user_sessions(store, &username);
 }
 
-fn change_password(username: String, new_password: String) {
-    let mut users = get_users();
-    if let Some(user) = users.get_mut(&username) {
-        user.password = authentication::hash_password(&new_password);
-        save_users(users);
-    } else {
-        println!("{username} does not exist");
+fn enable_two_factor(store: &dyn UserStore, username: String, issuer: String) -> Result<(), AuthError> {
+    let enrollment = authentication::enable_totp(store, &username, &issuer)?;
+
+    println!("Secret: {}", enrollment.config.secret);
+    println!("Add it to an authenticator app, or scan this URI as a QR code:");
+    println!("{}", enrollment.uri);
+    println!();
+    println!("Recovery codes, each works once. They will not be shown again:");
+    enrollment.recovery_codes.iter().for_each(|code| println!("  {code}"));
+
+    return Ok(());
+}
+
+fn manage_roles(store: &dyn UserStore, roles_path: &Path, command: RoleCommands) -> Result<(), AuthError> {
+    let mut registry = RoleRegistry::load(roles_path)?;
+
+    return match command {
+        RoleCommands::List => {
+            println!("{:<20}{:<20}", "Role", "Permissions");
+            println!("{:-<40}", "");
+
+            registry.roles().for_each(|(role, permissions)| {
+                let permissions = permissions.iter().cloned().collect::<Vec<_>>().join(",");
+                println!("{:<20}{:<20}", role.to_string(), permissions)
+            });
+
+            Ok(())
+        }
+        RoleCommands::Create { name, permissions } => {
+            registry.define(LoginRole::new(&name), permissions);
+            registry.save(roles_path)
+        }
+        RoleCommands::Delete { name } => {
+            if !registry.remove(&LoginRole::new(&name)) {
+                return Err(AuthError::UnknownRole(name));
+            }
+            registry.save(roles_path)
+        }
+        RoleCommands::Grant { username, role } => {
+            authentication::grant_role(store, &registry, &username, LoginRole::new(&role))
+        }
+        RoleCommands::Revoke { username, role } => {
+            authentication::revoke_role(store, &username, &LoginRole::new(&role))
+        }
+    };
+}
+
+fn print_audit_event(event: &AuditEvent) {
+    println!(
+        "{:<12}{:<16}{:<24}{:<16}{:<10}{}",
+        event.timestamp,
+        event.actor,
+        event.event,
+        event.target.as_deref().unwrap_or("-"),
+        event.outcome.to_string(),
+        event.detail.as_deref().unwrap_or("")
+    );
+}
+
+fn show_audit(log: &AuditLog, filter: AuditFilter, tail: Option<usize>, follow: bool) -> Result<(), AuthError> {
+    let (events, mut offset) = log.read_from(0, &filter)?;
+    let skip = tail.map_or(0, |tail| events.len().saturating_sub(tail));
+
+    println!("{:<12}{:<16}{:<24}{:<16}{:<10}Detail", "Timestamp", "Actor", "Event", "Target", "Outcome");
+    println!("{:-<90}", "");
+    events.iter().skip(skip).for_each(print_audit_event);
+
+    // Recorded before following, which only ends when interrupted.
+    audit(AuditEvent::success(&current_actor(), "audit.read", None))?;
+
+    if !follow {
+        return Ok(());
+    }
+
+    loop {
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        let (events, next_offset) = log.read_from(offset, &filter)?;
+        events.iter().for_each(print_audit_event);
+        offset = next_offset;
     }
 }
 
+/// The audit event name, target and context of a command.
+fn describe(command: &Commands) -> (&'static str, Option<String>, Option<String>) {
+    return match command {
+        Commands::List(_) => ("user.list", None, None),
+        Commands::Show { username, .. } => ("user.show", Some(username.clone()), None),
+        Commands::Edit { username, .. } => ("user.edit", Some(username.clone()), None),
+        Commands::Rename { username, new_username } => ("user.rename", Some(username.clone()), Some(format!("to {new_username}"))),
+        Commands::Enable { username } => ("user.enable", Some(username.clone()), None),
+        Commands::Disable { username } => ("user.disable", Some(username.clone()), None),
+        Commands::Add { username, admin, .. } => {
+            let role = if admin.unwrap_or(false) { LoginRole::admin() } else { LoginRole::user() };
+            ("user.add", Some(username.clone()), Some(format!("role {role}")))
+        }
+        Commands::Delete { username } => ("user.delete", Some(username.clone()), None),
+        Commands::ChangePassword { username, .. } => ("user.change_password", Some(username.clone()), None),
+        Commands::Unlock { username } => ("user.unlock", Some(username.clone()), None),
+        Commands::Enable2fa { username, .. } => ("user.enable_2fa", Some(username.clone()), None),
+        Commands::Disable2fa { username } => ("user.disable_2fa", Some(username.clone()), None),
+        Commands::Role(RoleCommands::List) => ("role.list", None, None),
+        Commands::Role(RoleCommands::Create { name, permissions }) => {
+            ("role.create", Some(name.clone()), Some(format!("permissions {}", permissions.join(","))))
+        }
+        Commands::Role(RoleCommands::Delete { name }) => ("role.delete", Some(name.clone()), None),
+        Commands::Role(RoleCommands::Grant { username, role }) => ("role.grant", Some(username.clone()), Some(format!("role {role}"))),
+        Commands::Role(RoleCommands::Revoke { username, role }) => ("role.revoke", Some(username.clone()), Some(format!("role {role}"))),
+        Commands::Import { file, dry_run, .. } => {
+            let context = format!("from {}{}", file.display(), if *dry_run { ", dry run" } else { "" });
+            ("user.import", None, Some(context))
+        }
+        Commands::Export { .. } => ("user.export", None, None),
+        Commands::Encrypt { .. } => ("store.encrypt", None, None),
+        Commands::Decrypt => ("store.decrypt", None, None),
+        Commands::Audit { .. } => ("audit.read", None, None),
+    };
+}
 
-fn main() {
-    let cli = Args::parse();
-    match cli.command {
-        Some(Commands::List) => {
-            list_users();
+fn is_sqlite(store_path: &Path) -> bool {
+    return matches!(store_path.extension().and_then(|extension| extension.to_str()), Some("db" | "sqlite" | "sqlite3"));
+}
+
+/// Creates a new file only the owner can read, for keys and password hashes.
+fn create_private(path: &Path) -> std::io::Result<std::fs::File> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    return options.open(path);
+}
+
+fn encrypt_store(store_path: &Path, generate_key: Option<PathBuf>) -> Result<(), AuthError> {
+    let store = JsonFileStore::new(store_path).with_key_from_env()?;
+
+    let key = match generate_key {
+        Some(key_path) => {
+            let mut key_file = create_private(&key_path).map_err(|error| match error.kind() {
+                std::io::ErrorKind::AlreadyExists => {
+                    AuthError::InvalidKey(format!("{} already exists, refusing to overwrite it", key_path.display()))
+                }
+                _ => error.into(),
+            })?;
+            writeln!(key_file, "{}", StoreKey::generate_hex())?;
+            println!("Wrote a new key to {}, set USERS_STORE_KEY_FILE to it to open the store.", key_path.display());
+            StoreKey::from_key_file(&key_path)?
         }
-        Some(Commands::Add { username, password, admin }) => {
-            add_user(username, password, admin.unwrap_or(false));
+        None => StoreKey::from_env()?.ok_or_else(|| {
+            return AuthError::InvalidKey("set USERS_STORE_KEY, USERS_STORE_KEY_FILE or USERS_STORE_PASSPHRASE, or pass --generate-key".to_string());
+        })?,
+    };
+
+    return store.convert(Some(key));
+}
+
+fn execute(store_path: &Path, roles_path: &Path, command: Commands) -> Result<(), AuthError> {
+    if is_sqlite(store_path) && matches!(command, Commands::Encrypt { .. } | Commands::Decrypt) {
+        return Err(AuthError::InvalidKey("only JSON user stores can be encrypted".to_string()));
+    }
+
+    match command {
+        Commands::Encrypt { generate_key } => return encrypt_store(store_path, generate_key),
+        Commands::Decrypt => return JsonFileStore::new(store_path).with_key_from_env()?.convert(None),
+        _ => {}
+    }
+
+    let store = open_store(store_path)?;
+    let store = store.as_ref();
+
+    return match command {
+        Commands::List(options) => {
+            list_users(store, options)
+        }
+        Commands::Show { username, output } => {
+            show_user(store, username, output)
+        }
+        Commands::Add { username, password, admin, password_stdin, display_name, email } => {
+            let password = read_password(password, password_stdin)?;
+            add_user(store, username, password, admin.unwrap_or(false), display_name, email)
+        }
+        Commands::Edit { username, display_name, email } => {
+            authentication::update_profile(store, &username, display_name.as_deref(), email.as_deref())
+        }
+        Commands::Rename { username, new_username } => {
+            authentication::rename_user(store, &username, &new_username)
+        }
+        Commands::Enable { username } => {
+            authentication::set_disabled(store, &username, false)
+        }
+        Commands::Disable { username } => {
+            authentication::set_disabled(store, &username, true)
+        }
+        Commands::Delete { username } => {
+            delete_user(store, username)
+        }
+        Commands::ChangePassword { username, new_password, password_stdin } => {
+            let new_password = read_password(new_password, password_stdin)?;
+            authentication::change_password(store, &username, &new_password)
+        }
+        Commands::Import { file, format, dry_run, update } => {
+            import_users(store, roles_path, file, format, dry_run, update)
+        }
+        Commands::Export { file, format, force } => {
+            export_users(store, file, format, force)
         }
-        Some(Commands::Delete { username }) => {
-            delete_user(username);
+        Commands::Unlock { username } => {
+            authentication::unlock_user(store, &username)
         }
-        Some(Commands::ChangePassword { username, new_password }) => {
-            change_password(username, new_password.trim().to_string())
+        Commands::Enable2fa { username, issuer } => {
+            enable_two_factor(store, username, issuer)
         }
-        None => {}
+        Commands::Disable2fa { username } => {
+            authentication::disable_totp(store, &username)
+        }
+        Commands::Role(command) => {
+            manage_roles(store, roles_path, command)
+        }
+        Commands::Encrypt { .. } | Commands::Decrypt => unreachable!("handled before opening the store"),
+        Commands::Audit { .. } => unreachable!("the audit log is shown by run"),
+    };
+}
+
+fn run(cli: Args) -> Result<(), AuthError> {
+    let store_path = cli.store.unwrap_or_else(|| JsonFileStore::in_project_root().path().to_path_buf());
+    let roles_path = cli.roles.unwrap_or_else(|| store_path.with_file_name("roles.json"));
+    let audit_log = cli.audit_log.map(AuditLog::new).unwrap_or_else(|| AuditLog::next_to(&store_path));
+    install_audit_log(audit_log.clone());
+
+    let Some(command) = cli.command else {
+        return Ok(());
+    };
+
+    if let Commands::Audit { actor, event, target, outcome, since, tail, follow } = command {
+        let filter = AuditFilter { actor, event, target, outcome, since };
+        return show_audit(&audit_log, filter, tail, follow);
+    }
+
+    let (event, target, context) = describe(&command);
+    let result = execute(&store_path, &roles_path, command);
+
+    let actor = current_actor();
+    let record = match (&result, context) {
+        (Ok(()), Some(context)) => AuditEvent::success(&actor, event, target.as_deref()).with_detail(&context),
+        (Ok(()), None) => AuditEvent::success(&actor, event, target.as_deref()),
+        (Err(error), Some(context)) => AuditEvent::failure(&actor, event, target.as_deref(), &format!("{context}: {error}")),
+        (Err(error), None) => AuditEvent::failure(&actor, event, target.as_deref(), &error.to_string()),
+    };
+    audit(record)?;
+
+    return result;
+}
+
+fn main() {
+    let cli = Args::parse();
+
+    if let Err(error) = run(cli) {
+        eprintln!("Error: {error}");
+        std::process::exit(error.exit_code());
     }
 }
@@ -0,0 +1,404 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use authentication::{
+    normalize_username, set_password_hash, unix_now, verify_password, AuthError, LoginRole, RoleRegistry, User, UserStore,
+};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DataFormat {
+    Json,
+    Csv,
+}
+
+impl DataFormat {
+    /// The explicit format, or the one matching the file extension.
+    pub fn pick(format: Option<DataFormat>, path: Option<&Path>) -> Result<DataFormat, AuthError> {
+        if let Some(format) = format {
+            return Ok(format);
+        }
+
+        return match path.and_then(|path| path.extension()).and_then(|extension| extension.to_str()) {
+            Some("json") => Ok(DataFormat::Json),
+            Some("csv") => Ok(DataFormat::Csv),
+            _ => Err(AuthError::InvalidInput("cannot tell the format from the file name, pass --format".to_string())),
+        };
+    }
+}
+
+/// One user in an import or export file. Imports give either a plaintext `password`, which
+/// has to satisfy the password policy, or a `password_hash` as written by `export`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserRecord {
+    pub username: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_hash: Option<String>,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+}
+
+/// CSV cannot hold lists, roles are separated by `;` instead.
+#[derive(Debug, Serialize, Deserialize)]
+struct CsvRecord {
+    username: String,
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    password_hash: Option<String>,
+    #[serde(default)]
+    roles: String,
+    #[serde(default)]
+    display_name: Option<String>,
+    #[serde(default)]
+    email: Option<String>,
+}
+
+impl From<CsvRecord> for UserRecord {
+    fn from(record: CsvRecord) -> Self {
+        let non_empty = |value: Option<String>| value.filter(|value| !value.is_empty());
+
+        return UserRecord {
+            username: record.username,
+            password: non_empty(record.password),
+            password_hash: non_empty(record.password_hash),
+            roles: record.roles.split(';').map(str::trim).filter(|role| !role.is_empty()).map(str::to_string).collect(),
+            display_name: non_empty(record.display_name),
+            email: non_empty(record.email),
+        };
+    }
+}
+
+impl From<&UserRecord> for CsvRecord {
+    fn from(record: &UserRecord) -> Self {
+        return CsvRecord {
+            username: record.username.clone(),
+            password: record.password.clone(),
+            password_hash: record.password_hash.clone(),
+            roles: record.roles.join(";"),
+            display_name: record.display_name.clone(),
+            email: record.email.clone(),
+        };
+    }
+}
+
+fn csv_error(error: csv::Error) -> AuthError {
+    return AuthError::InvalidInput(error.to_string());
+}
+
+pub fn read_records(reader: impl Read, format: DataFormat) -> Result<Vec<UserRecord>, AuthError> {
+    return match format {
+        DataFormat::Json => serde_json::from_reader(reader).map_err(|error| AuthError::InvalidInput(error.to_string())),
+        DataFormat::Csv => csv::Reader::from_reader(reader)
+            .deserialize::<CsvRecord>()
+            .map(|record| record.map(UserRecord::from).map_err(csv_error))
+            .collect(),
+    };
+}
+
+pub fn write_records(writer: impl Write, format: DataFormat, records: &[UserRecord]) -> Result<(), AuthError> {
+    match format {
+        DataFormat::Json => {
+            let mut writer = writer;
+            serde_json::to_writer_pretty(&mut writer, records)?;
+            writeln!(writer)?;
+        }
+        DataFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            for record in records {
+                writer.serialize(CsvRecord::from(record)).map_err(csv_error)?;
+            }
+            writer.flush()?;
+        }
+    }
+
+    return Ok(());
+}
+
+/// Every user with their password hash, sorted by name, so `import` can restore them.
+pub fn export_records(store: &dyn UserStore) -> Result<Vec<UserRecord>, AuthError> {
+    let mut records: Vec<UserRecord> = store
+        .load()?
+        .into_values()
+        .map(|user| UserRecord {
+            username: user.username,
+            password: None,
+            password_hash: Some(user.password),
+            roles: user.roles.iter().map(LoginRole::to_string).collect(),
+            display_name: user.display_name,
+            email: user.email,
+        })
+        .collect();
+    records.sort_by(|first, second| first.username.cmp(&second.username));
+
+    return Ok(records);
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum PlannedChange {
+    Add,
+    Update,
+}
+
+/// What `import_records` did, or would do on a dry run, one line per record.
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub lines: Vec<String>,
+    pub added: usize,
+    pub updated: usize,
+    pub errors: usize,
+}
+
+fn build_user(record: &UserRecord, registry: &RoleRegistry) -> Result<User, AuthError> {
+    let roles: Vec<LoginRole> = match record.roles.is_empty() {
+        true => vec![LoginRole::user()],
+        false => record.roles.iter().map(|role| LoginRole::new(role)).collect(),
+    };
+    if let Some(unknown) = roles.iter().find(|role| !registry.contains(role)) {
+        return Err(AuthError::UnknownRole(unknown.to_string()));
+    }
+
+    let mut user = match (&record.password, &record.password_hash) {
+        (Some(password), None) => User::new(&record.username, password, roles[0].clone())?,
+        (None, Some(hash)) => User::from_hash(&record.username, hash, roles[0].clone())?,
+        (Some(_), Some(_)) => return Err(AuthError::InvalidInput("give either password or password_hash, not both".to_string())),
+        (None, None) => return Err(AuthError::InvalidInput("password or password_hash is required".to_string())),
+    };
+    user.roles = roles;
+
+    return user.with_profile(record.display_name.as_deref(), record.email.as_deref());
+}
+
+/// Applies an imported `user` to the `current` one. Updates keep everything but the password,
+/// roles and given profile fields, e.g. two-factor settings, and a new password goes through the
+/// password history like `change_password`. Also returns whether the sessions of the user have
+/// to be revoked, because the password, the roles or the disabled state changed.
+fn update_user(current: User, user: User, password: Option<&str>) -> Result<(User, bool), AuthError> {
+    let same_password = match password {
+        Some(password) => verify_password(password, &current.password)?,
+        None => user.password == current.password,
+    };
+    let mut updated = User {
+        roles: user.roles,
+        display_name: user.display_name.or_else(|| current.display_name.clone()),
+        email: user.email.or_else(|| current.email.clone()),
+        ..current.clone()
+    };
+    if !same_password {
+        set_password_hash(&mut updated, user.password, password)?;
+    }
+
+    let revoke = !same_password || updated.roles != current.roles || updated.disabled != current.disabled;
+    if revoke || updated.display_name != current.display_name || updated.email != current.email {
+        updated.updated_at = Some(unix_now());
+    }
+
+    return Ok((updated, revoke));
+}
+
+/// Validates every record first and only writes when all of them are valid, in one update of
+/// the store. Existing users are an error unless `update` is set, and the sessions of updated
+/// users whose password or access changed are revoked.
+pub fn import_records(
+    store: &dyn UserStore,
+    registry: &RoleRegistry,
+    records: &[UserRecord],
+    update: bool,
+    dry_run: bool,
+) -> Result<ImportReport, AuthError> {
+    let existing = store.load()?;
+    let mut report = ImportReport::default();
+    let mut seen = HashMap::new();
+    let mut users = Vec::new();
+
+    for (index, record) in records.iter().enumerate() {
+        let number = index + 1;
+        let key = normalize_username(&record.username);
+        let planned = if key.is_empty() {
+            Err(AuthError::InvalidInput("the username is empty".to_string()))
+        } else if let Some(first) = seen.get(&key) {
+            Err(AuthError::InvalidInput(format!("the same user as record {first}")))
+        } else {
+            build_user(record, registry).and_then(|user| {
+                return match existing.get(&key) {
+                    Some(current) if !update => Err(AuthError::UserExists(current.username.clone())),
+                    Some(current) => {
+                        update_user(current.clone(), user.clone(), record.password.as_deref())?;
+                        Ok((PlannedChange::Update, user))
+                    }
+                    None => Ok((PlannedChange::Add, user)),
+                };
+            })
+        };
+        seen.entry(key).or_insert(number);
+
+        match planned {
+            Ok((change, user)) => {
+                let action = match change {
+                    PlannedChange::Add => {
+                        report.added += 1;
+                        "add"
+                    }
+                    PlannedChange::Update => {
+                        report.updated += 1;
+                        "update"
+                    }
+                };
+                let roles = user.roles.iter().map(LoginRole::to_string).collect::<Vec<_>>().join(",");
+                report.lines.push(format!("{number}: {action} {} ({roles})", user.username));
+                users.push((user, record.password.as_deref()));
+            }
+            Err(error) => {
+                report.lines.push(format!("{number}: error for {}: {error}", record.username));
+                report.errors += 1;
+            }
+        }
+    }
+
+    if dry_run || report.errors > 0 {
+        return Ok(report);
+    }
+
+    let mut users = Some(users);
+    let mut revoke = Vec::new();
+    store.update(&mut |stored| {
+        for (user, password) in users.take().expect("update calls the change once") {
+            let key = normalize_username(&user.username);
+            let user = match stored.remove(&key) {
+                Some(current) if update => {
+                    let (updated, revoke_sessions) = update_user(current, user, password)?;
+                    if revoke_sessions {
+                        revoke.push(updated.username.clone());
+                    }
+                    updated
+                }
+                // Someone else added the user since the records were checked.
+                Some(current) => return Err(AuthError::UserExists(current.username)),
+                None => user,
+            };
+            stored.insert(key, user);
+        }
+        return Ok(true);
+    })?;
+
+    for username in &revoke {
+        authentication::revoke_user_sessions(store, username)?;
+    }
+
+    return Ok(report);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use authentication::MemoryStore;
+
+    const PASSWORD: &str = "Correct-Horse-7";
+
+    fn store() -> MemoryStore {
+        let admin = User::new("admin", PASSWORD, LoginRole::admin()).unwrap();
+        return MemoryStore::with_users(HashMap::from([("admin".to_string(), admin)]));
+    }
+
+    #[test]
+    fn test_read_csv_records() {
+        let csv = "username,password,roles\nalice,Correct-Horse-7,admin; user\nbob,Correct-Horse-8,\n";
+        let records = read_records(csv.as_bytes(), DataFormat::Csv).unwrap();
+
+        assert_eq!(records[0].roles, vec!["admin", "user"]);
+        assert_eq!(records[1], UserRecord {
+            username: "bob".to_string(),
+            password: Some("Correct-Horse-8".to_string()),
+            ..UserRecord::default()
+        });
+        assert!(matches!(read_records("password\nCorrect-Horse-7\n".as_bytes(), DataFormat::Csv), Err(AuthError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn test_import_is_all_or_nothing() {
+        let store = store();
+        let registry = RoleRegistry::builtin();
+        let record = |username: &str, password: &str| UserRecord {
+            username: username.to_string(),
+            password: Some(password.to_string()),
+            ..UserRecord::default()
+        };
+        let records = [record("alice", PASSWORD), record("Admin", PASSWORD), record("carol", "short"), record("ALICE", PASSWORD)];
+
+        let report = import_records(&store, &registry, &records, false, false).unwrap();
+        assert_eq!((report.added, report.errors), (1, 3));
+        assert_eq!(report.lines[1], "2: error for Admin: User admin already exists");
+        assert_eq!(report.lines[3], "4: error for ALICE: Invalid input: the same user as record 1");
+        assert!(store.get("alice").unwrap().is_none());
+
+        let records = [record("alice", PASSWORD), record("admin", "Another-Horse-8")];
+        let report = import_records(&store, &registry, &records, true, true).unwrap();
+        assert_eq!(report.lines, vec!["1: add alice (user)", "2: update admin (user)"]);
+        assert!(store.get("alice").unwrap().is_none());
+
+        import_records(&store, &registry, &records, true, false).unwrap();
+        assert!(store.get("alice").unwrap().is_some());
+        assert_eq!(authentication::authenticate(&store, "admin", "Another-Horse-8").unwrap(), vec![LoginRole::user()]);
+    }
+
+    #[test]
+    fn test_import_update_changes_passwords_like_change_password() {
+        let store = store();
+        let registry = RoleRegistry::builtin();
+        let old_hash = store.get("admin").unwrap().unwrap().password;
+        let (token, _) = authentication::issue_session(&store, "admin", vec![LoginRole::admin()], 60, 0).unwrap();
+        let record = |password: &str| UserRecord {
+            username: "admin".to_string(),
+            password: Some(password.to_string()),
+            roles: vec!["admin".to_string()],
+            ..UserRecord::default()
+        };
+
+        // The same password and roles are not a change, the session survives.
+        let updated_at = store.get("admin").unwrap().unwrap().updated_at;
+        import_records(&store, &registry, &[record(PASSWORD)], true, false).unwrap();
+        assert!(authentication::validate_session(&store, &token, 1).is_ok());
+        assert_eq!(store.get("admin").unwrap().unwrap().updated_at, updated_at);
+
+        import_records(&store, &registry, &[record("Another-Horse-8")], true, false).unwrap();
+        assert_eq!(store.get("admin").unwrap().unwrap().password_history, vec![old_hash.clone()]);
+        assert!(authentication::validate_session(&store, &token, 1).is_err());
+
+        let report = import_records(&store, &registry, &[record(PASSWORD)], true, false).unwrap();
+        assert_eq!(report.errors, 1);
+        let reused = UserRecord { password: None, password_hash: Some(old_hash), ..record("") };
+        assert_eq!(import_records(&store, &registry, &[reused], true, false).unwrap().errors, 1);
+        assert_eq!(authentication::authenticate(&store, "admin", "Another-Horse-8").unwrap(), vec![LoginRole::admin()]);
+
+        // Losing a role ends the sessions too.
+        let (token, _) = authentication::issue_session(&store, "admin", vec![LoginRole::admin()], 60, 0).unwrap();
+        let demoted = UserRecord { roles: vec!["user".to_string()], ..record("Another-Horse-8") };
+        import_records(&store, &registry, &[demoted], true, false).unwrap();
+        assert!(authentication::validate_session(&store, &token, 1).is_err());
+    }
+
+    #[test]
+    fn test_export_import_round_trip() {
+        let source = store();
+        let mut exported = Vec::new();
+        write_records(&mut exported, DataFormat::Csv, &export_records(&source).unwrap()).unwrap();
+
+        let target = MemoryStore::new();
+        let records = read_records(exported.as_slice(), DataFormat::Csv).unwrap();
+        import_records(&target, &RoleRegistry::builtin(), &records, false, false).unwrap();
+
+        assert_eq!(authentication::authenticate(&target, "admin", PASSWORD).unwrap(), vec![LoginRole::admin()]);
+
+        let invalid = UserRecord { username: "eve".to_string(), password_hash: Some("plaintext".to_string()), ..UserRecord::default() };
+        let report = import_records(&target, &RoleRegistry::builtin(), &[invalid], false, false).unwrap();
+        assert_eq!(report.errors, 1);
+    }
+}
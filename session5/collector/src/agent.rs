@@ -0,0 +1,260 @@
+use std::time::{Duration, Instant};
+
+use shared_data::{encode, CollectorCommand, CollectorCommandV3, PayloadEncoding, Sample, ServerCommand};
+
+use crate::backoff::Backoff;
+use crate::queue::FrameQueue;
+use crate::sender::Sender;
+use crate::CollectorError;
+
+/// The server pings every 10 seconds, so a connection silent for this long is broken.
+const SILENCE_LIMIT: Duration = Duration::from_secs(30);
+
+/// Queues every sample before sending it and keeps it queued until the server acknowledges
+/// it, so samples survive both the server being down and connections breaking mid-send.
+pub struct Agent {
+    collector_id: u128,
+    encoding: PayloadEncoding,
+    queue: FrameQueue,
+    sender: Sender,
+    backoff: Backoff,
+    /// The last frame sent over the current connection, frames after it are still to be sent.
+    sent_through: Option<u64>,
+    last_heard: Instant,
+    reported_dropped: u64,
+}
+
+impl Agent {
+    pub fn new(sender: Sender, collector_id: u128, encoding: PayloadEncoding, queue: FrameQueue, backoff: Backoff) -> Self {
+        return Self {
+            collector_id,
+            encoding,
+            sender,
+            queue,
+            backoff,
+            sent_through: None,
+            last_heard: Instant::now(),
+            reported_dropped: 0,
+        };
+    }
+
+    pub fn submit(&mut self, sample: Sample) -> Result<(), CollectorError> {
+        let seq = self.queue.next_sequence();
+        let command = CollectorCommand::V3(CollectorCommandV3::SubmitData { seq, sample });
+        self.queue.push(&encode(&command, self.encoding))?;
+        if self.queue.dropped() > self.reported_dropped {
+            eprintln!("The queue is full, {} of the oldest frames were dropped so far", self.queue.dropped());
+            self.reported_dropped = self.queue.dropped();
+        }
+
+        return self.flush();
+    }
+
+    /// Sends the queued frames not yet sent over the current connection. After a reconnect
+    /// that is every queued frame, since the server may not have seen the unacknowledged ones.
+    pub fn flush(&mut self) -> Result<(), CollectorError> {
+        if !self.backoff.is_ready(Instant::now()) {
+            return Ok(());
+        }
+        if !self.sender.is_connected() {
+            self.sent_through = None;
+            self.last_heard = Instant::now();
+        }
+
+        let unsent: Vec<u64> = self.queue.sequences().filter(|seq| self.sent_through.is_none_or(|sent| *seq > sent)).collect();
+        for seq in unsent {
+            let sent = self.queue.read(seq).and_then(|frame| self.sender.send(&frame));
+            if let Err(error) = sent {
+                self.fail(&error);
+                return Ok(());
+            }
+            self.sent_through = Some(seq);
+        }
+
+        if self.backoff.failures() > 0 && self.sender.is_connected() {
+            eprintln!("Reconnected to {}, {} frames waiting for acknowledgement", self.sender.address(), self.queue.len());
+            self.backoff.succeed();
+        }
+
+        return Ok(());
+    }
+
+    fn fail(&mut self, error: &CollectorError) {
+        self.sender.disconnect();
+        let delay = self.backoff.fail(Instant::now());
+        eprintln!(
+            "Cannot submit to {}: {error}, {} frames queued, retrying in {:.1}s",
+            self.sender.address(),
+            self.queue.len(),
+            delay.as_secs_f32()
+        );
+    }
+
+    /// Asks the server for a task, the answer arrives through `poll`.
+    pub fn request_work(&mut self) {
+        if !self.sender.is_connected() {
+            return;
+        }
+
+        let command = CollectorCommand::V3(CollectorCommandV3::RequestWork { collector_id: self.collector_id });
+        if let Err(error) = self.sender.send(&encode(&command, self.encoding)) {
+            self.fail(&error);
+        }
+    }
+
+    /// Waits up to `timeout` for the server. Acknowledgements and pings are handled here,
+    /// tasks and interval changes are returned for the caller to act on.
+    pub fn poll(&mut self, timeout: Duration) -> Result<Option<ServerCommand>, CollectorError> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+
+            let was_connected = self.sender.is_connected();
+            let Some(command) = self.sender.receive(remaining) else {
+                if was_connected && !self.sender.is_connected() {
+                    eprintln!("{} closed the connection", self.sender.address());
+                } else if was_connected && self.last_heard.elapsed() > SILENCE_LIMIT {
+                    eprintln!("Nothing heard from {} in {}s, reconnecting", self.sender.address(), SILENCE_LIMIT.as_secs());
+                    self.sender.disconnect();
+                }
+                continue;
+            };
+            self.last_heard = Instant::now();
+
+            match command {
+                ServerCommand::Ack { seq } => self.queue.remove(seq)?,
+                ServerCommand::Ping { nonce } => {
+                    let pong = CollectorCommand::V3(CollectorCommandV3::Pong { nonce });
+                    if let Err(error) = self.sender.send(&encode(&pong, self.encoding)) {
+                        self.fail(&error);
+                    }
+                }
+                ServerCommand::NoWork | ServerCommand::Pong { .. } => {}
+                ServerCommand::Task { .. } | ServerCommand::SetInterval { .. } => return Ok(Some(command)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread::JoinHandle;
+
+    use shared_data::{Credentials, FrameReader, Key, Task};
+
+    use super::*;
+
+    /// Records the collector ids of the samples it receives and acknowledges them until stopped,
+    /// signing its replies like the server does.
+    struct StandInServer {
+        stop: Arc<AtomicBool>,
+        thread: JoinHandle<()>,
+    }
+
+    impl StandInServer {
+        fn start(address: SocketAddr, credentials: Credentials, received: Arc<Mutex<Vec<u128>>>) -> Self {
+            let listener = TcpListener::bind(address).unwrap();
+            listener.set_nonblocking(true).unwrap();
+            let stop = Arc::new(AtomicBool::new(false));
+
+            let stopped = stop.clone();
+            let thread = std::thread::spawn(move || {
+                while !stopped.load(Ordering::SeqCst) {
+                    let Ok((stream, _)) = listener.accept() else {
+                        std::thread::sleep(Duration::from_millis(5));
+                        continue;
+                    };
+                    stream.set_nonblocking(false).unwrap();
+                    stream.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+
+                    let mut writer = stream.try_clone().unwrap();
+                    let mut reader = FrameReader::new(stream);
+                    while !stopped.load(Ordering::SeqCst) {
+                        let reply = match reader.read_frame() {
+                            Ok(Some(Ok((_, command)))) => match command.into_latest() {
+                                CollectorCommandV3::SubmitData { seq, sample } => {
+                                    received.lock().unwrap().push(sample.collector_id);
+                                    ServerCommand::Ack { seq }
+                                }
+                                CollectorCommandV3::RequestWork { .. } => ServerCommand::Task { task_id: 1, task: Task::SampleNow },
+                                CollectorCommandV3::Ping { nonce } => ServerCommand::Pong { nonce },
+                                CollectorCommandV3::Pong { .. } => continue,
+                            },
+                            Ok(None) | Ok(Some(Err(_))) => break,
+                            // Read timeouts, to look at the stop flag.
+                            Err(_) => continue,
+                        };
+                        if writer.write_all(&credentials.sign(&encode(&reply, PayloadEncoding::Json))).is_err() {
+                            break;
+                        }
+                    }
+                }
+            });
+
+            return Self { stop, thread };
+        }
+
+        fn stop(self) {
+            self.stop.store(true, Ordering::SeqCst);
+            self.thread.join().unwrap();
+        }
+    }
+
+    fn wait_for(received: &Mutex<Vec<u128>>, count: usize, agent: &mut Agent) {
+        for _ in 0..200 {
+            agent.flush().unwrap();
+            agent.poll(Duration::from_millis(10)).unwrap();
+            if received.lock().unwrap().len() >= count && agent.queue.is_empty() {
+                return;
+            }
+        }
+        panic!("Expected {count} acknowledged samples, received {:?}", received.lock().unwrap());
+    }
+
+    fn sample(collector_id: u128) -> Sample {
+        return Sample { collector_id, total_memory: 100, used_memory: 50, total_swap: 0, used_swap: 0, average_cpu_usage: 0.5, cpu_usage: vec![] };
+    }
+
+    #[test]
+    fn test_agent_keeps_samples_until_acknowledged() {
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let directory = tempfile::tempdir().unwrap();
+        let queue = FrameQueue::open(directory.path(), 4).unwrap();
+        let backoff = Backoff::new(Duration::from_millis(10), Duration::from_millis(40));
+        let credentials = Credentials { collector_id: 1, key: Key::generate() };
+        let sender = Sender::new(&address.to_string(), credentials.clone(), None);
+        let mut agent = Agent::new(sender, 1, PayloadEncoding::Postcard, queue, backoff);
+
+        let server = StandInServer::start(address, credentials.clone(), received.clone());
+        agent.submit(sample(1)).unwrap();
+        agent.submit(sample(2)).unwrap();
+        wait_for(&received, 2, &mut agent);
+
+        agent.request_work();
+        let task = agent.poll(Duration::from_secs(5)).unwrap();
+        assert_eq!(task, Some(ServerCommand::Task { task_id: 1, task: Task::SampleNow }));
+        server.stop();
+
+        // Six samples while the server is down, the two oldest do not fit.
+        for collector_id in 3..=8 {
+            agent.submit(sample(collector_id)).unwrap();
+            agent.poll(Duration::from_millis(1)).unwrap();
+        }
+        assert_eq!(agent.queue.len(), 4);
+
+        let server = StandInServer::start(address, credentials, received.clone());
+        wait_for(&received, 6, &mut agent);
+        server.stop();
+
+        assert_eq!(*received.lock().unwrap(), vec![1, 2, 5, 6, 7, 8]);
+    }
+}
@@ -0,0 +1,67 @@
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+/// Exponential backoff with jitter, so collectors that lost the server at the same time do not
+/// all reconnect at the same time.
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    failures: u32,
+    next_attempt: Option<Instant>,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        return Self { base, max, failures: 0, next_attempt: None };
+    }
+
+    pub fn is_ready(&self, now: Instant) -> bool {
+        return self.next_attempt.is_none_or(|next_attempt| now >= next_attempt);
+    }
+
+    /// Records a failure and returns the delay before the next attempt, between half and all
+    /// of `base * 2^failures`, at most `max`.
+    pub fn fail(&mut self, now: Instant) -> Duration {
+        let delay = self.base.saturating_mul(2u32.saturating_pow(self.failures)).min(self.max);
+        let delay = rand::thread_rng().gen_range(delay / 2..=delay);
+
+        self.failures = self.failures.saturating_add(1);
+        self.next_attempt = Some(now + delay);
+
+        return delay;
+    }
+
+    pub fn succeed(&mut self) {
+        self.failures = 0;
+        self.next_attempt = None;
+    }
+
+    pub fn failures(&self) -> u32 {
+        return self.failures;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_grows_with_jitter() {
+        let now = Instant::now();
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+        assert!(backoff.is_ready(now));
+
+        let delays: Vec<Duration> = (0..6).map(|_| backoff.fail(now)).collect();
+        for (delay, expected) in delays.iter().zip([1, 2, 4, 8, 10, 10]) {
+            let expected = Duration::from_secs(expected);
+            assert!(*delay >= expected / 2 && *delay <= expected, "{delay:?} for {expected:?}");
+        }
+        assert!(!backoff.is_ready(now));
+        assert!(backoff.is_ready(now + Duration::from_secs(10)));
+
+        backoff.succeed();
+        assert!(backoff.is_ready(now));
+        assert_eq!(backoff.failures(), 0);
+    }
+}
@@ -0,0 +1,81 @@
+use std::path::Path;
+
+use shared_data::{write_private, Key};
+use uuid::Uuid;
+
+use crate::CollectorError;
+
+/// The id of this collector, created on the first run. The server tells collectors apart by
+/// this id, so it must survive restarts.
+pub fn load_or_create(path: &Path) -> Result<u128, CollectorError> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => {
+            let id = Uuid::parse_str(contents.trim()).map_err(|error| CollectorError::BadId(path.to_path_buf(), error))?;
+            return Ok(id.as_u128());
+        }
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+        Err(error) => return Err(error.into()),
+    }
+
+    let id = Uuid::new_v4();
+    create_parent(path)?;
+    std::fs::write(path, format!("{id}\n"))?;
+
+    return Ok(id.as_u128());
+}
+
+fn create_parent(path: &Path) -> Result<(), CollectorError> {
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    return Ok(());
+}
+
+/// The key this collector signs its frames with, created on the first run. The server only
+/// accepts collectors whose key it has, so a new key is printed along with the line to add
+/// to the server's key file.
+pub fn load_or_create_key(path: &Path, collector_id: u128) -> Result<Key, CollectorError> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => return contents.parse().map_err(|error| CollectorError::BadKey(path.to_path_buf(), error)),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+        Err(error) => return Err(error.into()),
+    }
+
+    let key = Key::generate();
+    create_parent(path)?;
+    write_private(path, &format!("{key}\n"))?;
+    eprintln!("Created a key in {}, add this line to the collector keys of the server:", path.display());
+    eprintln!("{collector_id:032x} {key}");
+
+    return Ok(key);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collector_id_and_key_are_persisted() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("state").join("collector_id");
+
+        let id = load_or_create(&path).unwrap();
+        assert_eq!(load_or_create(&path).unwrap(), id);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().trim(), Uuid::from_u128(id).to_string());
+
+        std::fs::write(&path, "not an id").unwrap();
+        assert!(matches!(load_or_create(&path), Err(CollectorError::BadId(..))));
+
+        let key_path = directory.path().join("state").join("collector_key");
+        let key = load_or_create_key(&key_path, id).unwrap();
+        assert_eq!(load_or_create_key(&key_path, id).unwrap(), key);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&key_path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        std::fs::write(&key_path, "abcd").unwrap();
+        assert!(matches!(load_or_create_key(&key_path, id), Err(CollectorError::BadKey(..))));
+    }
+}
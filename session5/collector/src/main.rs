@@ -1,3 +1,139 @@
+#![allow(clippy::needless_return)]
+
+mod agent;
+mod backoff;
+mod collector_id;
+mod queue;
+mod sampler;
+mod sender;
+
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use shared_data::{Credentials, PayloadEncoding, ServerCommand, Task, DATA_COLLECTOR_ADDRESS, MAX_INTERVAL_SECONDS};
+use thiserror::Error;
+
+use agent::Agent;
+use backoff::Backoff;
+use queue::FrameQueue;
+use sampler::Sampler;
+use sender::{Sender, Tls};
+
+#[derive(Debug, Error)]
+pub enum CollectorError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("{0} does not hold a collector id: {1}")]
+    BadId(PathBuf, uuid::Error),
+    #[error("{0} does not resolve to any address")]
+    NoAddress(String),
+    #[error("{0} does not hold a key: {1}")]
+    BadKey(PathBuf, String),
+    #[error("Cannot use the certificate in {0}: {1}")]
+    BadCertificate(PathBuf, String),
+    #[error("TLS error: {0}")]
+    Tls(#[from] rustls::Error),
+}
+
+/// Samples memory and CPU usage and submits it to the data collector server until stopped.
+#[derive(Parser)]
+#[command(version, about)]
+struct Args {
+    /// Server to submit samples to.
+    #[arg(long, default_value = DATA_COLLECTOR_ADDRESS)]
+    address: String,
+    /// Seconds between samples.
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..=MAX_INTERVAL_SECONDS))]
+    interval: u64,
+    /// Where the id of this collector is kept, created on the first run.
+    #[arg(long, default_value = "collector_id")]
+    id_file: PathBuf,
+    /// Where the key this collector signs with is kept, created on the first run.
+    #[arg(long, default_value = "collector_key")]
+    key_file: PathBuf,
+    /// Connect over TLS, trusting the server certificates signed by this PEM certificate.
+    #[arg(long)]
+    tls_ca: Option<PathBuf>,
+    /// The name the server certificate is checked against.
+    #[arg(long, default_value = "localhost")]
+    tls_name: String,
+    /// Payload encoding, json or postcard.
+    #[arg(long, default_value = "json")]
+    encoding: PayloadEncoding,
+    /// Where samples wait while the server cannot be reached.
+    #[arg(long, default_value = "collector_queue")]
+    queue_dir: PathBuf,
+    /// Samples kept while the server cannot be reached, the oldest are dropped beyond this.
+    #[arg(long, default_value_t = 3600)]
+    queue_limit: usize,
+}
+
+/// First and longest wait before connecting again after a failure.
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+fn exit_with(error: CollectorError) -> ! {
+    eprintln!("Cannot start the collector: {error}");
+    std::process::exit(1);
+}
+
 fn main() {
-    println!("Hello, world!");
+    let args = Args::parse();
+
+    let collector_id = collector_id::load_or_create(&args.id_file).unwrap_or_else(|error| exit_with(error));
+    let key = collector_id::load_or_create_key(&args.key_file, collector_id).unwrap_or_else(|error| exit_with(error));
+    let tls = args.tls_ca.map(|path| Tls::load(&path, &args.tls_name).unwrap_or_else(|error| exit_with(error)));
+    let queue = FrameQueue::open(&args.queue_dir, args.queue_limit).unwrap_or_else(|error| exit_with(error));
+    let transport = if tls.is_some() { "TLS" } else { "plain TCP" };
+    eprintln!("Collector {:032x} submitting to {} over {transport} every {}s", collector_id, args.address, args.interval);
+
+    let mut interval = Duration::from_secs(args.interval);
+    let mut sampler = Sampler::new(collector_id);
+    if !queue.is_empty() {
+        eprintln!("{} samples from a previous run are queued", queue.len());
+    }
+    let backoff = Backoff::new(MIN_RETRY_DELAY, MAX_RETRY_DELAY);
+    let sender = Sender::new(&args.address, Credentials { collector_id, key }, tls);
+    let mut agent = Agent::new(sender, collector_id, args.encoding, queue, backoff);
+    let mut next_sample = after(Instant::now(), interval);
+
+    loop {
+        // Between samples the agent listens to the server.
+        let command = agent.poll(next_sample.saturating_duration_since(Instant::now())).unwrap_or_else(|error| {
+            eprintln!("Cannot remove an acknowledged sample: {error}");
+            None
+        });
+
+        match command {
+            Some(ServerCommand::SetInterval { seconds }) => {
+                interval = Duration::from_secs(seconds.clamp(1, MAX_INTERVAL_SECONDS));
+                next_sample = after(Instant::now(), interval);
+                eprintln!("The server set the interval to {}s", interval.as_secs());
+            }
+            Some(ServerCommand::Task { task_id, task: Task::SampleNow }) => {
+                eprintln!("Sampling now for task {task_id}");
+                submit(&mut agent, &mut sampler);
+            }
+            Some(_) => {}
+            None if Instant::now() >= next_sample => {
+                // Skip the samples missed while a send was stuck instead of catching up in a burst.
+                next_sample = after(next_sample, interval).max(after(Instant::now(), interval / 2));
+                submit(&mut agent, &mut sampler);
+                agent.request_work();
+            }
+            None => {}
+        }
+    }
+}
+
+/// `interval` past `instant`, or `instant` itself where the clock cannot go that far.
+fn after(instant: Instant, interval: Duration) -> Instant {
+    return instant.checked_add(interval).unwrap_or(instant);
+}
+
+fn submit(agent: &mut Agent, sampler: &mut Sampler) {
+    if let Err(error) = agent.submit(sampler.sample()) {
+        eprintln!("Cannot queue a sample: {error}");
+    }
 }
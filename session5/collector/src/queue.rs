@@ -0,0 +1,148 @@
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+
+use crate::CollectorError;
+
+const EXTENSION: &str = "frame";
+
+/// Frames waiting to be acknowledged by the server, one file per frame so the queue survives
+/// restarts and a crash loses at most the frame being written. Files are named by sequence
+/// number, which keeps them in the order they were queued.
+pub struct FrameQueue {
+    directory: PathBuf,
+    limit: usize,
+    sequences: VecDeque<u64>,
+    next_sequence: u64,
+    dropped: u64,
+}
+
+impl FrameQueue {
+    /// Opens the queue in `directory`, picking up the frames left by a previous run.
+    pub fn open(directory: &Path, limit: usize) -> Result<Self, CollectorError> {
+        std::fs::create_dir_all(directory)?;
+
+        let mut sequences = Vec::new();
+        for entry in std::fs::read_dir(directory)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == EXTENSION) {
+                if let Some(sequence) = path.file_stem().and_then(|stem| stem.to_str()?.parse().ok()) {
+                    sequences.push(sequence);
+                }
+            }
+        }
+        sequences.sort_unstable();
+
+        let mut queue = Self {
+            directory: directory.to_path_buf(),
+            limit: limit.max(1),
+            next_sequence: sequences.last().map_or(0, |last| last + 1),
+            sequences: sequences.into(),
+            dropped: 0,
+        };
+        while queue.sequences.len() > queue.limit {
+            queue.drop_oldest()?;
+        }
+
+        return Ok(queue);
+    }
+
+    fn path_of(&self, sequence: u64) -> PathBuf {
+        return self.directory.join(format!("{sequence:020}.{EXTENSION}"));
+    }
+
+    fn drop_oldest(&mut self) -> Result<(), CollectorError> {
+        if let Some(sequence) = self.sequences.front().copied() {
+            self.remove(sequence)?;
+            self.dropped += 1;
+        }
+
+        return Ok(());
+    }
+
+    /// Queues a frame, dropping the oldest one when the queue is full.
+    pub fn push(&mut self, frame: &[u8]) -> Result<u64, CollectorError> {
+        while self.sequences.len() >= self.limit {
+            self.drop_oldest()?;
+        }
+
+        let sequence = self.next_sequence;
+        let path = self.path_of(sequence);
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, frame)?;
+        std::fs::rename(&temporary, &path)?;
+
+        self.sequences.push_back(sequence);
+        self.next_sequence += 1;
+
+        return Ok(sequence);
+    }
+
+    /// The sequence number the next frame gets.
+    pub fn next_sequence(&self) -> u64 {
+        return self.next_sequence;
+    }
+
+    /// Sequence numbers of the queued frames, oldest first.
+    pub fn sequences(&self) -> impl Iterator<Item = u64> + '_ {
+        return self.sequences.iter().copied();
+    }
+
+    pub fn read(&self, sequence: u64) -> Result<Vec<u8>, CollectorError> {
+        return Ok(std::fs::read(self.path_of(sequence))?);
+    }
+
+    pub fn remove(&mut self, sequence: u64) -> Result<(), CollectorError> {
+        match std::fs::remove_file(self.path_of(sequence)) {
+            Ok(()) => {}
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+            Err(error) => return Err(error.into()),
+        }
+        self.sequences.retain(|queued| *queued != sequence);
+
+        return Ok(());
+    }
+
+    pub fn len(&self) -> usize {
+        return self.sequences.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.sequences.is_empty();
+    }
+
+    /// Frames dropped since the queue was opened because it was full.
+    pub fn dropped(&self) -> u64 {
+        return self.dropped;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_queue_is_bounded_and_persistent() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut queue = FrameQueue::open(directory.path(), 3).unwrap();
+
+        for frame in [b"one", b"two", b"six"] {
+            queue.push(frame).unwrap();
+        }
+        assert_eq!(queue.read(0).unwrap(), b"one");
+        queue.remove(1).unwrap();
+
+        queue.push(b"ten").unwrap();
+        queue.push(b"end").unwrap();
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(queue.sequences().collect::<Vec<_>>(), [2, 3, 4]);
+        assert_eq!(queue.next_sequence(), 5);
+
+        // A smaller limit after a restart drops the oldest frames.
+        let queue = FrameQueue::open(directory.path(), 2).unwrap();
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(queue.read(3).unwrap(), b"ten");
+        assert_eq!(queue.next_sequence(), 5);
+    }
+}
@@ -0,0 +1,40 @@
+use shared_data::Sample;
+use sysinfo::System;
+
+/// Reads memory and CPU usage. CPU usage is measured between two refreshes, so every sample
+/// covers the time since the previous one.
+pub struct Sampler {
+    collector_id: u128,
+    system: System,
+}
+
+impl Sampler {
+    pub fn new(collector_id: u128) -> Self {
+        let mut system = System::new();
+        system.refresh_cpu_usage();
+
+        return Self { collector_id, system };
+    }
+
+    /// Samples should be at least `sysinfo::MINIMUM_CPU_UPDATE_INTERVAL` apart.
+    pub fn sample(&mut self) -> Sample {
+        self.system.refresh_memory();
+        self.system.refresh_cpu_usage();
+
+        let cpu_usage: Vec<f32> = self.system.cpus().iter().map(|cpu| cpu.cpu_usage()).collect();
+        let average_cpu_usage = match cpu_usage.len() {
+            0 => 0.0,
+            count => cpu_usage.iter().sum::<f32>() / count as f32,
+        };
+
+        return Sample {
+            collector_id: self.collector_id,
+            total_memory: self.system.total_memory(),
+            used_memory: self.system.used_memory(),
+            total_swap: self.system.total_swap(),
+            used_swap: self.system.used_swap(),
+            average_cpu_usage,
+            cpu_usage,
+        };
+    }
+}
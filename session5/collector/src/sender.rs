@@ -0,0 +1,238 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use shared_data::{CodecError, Credentials, FrameReader, ServerCodec, ServerCommand};
+
+use crate::CollectorError;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the connection thread waits for the server before looking for frames to send.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Trusts only the server certificates signed by one certificate, such as the self-signed
+/// certificate the server generates.
+pub struct Tls {
+    config: Arc<ClientConfig>,
+    server_name: ServerName<'static>,
+}
+
+impl Tls {
+    pub fn load(certificate_path: &Path, server_name: &str) -> Result<Self, CollectorError> {
+        let bad_certificate = |error: String| CollectorError::BadCertificate(certificate_path.to_path_buf(), error);
+
+        let mut roots = RootCertStore::empty();
+        for certificate in CertificateDer::pem_file_iter(certificate_path).map_err(|error| bad_certificate(error.to_string()))? {
+            roots.add(certificate.map_err(|error| bad_certificate(error.to_string()))?)?;
+        }
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let server_name = ServerName::try_from(server_name.to_string())
+            .map_err(|error| bad_certificate(format!("{server_name} cannot be checked against it: {error}")))?;
+
+        return Ok(Self { config: Arc::new(config), server_name });
+    }
+}
+
+trait Stream: Read + Write + Send {}
+
+impl<S: Read + Write + Send> Stream for S {}
+
+type Outgoing = (Vec<u8>, mpsc::Sender<io::Result<()>>);
+
+/// One connection to the server, owned by a thread that writes the frames it is handed and
+/// reads what the server sends. `None` from the thread means the connection ended.
+struct Connection {
+    outgoing: mpsc::Sender<Outgoing>,
+    replies: Receiver<Option<ServerCommand>>,
+}
+
+/// Keeps one connection to the server open, connecting again on the next send once it breaks.
+/// Frames are signed as they are sent, and only commands signed for this collector are
+/// received.
+pub struct Sender {
+    address: String,
+    credentials: Credentials,
+    tls: Option<Tls>,
+    connection: Option<Connection>,
+}
+
+impl Sender {
+    pub fn new(address: &str, credentials: Credentials, tls: Option<Tls>) -> Self {
+        return Self { address: address.to_string(), credentials, tls, connection: None };
+    }
+
+    pub fn address(&self) -> &str {
+        return &self.address;
+    }
+
+    fn connect_tcp(&self) -> Result<TcpStream, CollectorError> {
+        let mut last_error = None;
+
+        for address in self.address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, TIMEOUT) {
+                Ok(stream) => return Ok(stream),
+                Err(error) => last_error = Some(error),
+            }
+        }
+
+        return Err(match last_error {
+            Some(error) => error.into(),
+            None => CollectorError::NoAddress(self.address.clone()),
+        });
+    }
+
+    fn connect(&self) -> Result<Connection, CollectorError> {
+        let mut stream = self.connect_tcp()?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        let Some(tls) = &self.tls else {
+            stream.set_read_timeout(Some(POLL_INTERVAL))?;
+            return Ok(start(stream, self.credentials.clone()));
+        };
+
+        // The handshake is finished here, where it can take longer than one poll interval.
+        let mut connection = ClientConnection::new(tls.config.clone(), tls.server_name.clone())?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        while connection.is_handshaking() {
+            connection.complete_io(&mut stream)?;
+        }
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+
+        return Ok(start(StreamOwned::new(connection, stream), self.credentials.clone()));
+    }
+
+    pub fn is_connected(&self) -> bool {
+        return self.connection.is_some();
+    }
+
+    pub fn disconnect(&mut self) {
+        self.connection = None;
+    }
+
+    pub fn send(&mut self, frame: &[u8]) -> Result<(), CollectorError> {
+        let connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => self.connect()?,
+        };
+
+        let (done, written) = mpsc::channel();
+        let closed = || io::Error::new(io::ErrorKind::BrokenPipe, "The connection is closed");
+        connection.outgoing.send((self.credentials.sign(frame), done)).map_err(|_| closed())?;
+        written.recv().map_err(|_| closed())??;
+        self.connection = Some(connection);
+
+        return Ok(());
+    }
+
+    /// Waits up to `timeout` for a command from the server. Without a connection this just
+    /// waits, and a connection the server closed is dropped.
+    pub fn receive(&mut self, timeout: Duration) -> Option<ServerCommand> {
+        let Some(connection) = &self.connection else {
+            std::thread::sleep(timeout);
+            return None;
+        };
+
+        return match connection.replies.recv_timeout(timeout) {
+            Ok(Some(command)) => Some(command),
+            Ok(None) | Err(RecvTimeoutError::Disconnected) => {
+                self.connection = None;
+                None
+            }
+            Err(RecvTimeoutError::Timeout) => None,
+        };
+    }
+}
+
+/// Runs the connection until either side ends it. The stream times out reads every poll
+/// interval, so frames to send are picked up in between.
+fn start<S: Stream + 'static>(stream: S, credentials: Credentials) -> Connection {
+    let (outgoing, to_send) = mpsc::channel::<Outgoing>();
+    let (replier, replies) = mpsc::channel();
+    let mut reader = FrameReader::with_codec(stream, ServerCodec::new().with_credentials(credentials));
+
+    std::thread::spawn(move || loop {
+        loop {
+            match to_send.try_recv() {
+                Ok((frame, done)) => {
+                    let stream = reader.get_mut();
+                    let written = stream.write_all(&frame).and_then(|()| stream.flush());
+                    let failed = written.is_err();
+                    let _ = done.send(written);
+                    if failed {
+                        let _ = replier.send(None);
+                        return;
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                // The `Sender` dropped the connection.
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+
+        match reader.read_frame() {
+            Ok(Some(Ok((_, command)))) => {
+                if replier.send(Some(command)).is_err() {
+                    return;
+                }
+            }
+            Ok(Some(Err(error))) => eprintln!("Ignoring a frame from the server: {error}"),
+            Err(CodecError::Io(error)) if matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+            Ok(None) | Err(_) => {
+                let _ = replier.send(None);
+                return;
+            }
+        }
+    });
+
+    return Connection { outgoing, replies };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use shared_data::{encode, encode_v1, CollectorCommand, CollectorCommandV1, Key, PayloadEncoding};
+
+    use super::*;
+
+    #[test]
+    fn test_sender_signs_reads_replies_and_reconnects() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let credentials = Credentials { collector_id: 1, key: Key::generate() };
+        let mut sender = Sender::new(&listener.local_addr().unwrap().to_string(), credentials.clone(), None);
+        let command = |collector_id| CollectorCommandV1::SubmitData {
+            collector_id,
+            total_memory: 100,
+            used_memory: 50,
+            average_cpu_usage: 0.5,
+        };
+
+        sender.send(&encode_v1(&command(1))).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        let mut reader = FrameReader::new(server.try_clone().unwrap());
+        assert_eq!(reader.read_frame().unwrap().unwrap().unwrap().1, CollectorCommand::V1(command(1)));
+
+        // Only replies signed for this collector get through.
+        server.write_all(&encode(&ServerCommand::Ack { seq: 1 }, PayloadEncoding::Json)).unwrap();
+        server.write_all(&credentials.sign(&encode(&ServerCommand::Ack { seq: 2 }, PayloadEncoding::Json))).unwrap();
+        assert_eq!(sender.receive(TIMEOUT), Some(ServerCommand::Ack { seq: 2 }));
+
+        // The server hanging up is noticed, the next frame goes over a new connection.
+        drop((reader, server));
+        assert_eq!(sender.receive(TIMEOUT), None);
+        assert!(!sender.is_connected());
+        sender.send(&encode_v1(&command(2))).unwrap();
+        let mut reader = FrameReader::new(listener.accept().unwrap().0);
+        assert_eq!(reader.read_frame().unwrap().unwrap().unwrap().1, CollectorCommand::V1(command(2)));
+    }
+}
@@ -0,0 +1,16 @@
+-- One row per SubmitData command, V1 samples have no swap or per-CPU usage.
+CREATE TABLE IF NOT EXISTS samples
+(
+    id                INTEGER PRIMARY KEY NOT NULL,
+    collector_id      TEXT                NOT NULL,
+    timestamp         INTEGER             NOT NULL,
+    received_at       INTEGER             NOT NULL,
+    total_memory      INTEGER             NOT NULL,
+    used_memory       INTEGER             NOT NULL,
+    total_swap        INTEGER             NOT NULL,
+    used_swap         INTEGER             NOT NULL,
+    average_cpu_usage REAL                NOT NULL,
+    cpu_usage         TEXT                NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS samples_by_collector ON samples (collector_id, timestamp);
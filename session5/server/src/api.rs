@@ -0,0 +1,293 @@
+use axum::extract::{Path, Query};
+use axum::http::StatusCode;
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Extension, Json, Router};
+use serde::{Deserialize, Serialize};
+use shared_data::{ServerCommand, Task, MAX_INTERVAL_SECONDS};
+use thiserror::Error;
+
+use crate::hub::{Hub, MAX_QUEUED_TASKS};
+use crate::storage::{format_collector_id, unix_now, CollectorSummary, SampleBucket, Storage};
+
+/// Without `from`, samples of the last hour are returned.
+const DEFAULT_RANGE: i64 = 60 * 60;
+/// Without `step`, the range is split into about this many buckets.
+const DEFAULT_BUCKETS: i64 = 300;
+const MAX_BUCKETS: i64 = 10_000;
+/// The last second of the year 9999, later timestamps are taken for mistakes.
+const MAX_TIMESTAMP: i64 = 253_402_300_799;
+
+#[derive(Debug, Error)]
+pub enum ApiError {
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    TooManyRequests(String),
+    #[error("Internal error: {0}")]
+    Internal(#[from] anyhow::Error),
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Internal(error) => {
+                tracing::error!(%error, "Request failed");
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+
+        return (status, Json(ErrorBody { error: self.to_string() })).into_response();
+    }
+}
+
+/// The dashboard and the JSON API. Nothing here is authenticated, so it must only be served on
+/// localhost.
+pub fn router(storage: Storage, hub: Hub) -> Router {
+    return Router::new()
+        .route("/", get(dashboard))
+        .route("/api/collectors", get(collectors))
+        .route("/api/collectors/:id/samples", get(samples))
+        .route("/api/collectors/:id/interval", post(set_interval))
+        .route("/api/collectors/:id/tasks", post(queue_task))
+        .layer(Extension(storage))
+        .layer(Extension(hub));
+}
+
+fn parse_collector_id(id: &str) -> Result<u128, ApiError> {
+    return u128::from_str_radix(id, 16).map_err(|_| ApiError::BadRequest(format!("{id} is not a collector id, expected 32 hex digits")));
+}
+
+async fn dashboard() -> Html<&'static str> {
+    const HTML: &str = include_str!("dashboard.html");
+    return Html(HTML);
+}
+
+async fn collectors(Extension(storage): Extension<Storage>) -> Result<Json<Vec<CollectorSummary>>, ApiError> {
+    return Ok(Json(storage.collectors().await?));
+}
+
+/// Unix timestamps in seconds.
+#[derive(Debug, Deserialize)]
+struct SamplesQuery {
+    from: Option<i64>,
+    to: Option<i64>,
+    step: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct Samples {
+    collector_id: String,
+    from: i64,
+    to: i64,
+    step: i64,
+    buckets: Vec<SampleBucket>,
+}
+
+fn check_timestamp(name: &str, timestamp: i64) -> Result<i64, ApiError> {
+    if !(0..=MAX_TIMESTAMP).contains(&timestamp) {
+        return Err(ApiError::BadRequest(format!("{name} must be a Unix timestamp between 0 and {MAX_TIMESTAMP}")));
+    }
+
+    return Ok(timestamp);
+}
+
+async fn samples(
+    Extension(storage): Extension<Storage>,
+    Path(id): Path<String>,
+    Query(query): Query<SamplesQuery>,
+) -> Result<Json<Samples>, ApiError> {
+    let collector_id = parse_collector_id(&id)?;
+
+    let to = check_timestamp("to", query.to.unwrap_or_else(|| unix_now() + 1))?;
+    let from = match query.from {
+        Some(from) => check_timestamp("from", from)?,
+        None => to.checked_sub(DEFAULT_RANGE).map_or(0, |from| from.max(0)),
+    };
+    if from >= to {
+        return Err(ApiError::BadRequest("from must be before to".to_string()));
+    }
+    let too_many = || ApiError::BadRequest(format!("More than {MAX_BUCKETS} buckets, use a larger step"));
+    let range = to.checked_sub(from).ok_or_else(too_many)?;
+    let step = query.step.unwrap_or((range / DEFAULT_BUCKETS).max(1));
+    if step < 1 {
+        return Err(ApiError::BadRequest("step must be at least 1 second".to_string()));
+    }
+    if range.checked_div(step).ok_or_else(too_many)? > MAX_BUCKETS {
+        return Err(too_many());
+    }
+
+    if storage.count_samples(collector_id).await? == 0 {
+        return Err(ApiError::NotFound(format!("No samples from collector {id}")));
+    }
+
+    let buckets = storage.sample_buckets(collector_id, from, to, step).await?;
+
+    return Ok(Json(Samples { collector_id: format_collector_id(collector_id), from, to, step, buckets }));
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Interval {
+    seconds: u64,
+}
+
+/// Pushed to the collector right away, so it has to be connected.
+async fn set_interval(
+    Extension(hub): Extension<Hub>,
+    Path(id): Path<String>,
+    Json(interval): Json<Interval>,
+) -> Result<(StatusCode, Json<Interval>), ApiError> {
+    let collector_id = parse_collector_id(&id)?;
+    if !(1..=MAX_INTERVAL_SECONDS).contains(&interval.seconds) {
+        return Err(ApiError::BadRequest(format!("The interval must be between 1 and {MAX_INTERVAL_SECONDS} seconds")));
+    }
+
+    if !hub.push(collector_id, ServerCommand::SetInterval { seconds: interval.seconds }) {
+        return Err(ApiError::NotFound(format!("Collector {id} is not connected")));
+    }
+
+    return Ok((StatusCode::ACCEPTED, Json(interval)));
+}
+
+#[derive(Deserialize)]
+struct NewTask {
+    task: Task,
+}
+
+#[derive(Serialize)]
+struct QueuedTask {
+    task_id: u64,
+}
+
+/// Handed out the next time the collector asks for work. Only collectors that submitted
+/// samples, so with a known key, get tasks.
+async fn queue_task(
+    Extension(storage): Extension<Storage>,
+    Extension(hub): Extension<Hub>,
+    Path(id): Path<String>,
+    Json(new_task): Json<NewTask>,
+) -> Result<(StatusCode, Json<QueuedTask>), ApiError> {
+    let collector_id = parse_collector_id(&id)?;
+    if storage.count_samples(collector_id).await? == 0 {
+        return Err(ApiError::NotFound(format!("No samples from collector {id}")));
+    }
+
+    let Some(task_id) = hub.queue_task(collector_id, new_task.task) else {
+        return Err(ApiError::TooManyRequests(format!("{MAX_QUEUED_TASKS} tasks are already waiting for collector {id}")));
+    };
+
+    return Ok((StatusCode::ACCEPTED, Json(QueuedTask { task_id })));
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::Request;
+    use http_body_util::BodyExt;
+    use shared_data::Sample;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn get_json(router: &Router, uri: &str) -> (StatusCode, serde_json::Value) {
+        let response = router.clone().oneshot(Request::get(uri).body(Body::empty()).unwrap()).await.unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+
+        return (status, serde_json::from_slice(&body).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_samples_are_aggregated_into_buckets() {
+        let storage = Storage::connect("sqlite::memory:").await.unwrap();
+        for (timestamp, used_memory, cpu) in [(1000, 10, 1.0), (1005, 30, 3.0), (1010, 50, 5.0), (2000, 99, 9.0)] {
+            let sample = Sample {
+                collector_id: 0xabc,
+                total_memory: 100,
+                used_memory,
+                total_swap: 0,
+                used_swap: 0,
+                average_cpu_usage: cpu,
+                cpu_usage: Vec::new(),
+            };
+            storage.insert_sample(timestamp, &sample).await.unwrap();
+        }
+        let router = router(storage, Hub::new());
+
+        let (status, collectors) = get_json(&router, "/api/collectors").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            collectors,
+            serde_json::json!([{ "collector_id": "00000000000000000000000000000abc", "samples": 4, "first_seen": 1000, "last_seen": 2000 }])
+        );
+
+        let (status, samples) = get_json(&router, "/api/collectors/ABC/samples?from=1000&to=1020&step=10").await;
+        assert_eq!(status, StatusCode::OK);
+        let buckets = samples["buckets"].as_array().unwrap();
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(buckets[0]["timestamp"], 1000);
+        assert_eq!(buckets[0]["samples"], 2);
+        assert_eq!(buckets[0]["used_memory"], serde_json::json!({ "min": 10.0, "avg": 20.0, "max": 30.0 }));
+        assert_eq!(buckets[1]["average_cpu_usage"], serde_json::json!({ "min": 5.0, "avg": 5.0, "max": 5.0 }));
+
+        assert_eq!(get_json(&router, "/api/collectors/xyz/samples").await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(get_json(&router, "/api/collectors/abc/samples?from=10&to=5").await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(get_json(&router, "/api/collectors/abc/samples?from=0&to=100000&step=1").await.0, StatusCode::BAD_REQUEST);
+        for range in ["from=-10&to=10", "to=-1", "from=0&to=9223372036854775807", "from=-9223372036854775808&to=9223372036854775807&step=1"] {
+            assert_eq!(get_json(&router, &format!("/api/collectors/abc/samples?{range}")).await.0, StatusCode::BAD_REQUEST, "{range}");
+        }
+        assert_eq!(get_json(&router, "/api/collectors/abc/samples?to=100").await.0, StatusCode::OK);
+        assert_eq!(get_json(&router, "/api/collectors/def/samples").await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_commands_for_collectors() {
+        let hub = Hub::new();
+        let storage = Storage::connect("sqlite::memory:").await.unwrap();
+        let router = router(storage.clone(), hub.clone());
+        let post = |uri: &str, body: &str| {
+            let request = Request::post(uri).header("content-type", "application/json").body(Body::from(body.to_string())).unwrap();
+            return router.clone().oneshot(request);
+        };
+
+        let task = r#"{"task": "SampleNow"}"#;
+        assert_eq!(post("/api/collectors/abc/tasks", task).await.unwrap().status(), StatusCode::NOT_FOUND);
+        let sample = Sample {
+            collector_id: 0xabc,
+            total_memory: 100,
+            used_memory: 50,
+            total_swap: 0,
+            used_swap: 0,
+            average_cpu_usage: 0.5,
+            cpu_usage: Vec::new(),
+        };
+        storage.insert_sample(1000, &sample).await.unwrap();
+        let response = post("/api/collectors/abc/tasks", task).await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert_eq!(hub.take_task(0xabc), Some((1, Task::SampleNow)));
+
+        for _ in 0..MAX_QUEUED_TASKS {
+            assert_eq!(post("/api/collectors/abc/tasks", task).await.unwrap().status(), StatusCode::ACCEPTED);
+        }
+        assert_eq!(post("/api/collectors/abc/tasks", task).await.unwrap().status(), StatusCode::TOO_MANY_REQUESTS);
+
+        assert_eq!(post("/api/collectors/abc/interval", r#"{"seconds": 5}"#).await.unwrap().status(), StatusCode::NOT_FOUND);
+        let (sender, mut pushed) = tokio::sync::mpsc::unbounded_channel();
+        hub.register(0xabc, sender);
+        assert_eq!(post("/api/collectors/abc/interval", r#"{"seconds": 5}"#).await.unwrap().status(), StatusCode::ACCEPTED);
+        assert_eq!(pushed.recv().await, Some(ServerCommand::SetInterval { seconds: 5 }));
+        assert_eq!(post("/api/collectors/abc/interval", r#"{"seconds": 0}"#).await.unwrap().status(), StatusCode::BAD_REQUEST);
+        let too_long = format!(r#"{{"seconds": {}}}"#, u64::MAX);
+        assert_eq!(post("/api/collectors/abc/interval", &too_long).await.unwrap().status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,65 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use shared_data::{ServerCommand, Task};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Tasks waiting for one collector, more are refused until it takes some.
+pub const MAX_QUEUED_TASKS: usize = 100;
+
+/// The collectors connected right now and the tasks waiting for them, shared by the
+/// connections and the HTTP API.
+#[derive(Clone, Default)]
+pub struct Hub {
+    state: Arc<Mutex<HubState>>,
+}
+
+#[derive(Default)]
+struct HubState {
+    connections: HashMap<u128, UnboundedSender<ServerCommand>>,
+    tasks: HashMap<u128, VecDeque<(u64, Task)>>,
+    next_task_id: u64,
+}
+
+impl Hub {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    /// A collector that connects again replaces its previous connection.
+    pub fn register(&self, collector_id: u128, sender: UnboundedSender<ServerCommand>) {
+        self.state.lock().unwrap().connections.insert(collector_id, sender);
+    }
+
+    pub fn unregister(&self, collector_id: u128, sender: &UnboundedSender<ServerCommand>) {
+        let mut state = self.state.lock().unwrap();
+        if state.connections.get(&collector_id).is_some_and(|registered| registered.same_channel(sender)) {
+            state.connections.remove(&collector_id);
+        }
+    }
+
+    /// Sends a command to a connected collector, `false` if it is not connected.
+    pub fn push(&self, collector_id: u128, command: ServerCommand) -> bool {
+        let state = self.state.lock().unwrap();
+        return state.connections.get(&collector_id).is_some_and(|sender| sender.send(command).is_ok());
+    }
+
+    /// Queues a task for the next time the collector asks for work, returns its id. `None` when
+    /// `MAX_QUEUED_TASKS` are already waiting.
+    pub fn queue_task(&self, collector_id: u128, task: Task) -> Option<u64> {
+        let mut state = self.state.lock().unwrap();
+        if state.tasks.get(&collector_id).is_some_and(|tasks| tasks.len() >= MAX_QUEUED_TASKS) {
+            return None;
+        }
+
+        state.next_task_id += 1;
+        let task_id = state.next_task_id;
+        state.tasks.entry(collector_id).or_default().push_back((task_id, task));
+
+        return Some(task_id);
+    }
+
+    pub fn take_task(&self, collector_id: u128) -> Option<(u64, Task)> {
+        return self.state.lock().unwrap().tasks.get_mut(&collector_id)?.pop_front();
+    }
+}
@@ -0,0 +1,332 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use shared_data::{CollectorCodec, CollectorCommandV3, DecodeError, ServerCommand, VERSION_V3};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_util::codec::Framed;
+
+use crate::hub::Hub;
+use crate::security::Security;
+use crate::storage::Storage;
+
+/// How often collectors that speak V3 are pinged.
+const HEARTBEAT: Duration = Duration::from_secs(10);
+/// Collectors that speak V3 answer pings, so one that stays silent this long is gone.
+const SILENCE_LIMIT: Duration = Duration::from_secs(30);
+
+/// Accepts collectors until the listener fails, each connection on a task of its own.
+pub async fn serve(listener: TcpListener, storage: Storage, hub: Hub, security: Security) -> anyhow::Result<()> {
+    loop {
+        let (stream, address) = listener.accept().await?;
+        let storage = storage.clone();
+        let hub = hub.clone();
+        let security = security.clone();
+
+        tokio::spawn(async move {
+            let codec = match security.keys {
+                Some(keys) => CollectorCodec::new().with_keys(keys),
+                None => CollectorCodec::new(),
+            };
+
+            tracing::info!(%address, "Collector connected");
+            match security.tls {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(stream) => handle_connection(Framed::new(stream, codec), address, storage, hub).await,
+                    Err(error) => tracing::warn!(%address, %error, "TLS handshake failed"),
+                },
+                None => handle_connection(Framed::new(stream, codec), address, storage, hub).await,
+            }
+            tracing::info!(%address, "Collector disconnected");
+        });
+    }
+}
+
+/// Errors of frames that are well formed but not from a collector the server trusts.
+fn is_rejection(error: &DecodeError) -> bool {
+    return matches!(
+        error,
+        DecodeError::Unsigned
+            | DecodeError::UnknownCollector(_)
+            | DecodeError::BadSignature
+            | DecodeError::Stale { .. }
+            | DecodeError::Replayed { .. }
+    );
+}
+
+/// Stores samples until the collector hangs up. A frame that does not decode means the
+/// collector is broken or not a collector at all, so the connection is dropped. So is a frame
+/// the codec does not trust, or one signed by a collector other than the one it is about.
+///
+/// V1 and V2 collectors never read, so only collectors that sent a V3 command get replies,
+/// heartbeats and commands pushed through the hub.
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(mut framed: Framed<S, CollectorCodec>, address: SocketAddr, storage: Storage, hub: Hub) {
+    let (pusher, mut pushed) = mpsc::unbounded_channel();
+    let mut registered = None;
+    let mut heartbeat = tokio::time::interval_at(Instant::now() + HEARTBEAT, HEARTBEAT);
+    let mut last_heard = Instant::now();
+    let mut nonce = 0;
+
+    loop {
+        let reply = tokio::select! {
+            frame = framed.next() => {
+                let (timestamp, command) = match frame {
+                    Some(Ok(Ok(decoded))) => decoded,
+                    Some(Ok(Err(error))) if is_rejection(&error) => {
+                        tracing::warn!(%address, %error, "Rejected a frame, dropping the connection");
+                        break;
+                    }
+                    Some(Ok(Err(error))) => {
+                        tracing::warn!(%address, %error, "Malformed frame, dropping the connection");
+                        break;
+                    }
+                    Some(Err(error)) => {
+                        tracing::warn!(%address, %error, "Cannot read frames, dropping the connection");
+                        break;
+                    }
+                    None => break,
+                };
+                last_heard = Instant::now();
+
+                let speaks_v3 = command.version() >= VERSION_V3;
+                let command = command.into_latest();
+                if let (Some(signer), Some(collector_id)) = (framed.codec().peer(), collector_of(&command)) {
+                    if signer != collector_id {
+                        tracing::warn!(%address, "Collector {signer:032x} sent a command of {collector_id:032x}, dropping the connection");
+                        break;
+                    }
+                }
+                if let (true, None, Some(collector_id)) = (speaks_v3, registered, collector_of(&command)) {
+                    hub.register(collector_id, pusher.clone());
+                    registered = Some(collector_id);
+                }
+
+                let reply = reply_to(command, timestamp, &storage, &hub, address).await;
+                reply.filter(|_| speaks_v3)
+            }
+            Some(command) = pushed.recv() => Some(command),
+            _ = heartbeat.tick() => {
+                if registered.is_none() {
+                    None
+                } else if last_heard.elapsed() > SILENCE_LIMIT {
+                    tracing::warn!(%address, "No answer to pings, dropping the connection");
+                    break;
+                } else {
+                    nonce += 1;
+                    Some(ServerCommand::Ping { nonce })
+                }
+            }
+        };
+
+        if let Some(reply) = reply {
+            if let Err(error) = framed.send(reply).await {
+                tracing::warn!(%address, %error, "Cannot reply, dropping the connection");
+                break;
+            }
+        }
+    }
+
+    if let Some(collector_id) = registered {
+        hub.unregister(collector_id, &pusher);
+    }
+}
+
+fn collector_of(command: &CollectorCommandV3) -> Option<u128> {
+    return match command {
+        CollectorCommandV3::SubmitData { sample, .. } => Some(sample.collector_id),
+        CollectorCommandV3::RequestWork { collector_id } => Some(*collector_id),
+        CollectorCommandV3::Ping { .. } | CollectorCommandV3::Pong { .. } => None,
+    };
+}
+
+async fn reply_to(command: CollectorCommandV3, timestamp: u32, storage: &Storage, hub: &Hub, address: SocketAddr) -> Option<ServerCommand> {
+    return match command {
+        CollectorCommandV3::SubmitData { seq, sample } => match storage.insert_sample(timestamp, &sample).await {
+            Ok(()) => Some(ServerCommand::Ack { seq }),
+            // Without an ack the collector sends the sample again.
+            Err(error) => {
+                tracing::error!(%address, %error, "Cannot store a sample");
+                None
+            }
+        },
+        CollectorCommandV3::RequestWork { collector_id } => match hub.take_task(collector_id) {
+            Some((task_id, task)) => Some(ServerCommand::Task { task_id, task }),
+            None => Some(ServerCommand::NoWork),
+        },
+        CollectorCommandV3::Ping { nonce } => Some(ServerCommand::Pong { nonce }),
+        CollectorCommandV3::Pong { .. } => None,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use shared_data::{
+        encode_v1, encode_v2, CollectorCommand, CollectorCommandV1, CollectorCommandV2, Credentials, Key, KeyRing,
+        PayloadEncoding, Sample, ServerCodec, Task,
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio_rustls::rustls::crypto::ring;
+    use tokio_rustls::rustls::pki_types::pem::PemObject;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    use tokio_rustls::TlsConnector;
+
+    use super::*;
+
+    async fn start() -> (SocketAddr, Storage, Hub) {
+        return start_with(Security::default()).await;
+    }
+
+    async fn start_with(security: Security) -> (SocketAddr, Storage, Hub) {
+        let storage = Storage::connect("sqlite::memory:").await.unwrap();
+        let hub = Hub::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, storage.clone(), hub.clone(), security));
+
+        return (address, storage, hub);
+    }
+
+    fn sample(collector_id: u128) -> Sample {
+        return Sample { collector_id, total_memory: 100, used_memory: 50, total_swap: 0, used_swap: 0, average_cpu_usage: 0.5, cpu_usage: vec![] };
+    }
+
+    fn submit(collector_id: u128) -> CollectorCommand {
+        return CollectorCommand::V3(CollectorCommandV3::SubmitData { seq: 1, sample: sample(collector_id) });
+    }
+
+    /// Security with one known collector, and its credentials.
+    fn keys() -> (Security, Credentials) {
+        let credentials = Credentials { collector_id: 9, key: Key::generate() };
+        let mut keys = KeyRing::new();
+        keys.insert(credentials.collector_id, credentials.key.clone());
+
+        return (Security { keys: Some(Arc::new(keys)), tls: None }, credentials);
+    }
+
+    #[tokio::test]
+    async fn test_stores_samples_and_drops_malformed_connections() {
+        let (address, storage, _) = start().await;
+
+        let v1 = CollectorCommandV1::SubmitData { collector_id: 7, total_memory: 100, used_memory: 50, average_cpu_usage: 0.5 };
+        let v2 = CollectorCommandV2::SubmitData {
+            collector_id: 7,
+            total_memory: 100,
+            used_memory: 60,
+            total_swap: 10,
+            used_swap: 1,
+            average_cpu_usage: 0.25,
+            cpu_usage: vec![0.25, 0.25],
+        };
+
+        let mut collector = TcpStream::connect(address).await.unwrap();
+        collector.write_all(&[encode_v1(&v1), encode_v2(&v2)].concat()).await.unwrap();
+        collector.write_all(b"this is not a frame").await.unwrap();
+        // The server hangs up instead of answering.
+        assert_eq!(collector.read(&mut [0u8; 16]).await.unwrap(), 0);
+
+        // Other collectors are still served.
+        let mut collector = TcpStream::connect(address).await.unwrap();
+        collector.write_all(&encode_v1(&v1)).await.unwrap();
+        drop(collector);
+
+        for _ in 0..100 {
+            if storage.count_samples(7).await.unwrap() == 3 {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("Expected 3 samples, found {}", storage.count_samples(7).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_replies_to_v3_collectors() {
+        let (address, storage, hub) = start().await;
+        let mut collector = Framed::new(TcpStream::connect(address).await.unwrap(), ServerCodec::new().with_encoding(PayloadEncoding::Postcard));
+
+        collector.send(CollectorCommand::V3(CollectorCommandV3::SubmitData { seq: 41, sample: sample(9) })).await.unwrap();
+        collector.send(CollectorCommand::V3(CollectorCommandV3::RequestWork { collector_id: 9 })).await.unwrap();
+        assert_eq!(collector.next().await.unwrap().unwrap().unwrap().1, ServerCommand::Ack { seq: 41 });
+        assert_eq!(collector.next().await.unwrap().unwrap().unwrap().1, ServerCommand::NoWork);
+        assert_eq!(storage.count_samples(9).await.unwrap(), 1);
+
+        let task_id = hub.queue_task(9, Task::SampleNow).unwrap();
+        collector.send(CollectorCommand::V3(CollectorCommandV3::RequestWork { collector_id: 9 })).await.unwrap();
+        assert_eq!(collector.next().await.unwrap().unwrap().unwrap().1, ServerCommand::Task { task_id, task: Task::SampleNow });
+
+        assert!(hub.push(9, ServerCommand::SetInterval { seconds: 5 }));
+        assert_eq!(collector.next().await.unwrap().unwrap().unwrap().1, ServerCommand::SetInterval { seconds: 5 });
+        collector.send(CollectorCommand::V3(CollectorCommandV3::Ping { nonce: 3 })).await.unwrap();
+        assert_eq!(collector.next().await.unwrap().unwrap().unwrap().1, ServerCommand::Pong { nonce: 3 });
+        // Replies follow the encoding the collector chose.
+        assert_eq!(collector.codec().encoding(), PayloadEncoding::Postcard);
+
+        drop(collector);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!hub.push(9, ServerCommand::SetInterval { seconds: 5 }));
+        assert!(!hub.push(8, ServerCommand::NoWork));
+    }
+
+    #[tokio::test]
+    async fn test_rejects_untrusted_collectors() {
+        let (security, credentials) = keys();
+        let (address, storage, _) = start_with(security).await;
+        let connect = |codec: ServerCodec| async move { Framed::new(TcpStream::connect(address).await.unwrap(), codec) };
+
+        let mut collector = connect(ServerCodec::new().with_credentials(credentials.clone())).await;
+        collector.send(submit(9)).await.unwrap();
+        assert_eq!(collector.next().await.unwrap().unwrap().unwrap().1, ServerCommand::Ack { seq: 1 });
+
+        let stranger = Credentials { collector_id: 10, key: Key::generate() };
+        for (codec, command) in [
+            (ServerCodec::new(), submit(9)),
+            (ServerCodec::new().with_credentials(stranger), submit(10)),
+            // A known collector cannot submit for another one.
+            (ServerCodec::new().with_credentials(credentials), submit(8)),
+        ] {
+            let mut collector = connect(codec).await;
+            collector.send(command).await.unwrap();
+            assert!(collector.next().await.is_none());
+        }
+
+        assert_eq!(storage.count_samples(9).await.unwrap(), 1);
+        assert_eq!(storage.count_samples(8).await.unwrap(), 0);
+        assert_eq!(storage.count_samples(10).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_serves_collectors_over_tls() {
+        let directory = tempfile::tempdir().unwrap();
+        let certificate = directory.path().join("cert.pem");
+        let (mut security, credentials) = keys();
+        security.tls = Some(crate::security::tls_acceptor(&certificate, &directory.path().join("key.pem")).unwrap());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let key_mode = std::fs::metadata(directory.path().join("key.pem")).unwrap().permissions().mode();
+            assert_eq!(key_mode & 0o777, 0o600);
+        }
+        let (address, storage, _) = start_with(security).await;
+
+        let mut roots = RootCertStore::empty();
+        roots.add(CertificateDer::from_pem_file(&certificate).unwrap()).unwrap();
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let stream = TcpStream::connect(address).await.unwrap();
+        let stream = TlsConnector::from(Arc::new(config)).connect(ServerName::try_from("localhost").unwrap(), stream).await.unwrap();
+
+        let mut collector = Framed::new(stream, ServerCodec::new().with_credentials(credentials));
+        collector.send(submit(9)).await.unwrap();
+        assert_eq!(collector.next().await.unwrap().unwrap().unwrap().1, ServerCommand::Ack { seq: 1 });
+        assert_eq!(storage.count_samples(9).await.unwrap(), 1);
+    }
+}
@@ -0,0 +1,62 @@
+#![allow(clippy::needless_return)]
+
+mod api;
+mod hub;
+mod ingest;
+mod security;
+mod storage;
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use shared_data::DATA_COLLECTOR_ADDRESS;
+use tokio::net::TcpListener;
+
+use hub::Hub;
+use security::Security;
+use storage::Storage;
+
+/// Where the dashboard and the JSON API are served, unless set with `HTTP_ADDRESS`. The API is
+/// not authenticated, so keep it on localhost.
+const DEFAULT_HTTP_ADDRESS: &str = "127.0.0.1:3000";
+/// The keys of the collectors allowed to submit, unless set with `COLLECTOR_KEYS`.
+const DEFAULT_COLLECTOR_KEYS: &str = "collector_keys";
+
+/// TLS for collectors when both `TLS_CERT` and `TLS_KEY` name PEM files, which are generated
+/// when neither exists.
+fn tls_from_env() -> anyhow::Result<Option<tokio_rustls::TlsAcceptor>> {
+    return match (std::env::var_os("TLS_CERT"), std::env::var_os("TLS_KEY")) {
+        (Some(certificate), Some(key)) => Ok(Some(security::tls_acceptor(&PathBuf::from(certificate), &PathBuf::from(key))?)),
+        (None, None) => Ok(None),
+        _ => anyhow::bail!("Set both TLS_CERT and TLS_KEY to serve collectors over TLS"),
+    };
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().compact().init();
+    // These may also come from a .env file.
+    dotenv::dotenv().ok();
+
+    let database_url = std::env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite:collector.db".to_string());
+    let http_address = std::env::var("HTTP_ADDRESS").unwrap_or_else(|_| DEFAULT_HTTP_ADDRESS.to_string());
+    let keys_path = std::env::var("COLLECTOR_KEYS").unwrap_or_else(|_| DEFAULT_COLLECTOR_KEYS.to_string());
+    let keys = security::load_keys(keys_path.as_ref())?;
+    tracing::info!("Accepting the {} collectors in {keys_path}", keys.len());
+    let security = Security { keys: Some(Arc::new(keys)), tls: tls_from_env()? };
+    let storage = Storage::connect(&database_url).await?;
+
+    let listener = TcpListener::bind(DATA_COLLECTOR_ADDRESS).await?;
+    let transport = if security.tls.is_some() { "TLS" } else { "plain TCP" };
+    tracing::info!("Listening for collectors on {DATA_COLLECTOR_ADDRESS} over {transport}, storing samples in {database_url}");
+    let http_listener = TcpListener::bind(&http_address).await?;
+    tracing::info!("Serving the dashboard on http://{http_address}");
+
+    let hub = Hub::new();
+    tokio::try_join!(
+        ingest::serve(listener, storage.clone(), hub.clone(), security),
+        async { return Ok(axum::serve(http_listener, api::router(storage, hub)).await?) },
+    )?;
+
+    return Ok(());
+}
@@ -0,0 +1,58 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Context;
+use shared_data::{write_private, KeyRing};
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+/// What collectors need to connect: a key in `keys` to sign their frames with, and TLS when
+/// `tls` is set. The default accepts anyone, which only tests want.
+#[derive(Clone, Default)]
+pub struct Security {
+    pub keys: Option<Arc<KeyRing>>,
+    pub tls: Option<TlsAcceptor>,
+}
+
+/// Reads the keys of the collectors allowed to submit, see `KeyRing`.
+pub fn load_keys(path: &Path) -> anyhow::Result<KeyRing> {
+    let text = std::fs::read_to_string(path).with_context(|| {
+        format!("Cannot read the collector keys in {}, collectors print the line to add there when they create their key", path.display())
+    })?;
+
+    return text.parse().map_err(|error| anyhow::anyhow!("{}: {error}", path.display()));
+}
+
+/// A self-signed certificate for localhost, which collectors trust with `--tls-ca`. Returns
+/// the certificate and its private key as PEM.
+pub fn generate_certificate() -> anyhow::Result<(String, String)> {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string(), "127.0.0.1".to_string()])?;
+
+    return Ok((certified.cert.pem(), certified.key_pair.serialize_pem()));
+}
+
+/// Serves TLS with the certificate and key in PEM files, generating both first when neither
+/// exists yet.
+pub fn tls_acceptor(certificate_path: &Path, key_path: &Path) -> anyhow::Result<TlsAcceptor> {
+    if !certificate_path.exists() && !key_path.exists() {
+        let (certificate, key) = generate_certificate()?;
+        std::fs::write(certificate_path, certificate)?;
+        write_private(key_path, &key)?;
+        tracing::info!("Generated a self-signed certificate in {}", certificate_path.display());
+    }
+
+    let certificates = CertificateDer::pem_file_iter(certificate_path)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Cannot read the certificate in {}", certificate_path.display()))?;
+    let key = PrivateKeyDer::from_pem_file(key_path).with_context(|| format!("Cannot read the private key in {}", key_path.display()))?;
+
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)?;
+
+    return Ok(TlsAcceptor::from(Arc::new(config)));
+}
@@ -0,0 +1,153 @@
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use serde::Serialize;
+use shared_data::Sample;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use sqlx::{FromRow, SqlitePool};
+
+/// Collector ids are u128, which SQLite cannot hold, so they are stored as 32 hex digits.
+pub fn format_collector_id(collector_id: u128) -> String {
+    return format!("{collector_id:032x}");
+}
+
+pub fn unix_now() -> i64 {
+    return SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
+}
+
+#[derive(Debug, Serialize, FromRow, PartialEq)]
+pub struct CollectorSummary {
+    pub collector_id: String,
+    pub samples: i64,
+    pub first_seen: i64,
+    pub last_seen: i64,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+pub struct Stats {
+    pub min: f64,
+    pub avg: f64,
+    pub max: f64,
+}
+
+/// The samples of one collector from `timestamp` until the next bucket.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct SampleBucket {
+    pub timestamp: i64,
+    pub samples: i64,
+    pub total_memory: i64,
+    pub used_memory: Stats,
+    pub total_swap: i64,
+    pub used_swap: Stats,
+    pub average_cpu_usage: Stats,
+}
+
+#[derive(FromRow)]
+struct BucketRow {
+    bucket: i64,
+    samples: i64,
+    total_memory: i64,
+    min_used_memory: f64,
+    avg_used_memory: f64,
+    max_used_memory: f64,
+    total_swap: i64,
+    min_used_swap: f64,
+    avg_used_swap: f64,
+    max_used_swap: f64,
+    min_cpu: f64,
+    avg_cpu: f64,
+    max_cpu: f64,
+}
+
+#[derive(Clone)]
+pub struct Storage {
+    pool: SqlitePool,
+}
+
+impl Storage {
+    /// Opens the database, creating it if needed, and runs the migrations.
+    pub async fn connect(url: &str) -> anyhow::Result<Self> {
+        let options = SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+        // Every connection to an in-memory database is a database of its own.
+        let max_connections = if url.contains(":memory:") { 1 } else { 8 };
+        let pool = SqlitePoolOptions::new().max_connections(max_connections).connect_with(options).await?;
+
+        sqlx::migrate!("./migrations").run(&pool).await?;
+
+        return Ok(Self { pool });
+    }
+
+    pub async fn insert_sample(&self, timestamp: u32, sample: &Sample) -> anyhow::Result<()> {
+        sqlx::query(
+            "insert into samples (collector_id, timestamp, received_at, total_memory, used_memory, total_swap, used_swap, average_cpu_usage, cpu_usage)
+             values (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(format_collector_id(sample.collector_id))
+        .bind(timestamp as i64)
+        .bind(unix_now())
+        .bind(sample.total_memory as i64)
+        .bind(sample.used_memory as i64)
+        .bind(sample.total_swap as i64)
+        .bind(sample.used_swap as i64)
+        .bind(sample.average_cpu_usage)
+        .bind(serde_json::to_string(&sample.cpu_usage)?)
+        .execute(&self.pool)
+        .await?;
+
+        return Ok(());
+    }
+
+    pub async fn collectors(&self) -> anyhow::Result<Vec<CollectorSummary>> {
+        let collectors = sqlx::query_as(
+            "select collector_id, count(*) as samples, min(timestamp) as first_seen, max(timestamp) as last_seen
+             from samples group by collector_id order by collector_id",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        return Ok(collectors);
+    }
+
+    /// Samples with `from <= timestamp < to`, aggregated into buckets of `step` seconds. Buckets
+    /// without samples are left out.
+    pub async fn sample_buckets(&self, collector_id: u128, from: i64, to: i64, step: i64) -> anyhow::Result<Vec<SampleBucket>> {
+        let rows: Vec<BucketRow> = sqlx::query_as(
+            "select (timestamp - ?1) / ?2 as bucket, count(*) as samples,
+                    max(total_memory) as total_memory,
+                    cast(min(used_memory) as real) as min_used_memory, avg(used_memory) as avg_used_memory, cast(max(used_memory) as real) as max_used_memory,
+                    max(total_swap) as total_swap,
+                    cast(min(used_swap) as real) as min_used_swap, avg(used_swap) as avg_used_swap, cast(max(used_swap) as real) as max_used_swap,
+                    min(average_cpu_usage) as min_cpu, avg(average_cpu_usage) as avg_cpu, max(average_cpu_usage) as max_cpu
+             from samples where collector_id = ?3 and timestamp >= ?1 and timestamp < ?4
+             group by bucket order by bucket",
+        )
+        .bind(from)
+        .bind(step)
+        .bind(format_collector_id(collector_id))
+        .bind(to)
+        .fetch_all(&self.pool)
+        .await?;
+
+        return Ok(rows
+            .into_iter()
+            .map(|row| SampleBucket {
+                timestamp: from + row.bucket * step,
+                samples: row.samples,
+                total_memory: row.total_memory,
+                used_memory: Stats { min: row.min_used_memory, avg: row.avg_used_memory, max: row.max_used_memory },
+                total_swap: row.total_swap,
+                used_swap: Stats { min: row.min_used_swap, avg: row.avg_used_swap, max: row.max_used_swap },
+                average_cpu_usage: Stats { min: row.min_cpu, avg: row.avg_cpu, max: row.max_cpu },
+            })
+            .collect());
+    }
+
+    pub async fn count_samples(&self, collector_id: u128) -> anyhow::Result<i64> {
+        let count = sqlx::query_scalar("select count(*) from samples where collector_id = ?")
+            .bind(format_collector_id(collector_id))
+            .fetch_one(&self.pool)
+            .await?;
+
+        return Ok(count);
+    }
+}
@@ -0,0 +1,36 @@
+#![allow(clippy::needless_return)]
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use shared_data::{decode, encode, CollectorCommand, CollectorCommandV1, PayloadEncoding};
+
+fn command() -> CollectorCommand {
+    return CollectorCommand::V1(CollectorCommandV1::SubmitData {
+        collector_id: u128::MAX / 3,
+        total_memory: 16 * 1024 * 1024 * 1024,
+        used_memory: 9 * 1024 * 1024 * 1024,
+        average_cpu_usage: 37.5,
+    });
+}
+
+fn encodings(criterion: &mut Criterion) {
+    let command = command();
+    let mut group = criterion.benchmark_group("SubmitData");
+
+    for encoding in [PayloadEncoding::Json, PayloadEncoding::Postcard] {
+        let frame = encode(&command, encoding);
+        println!("{encoding:?} frame: {} bytes", frame.len());
+        group.throughput(Throughput::Bytes(frame.len() as u64));
+
+        group.bench_with_input(BenchmarkId::new("encode", format!("{encoding:?}")), &encoding, |bencher, encoding| {
+            bencher.iter(|| encode(black_box(&command), *encoding));
+        });
+        group.bench_with_input(BenchmarkId::new("decode", format!("{encoding:?}")), &frame, |bencher, frame| {
+            bencher.iter(|| decode(black_box(frame)).unwrap());
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, encodings);
+criterion_main!(benches);
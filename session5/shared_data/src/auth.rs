@@ -0,0 +1,368 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+use crate::{unix_now, DecodeError, FrameHeader};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Set in the encoding byte of frames that end with a signature.
+pub(crate) const SIGNED_FLAG: u8 = 0x80;
+/// The high byte of the version field, which holds the encoding.
+const ENCODING_INDEX: usize = 2;
+const MAC_SIZE: usize = 32;
+/// Collector id, sending time and nonce, followed by the HMAC-SHA256 of everything before it.
+pub(crate) const SIGNATURE_SIZE: usize = 16 + 4 + 8 + MAC_SIZE;
+/// How far the sending time of a signed frame may be from the clock of the receiver.
+pub const REPLAY_WINDOW: u32 = 5 * 60;
+const MIN_KEY_SIZE: usize = 16;
+
+/// A key shared by one collector and the server, written as hex.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Key(Vec<u8>);
+
+impl Key {
+    pub fn generate() -> Self {
+        let mut bytes = vec![0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+
+        return Key(bytes);
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        return HmacSha256::new_from_slice(&self.0).expect("HMAC takes keys of any size");
+    }
+}
+
+/// Keeps keys out of logs.
+impl fmt::Debug for Key {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        return formatter.write_str("Key(..)");
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        return formatter.write_str(&hex::encode(&self.0));
+    }
+}
+
+impl std::str::FromStr for Key {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(text.trim()).map_err(|error| format!("A key is written as hex: {error}"))?;
+        if bytes.len() < MIN_KEY_SIZE {
+            return Err(format!("A key needs at least {MIN_KEY_SIZE} bytes, this one has {}", bytes.len()));
+        }
+
+        return Ok(Key(bytes));
+    }
+}
+
+/// Creates a file only the owner can read, for keys. An existing file is an error.
+pub fn write_private(path: &Path, contents: &str) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    return options.open(path)?.write_all(contents.as_bytes());
+}
+
+/// The identity a collector signs its frames with.
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub collector_id: u128,
+    pub key: Key,
+}
+
+impl Credentials {
+    /// Signs an unsigned frame. Frames are signed when they are sent rather than when they are
+    /// encoded, since the timestamp in the header is when the sample was taken and a queued
+    /// sample can be sent long after that.
+    pub fn sign(&self, frame: &[u8]) -> Vec<u8> {
+        return sign(frame, self.collector_id, &self.key);
+    }
+}
+
+fn sign(frame: &[u8], collector_id: u128, key: &Key) -> Vec<u8> {
+    let mut signed = Vec::with_capacity(frame.len() + SIGNATURE_SIZE);
+    signed.extend_from_slice(frame);
+    signed[ENCODING_INDEX] |= SIGNED_FLAG;
+    signed.extend_from_slice(&collector_id.to_be_bytes());
+    signed.extend_from_slice(&unix_now().to_be_bytes());
+    signed.extend_from_slice(&rand::thread_rng().next_u64().to_be_bytes());
+
+    let mut mac = key.mac();
+    mac.update(&signed);
+    signed.extend_from_slice(&mac.finalize().into_bytes());
+
+    return signed;
+}
+
+/// What a signed frame says about who sent it and when.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Signature {
+    collector_id: u128,
+    sent_at: u32,
+    nonce: u64,
+}
+
+impl Signature {
+    /// Reads the signature at the end of a complete frame, without checking it.
+    pub(crate) fn read(header: &FrameHeader, frame: &[u8]) -> Result<Self, DecodeError> {
+        if !header.is_signed() {
+            return Err(DecodeError::Unsigned);
+        }
+
+        let trailer = &frame[header.frame_size() - SIGNATURE_SIZE..];
+        return Ok(Signature {
+            collector_id: u128::from_be_bytes(trailer[..16].try_into().unwrap()),
+            sent_at: u32::from_be_bytes(trailer[16..20].try_into().unwrap()),
+            nonce: u64::from_be_bytes(trailer[20..28].try_into().unwrap()),
+        });
+    }
+
+    fn verify(&self, frame: &[u8], key: &Key) -> Result<(), DecodeError> {
+        let (signed, expected) = frame.split_at(frame.len() - MAC_SIZE);
+        let mut mac = key.mac();
+        mac.update(signed);
+
+        return mac.verify_slice(expected).map_err(|_| DecodeError::BadSignature);
+    }
+}
+
+/// Remembers the nonces of recent frames so none is accepted twice. Frames sent outside the
+/// window are refused anyway, so a nonce is only remembered for as long as the window.
+#[derive(Debug, Default)]
+pub(crate) struct ReplayGuard {
+    seen: HashMap<(u128, u64), u32>,
+    pruned_at: u32,
+}
+
+impl ReplayGuard {
+    fn check(&mut self, signature: &Signature, now: u32) -> Result<(), DecodeError> {
+        if signature.sent_at.abs_diff(now) > REPLAY_WINDOW {
+            return Err(DecodeError::Stale { sent_at: signature.sent_at });
+        }
+
+        if self.pruned_at != now {
+            self.seen.retain(|_, sent_at| sent_at.abs_diff(now) <= REPLAY_WINDOW);
+            self.pruned_at = now;
+        }
+        if self.seen.insert((signature.collector_id, signature.nonce), signature.sent_at).is_some() {
+            return Err(DecodeError::Replayed { nonce: signature.nonce });
+        }
+
+        return Ok(());
+    }
+}
+
+/// The keys of the collectors a server accepts, and the nonces it saw lately. Shared by all
+/// connections, so a frame cannot be replayed over another connection either.
+#[derive(Debug, Default)]
+pub struct KeyRing {
+    keys: HashMap<u128, Key>,
+    replays: Mutex<ReplayGuard>,
+}
+
+impl KeyRing {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    pub fn insert(&mut self, collector_id: u128, key: Key) {
+        self.keys.insert(collector_id, key);
+    }
+
+    pub fn len(&self) -> usize {
+        return self.keys.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.keys.is_empty();
+    }
+}
+
+/// One `<collector id> <key>` pair per line, both in hex, the way a collector prints them when
+/// it creates its key. `#` starts a comment.
+impl std::str::FromStr for KeyRing {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut keys = KeyRing::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let parsed = match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [collector_id, key] => u128::from_str_radix(&collector_id.replace('-', ""), 16)
+                    .map_err(|error| format!("bad collector id: {error}"))
+                    .and_then(|collector_id| Ok((collector_id, key.parse()?))),
+                _ => Err("expected a collector id and a key".to_string()),
+            };
+            let (collector_id, key) = parsed.map_err(|error| format!("Line {}: {error}", index + 1))?;
+            keys.insert(collector_id, key);
+        }
+
+        return Ok(keys);
+    }
+}
+
+/// How a codec signs what it encodes and which frames it accepts.
+#[derive(Debug, Clone, Default)]
+pub(crate) enum Auth {
+    /// Signs nothing and accepts any frame, signed or not.
+    #[default]
+    None,
+    /// A collector, which signs with its own key and accepts only frames signed with it.
+    Credentials { credentials: Credentials, replays: Arc<Mutex<ReplayGuard>> },
+    /// A server, which accepts frames of the collectors it has keys for and signs with the key of
+    /// the collector it last heard from.
+    KeyRing { keys: Arc<KeyRing>, peer: Option<u128> },
+}
+
+impl Auth {
+    pub(crate) fn credentials(credentials: Credentials) -> Self {
+        return Auth::Credentials { credentials, replays: Arc::default() };
+    }
+
+    pub(crate) fn key_ring(keys: Arc<KeyRing>) -> Self {
+        return Auth::KeyRing { keys, peer: None };
+    }
+
+    pub(crate) fn peer(&self) -> Option<u128> {
+        return match self {
+            Auth::None => None,
+            Auth::Credentials { credentials, .. } => Some(credentials.collector_id),
+            Auth::KeyRing { peer, .. } => *peer,
+        };
+    }
+
+    /// Checks the signature of a complete frame. The nonce is only remembered once the
+    /// signature holds, so forged frames cannot fill the replay guard.
+    pub(crate) fn verify(&mut self, header: &FrameHeader, frame: &[u8]) -> Result<(), DecodeError> {
+        match self {
+            Auth::None => return Ok(()),
+            Auth::Credentials { credentials, replays } => {
+                let signature = Signature::read(header, frame)?;
+                if signature.collector_id != credentials.collector_id {
+                    return Err(DecodeError::UnknownCollector(signature.collector_id));
+                }
+                signature.verify(frame, &credentials.key)?;
+                return replays.lock().unwrap().check(&signature, unix_now());
+            }
+            Auth::KeyRing { keys, peer } => {
+                let signature = Signature::read(header, frame)?;
+                let key = keys.keys.get(&signature.collector_id).ok_or(DecodeError::UnknownCollector(signature.collector_id))?;
+                signature.verify(frame, key)?;
+                keys.replays.lock().unwrap().check(&signature, unix_now())?;
+                *peer = Some(signature.collector_id);
+                return Ok(());
+            }
+        }
+    }
+
+    /// Signs an encoded frame. A server that has not heard from a collector yet has no key to
+    /// sign with, and its frames go out unsigned for the collector to refuse.
+    pub(crate) fn sign(&self, frame: Vec<u8>) -> Vec<u8> {
+        return match self {
+            Auth::None => frame,
+            Auth::Credentials { credentials, .. } => credentials.sign(&frame),
+            Auth::KeyRing { keys, peer } => match peer.and_then(|peer| Some((peer, keys.keys.get(&peer)?))) {
+                Some((peer, key)) => sign(&frame, peer, key),
+                None => frame,
+            },
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decode, encode, CollectorCommand, CollectorCommandV3, PayloadEncoding, ServerCommand};
+
+    fn credentials() -> Credentials {
+        return Credentials { collector_id: 7, key: Key::generate() };
+    }
+
+    fn verify(auth: &mut Auth, frame: &[u8]) -> Result<(), DecodeError> {
+        let header = FrameHeader::parse(frame).unwrap().unwrap();
+        return auth.verify(&header, frame);
+    }
+
+    #[test]
+    fn test_signed_frames() {
+        let credentials = credentials();
+        let mut keys = KeyRing::new();
+        keys.insert(credentials.collector_id, credentials.key.clone());
+        let mut server = Auth::key_ring(Arc::new(keys));
+
+        let command = CollectorCommand::V3(CollectorCommandV3::Ping { nonce: 1 });
+        let frame = encode(&command, PayloadEncoding::Postcard);
+        let signed = credentials.sign(&frame);
+        // Readers that do not check signatures still decode signed frames.
+        assert_eq!(decode(&signed).unwrap().1, command);
+
+        assert_eq!(verify(&mut server, &frame), Err(DecodeError::Unsigned));
+        assert_eq!(verify(&mut server, &signed), Ok(()));
+        assert_eq!(server.peer(), Some(7));
+        assert!(matches!(verify(&mut server, &signed), Err(DecodeError::Replayed { .. })));
+
+        let mut tampered = credentials.sign(&frame);
+        tampered[crate::HEADER_SIZE] ^= 1;
+        assert_eq!(verify(&mut server, &tampered), Err(DecodeError::BadSignature));
+
+        let stranger = Credentials { collector_id: 8, key: credentials.key.clone() };
+        assert_eq!(verify(&mut server, &stranger.sign(&frame)), Err(DecodeError::UnknownCollector(8)));
+
+        // Replies are signed with the key of the collector, which accepts nothing else.
+        let reply = server.sign(encode(&ServerCommand::Pong { nonce: 1 }, PayloadEncoding::Json));
+        let mut collector = Auth::credentials(credentials.clone());
+        assert_eq!(verify(&mut collector, &reply), Ok(()));
+        let mut impostor = Auth::credentials(Credentials { collector_id: 7, key: Key::generate() });
+        assert_eq!(verify(&mut impostor, &reply), Err(DecodeError::BadSignature));
+    }
+
+    #[test]
+    fn test_replay_guard_window() {
+        let mut guard = ReplayGuard::default();
+        let signature = |sent_at, nonce| Signature { collector_id: 1, sent_at, nonce };
+
+        assert_eq!(guard.check(&signature(1000, 1), 1000), Ok(()));
+        assert_eq!(guard.check(&signature(1000, 2), 1000), Ok(()));
+        assert_eq!(guard.check(&signature(1000, 1), 1001), Err(DecodeError::Replayed { nonce: 1 }));
+        assert_eq!(guard.check(&signature(1000, 1), 1000 + REPLAY_WINDOW + 1), Err(DecodeError::Stale { sent_at: 1000 }));
+        // Nonces are forgotten once their frames would be refused as stale anyway.
+        assert_eq!(guard.check(&signature(1000 + REPLAY_WINDOW + 1, 1), 1000 + REPLAY_WINDOW + 1), Ok(()));
+        assert_eq!(guard.seen.len(), 1);
+    }
+
+    #[test]
+    fn test_key_ring_file() {
+        let key = Key::generate();
+        let text = format!("# collectors\n0000000000000000000000000000000a {key}\n\n0000000b-0000-0000-0000-000000000000 {key} # laptop\n");
+        let keys: KeyRing = text.parse().unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys.keys.get(&10), Some(&key));
+
+        assert!("a 1234".parse::<KeyRing>().unwrap_err().starts_with("Line 1"));
+        assert!("a".parse::<KeyRing>().is_err());
+        assert!(format!("{key}").parse::<Key>().is_ok());
+        assert!("zz".parse::<Key>().is_err());
+        assert_eq!(format!("{key:?}"), "Key(..)");
+    }
+}
@@ -0,0 +1,405 @@
+use std::io::{self, Read};
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use bytes::{Buf, BytesMut};
+use thiserror::Error;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::auth::Auth;
+use crate::{CollectorCommand, Credentials, KeyRing, DecodeError, FrameHeader, PayloadEncoding, ServerCommand, WireMessage, CRC_SIZE, HEADER_SIZE};
+
+/// Default limit for the payload of one frame. Commands are a few hundred bytes, anything much
+/// larger is a broken or hostile peer.
+pub const MAX_PAYLOAD_SIZE: usize = 1024 * 1024;
+
+/// One decoded frame, or the error of a frame that was skipped.
+pub type Frame<T = CollectorCommand> = Result<(u32, T), DecodeError>;
+
+/// Errors that end a stream of frames. Per-frame errors such as a CRC mismatch are yielded as
+/// items instead, since the next frame can still be read.
+#[derive(Debug, Error)]
+pub enum CodecError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Cannot read the stream any further: {0}")]
+    Decode(#[from] DecodeError),
+}
+
+/// Frames over a byte stream, for `FramedRead`, `FramedWrite` and `Framed`. Decodes messages
+/// of type `T` and encodes any `WireMessage`, see `CollectorCodec` and `ServerCodec`.
+///
+/// Decoded items are `Ok((timestamp, command))` or the `DecodeError` of a frame that was
+/// skipped. A bad magic number or a payload over the limit cannot be skipped safely, those
+/// end the stream with `CodecError::Decode`.
+///
+/// Frames of any encoding are decoded. Commands are encoded with the codec's encoding, which
+/// follows the encoding of the last decoded frame, so a server replies in whatever its
+/// collector chose for the connection.
+///
+/// By default frames are neither signed nor checked. With `with_credentials` or `with_keys`
+/// every frame is signed, and frames that are unsigned, signed by an unknown collector or
+/// replayed are yielded as errors.
+#[derive(Debug)]
+pub struct FrameCodec<T> {
+    max_payload_size: usize,
+    encoding: PayloadEncoding,
+    auth: Auth,
+    decodes: PhantomData<fn() -> T>,
+}
+
+/// The server side, decoding what collectors send.
+pub type CollectorCodec = FrameCodec<CollectorCommand>;
+/// The collector side, decoding what the server sends.
+pub type ServerCodec = FrameCodec<ServerCommand>;
+
+impl<T> Clone for FrameCodec<T> {
+    fn clone(&self) -> Self {
+        return Self { max_payload_size: self.max_payload_size, encoding: self.encoding, auth: self.auth.clone(), decodes: PhantomData };
+    }
+}
+
+impl<T> Default for FrameCodec<T> {
+    fn default() -> Self {
+        return Self { max_payload_size: MAX_PAYLOAD_SIZE, encoding: PayloadEncoding::Json, auth: Auth::None, decodes: PhantomData };
+    }
+}
+
+impl<T> FrameCodec<T> {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    pub fn with_max_payload_size(self, max_payload_size: usize) -> Self {
+        return Self { max_payload_size, ..self };
+    }
+
+    pub fn with_encoding(self, encoding: PayloadEncoding) -> Self {
+        return Self { encoding, ..self };
+    }
+
+    pub fn encoding(&self) -> PayloadEncoding {
+        return self.encoding;
+    }
+
+    /// For a collector, which signs with its key and accepts only frames signed with it.
+    pub fn with_credentials(self, credentials: Credentials) -> Self {
+        return Self { auth: Auth::credentials(credentials), ..self };
+    }
+
+    /// For a server, which accepts frames signed by the collectors in `keys` and signs with the
+    /// key of the collector it last heard from.
+    pub fn with_keys(self, keys: Arc<KeyRing>) -> Self {
+        return Self { auth: Auth::key_ring(keys), ..self };
+    }
+
+    /// The collector whose key signs the frames, once known. `None` when frames are not signed.
+    pub fn peer(&self) -> Option<u128> {
+        return self.auth.peer();
+    }
+}
+
+impl<T: WireMessage> Decoder for FrameCodec<T> {
+    type Item = Frame<T>;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let Some(header) = FrameHeader::parse(src)? else {
+            return Ok(None);
+        };
+        header.check_size(self.max_payload_size)?;
+
+        let frame_size = header.frame_size();
+        if src.len() < frame_size {
+            src.reserve(frame_size - src.len());
+            return Ok(None);
+        }
+
+        let frame = src.split_to(frame_size);
+        let decoded = self.auth.verify(&header, &frame).and_then(|()| header.decode(&frame));
+        if decoded.is_ok() {
+            self.encoding = header.encoding()?;
+        }
+
+        return Ok(Some(decoded));
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if let Some(item) = self.decode(buf)? {
+            return Ok(Some(item));
+        }
+        if buf.is_empty() {
+            return Ok(None);
+        }
+
+        let expected = match FrameHeader::parse(buf)? {
+            Some(header) => header.frame_size(),
+            None => HEADER_SIZE,
+        };
+        let available = buf.len();
+        buf.advance(available);
+
+        return Err(DecodeError::Truncated { expected, available }.into());
+    }
+}
+
+impl<T, E: WireMessage> Encoder<E> for FrameCodec<T> {
+    type Error = CodecError;
+
+    fn encode(&mut self, message: E, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let frame = message.encode(self.encoding);
+
+        let size = frame.len() - HEADER_SIZE - CRC_SIZE;
+        if size > self.max_payload_size {
+            return Err(DecodeError::PayloadTooLarge { size, max: self.max_payload_size }.into());
+        }
+
+        dst.extend_from_slice(&self.auth.sign(frame));
+        return Ok(());
+    }
+}
+
+/// Reads frames from a blocking reader such as a `TcpStream`, with the same rules as
+/// `FrameCodec`.
+pub struct FrameReader<R: Read, T = CollectorCommand> {
+    reader: R,
+    buffer: BytesMut,
+    codec: FrameCodec<T>,
+}
+
+impl<R: Read> FrameReader<R> {
+    /// Reads collector commands.
+    pub fn new(reader: R) -> Self {
+        return Self::with_codec(reader, CollectorCodec::new());
+    }
+}
+
+impl<R: Read, T: WireMessage> FrameReader<R, T> {
+    pub fn with_codec(reader: R, codec: FrameCodec<T>) -> Self {
+        return Self { reader, buffer: BytesMut::new(), codec };
+    }
+
+    pub fn with_max_payload_size(self, max_payload_size: usize) -> Self {
+        return Self { codec: self.codec.with_max_payload_size(max_payload_size), ..self };
+    }
+
+    /// The next frame, or `None` once the reader ends between frames.
+    pub fn read_frame(&mut self) -> Result<Option<Frame<T>>, CodecError> {
+        let mut chunk = [0u8; 4096];
+
+        loop {
+            if let Some(item) = self.codec.decode(&mut self.buffer)? {
+                return Ok(Some(item));
+            }
+
+            let read = match self.reader.read(&mut chunk) {
+                Ok(read) => read,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error.into()),
+            };
+            if read == 0 {
+                return self.codec.decode_eof(&mut self.buffer);
+            }
+            self.buffer.extend_from_slice(&chunk[..read]);
+        }
+    }
+
+    /// The reader, for writing to a stream that is also read. Reading from it directly loses
+    /// frames.
+    pub fn get_mut(&mut self) -> &mut R {
+        return &mut self.reader;
+    }
+
+    pub fn into_inner(self) -> R {
+        return self.reader;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{SinkExt, StreamExt};
+    use proptest::prelude::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio_util::codec::{FramedRead, FramedWrite};
+
+    use super::*;
+    use crate::{encode, CollectorCommandV1, CollectorCommandV2, Key};
+
+    fn commands() -> Vec<CollectorCommand> {
+        return vec![
+            CollectorCommand::V1(CollectorCommandV1::SubmitData {
+                collector_id: 1,
+                total_memory: 100,
+                used_memory: 50,
+                average_cpu_usage: 0.5,
+            }),
+            CollectorCommand::V2(CollectorCommandV2::SubmitData {
+                collector_id: 2,
+                total_memory: 200,
+                used_memory: 20,
+                total_swap: 10,
+                used_swap: 1,
+                average_cpu_usage: 0.25,
+                cpu_usage: vec![0.25, 0.75],
+            }),
+        ];
+    }
+
+    fn encoded(commands: &[CollectorCommand]) -> Vec<u8> {
+        let mut buffer = BytesMut::new();
+        for command in commands {
+            CollectorCodec::new().encode(command.clone(), &mut buffer).unwrap();
+        }
+
+        return buffer.to_vec();
+    }
+
+    /// Hands out at most `chunk` bytes per read, like a slow socket.
+    struct Trickle<'a> {
+        bytes: &'a [u8],
+        chunk: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let size = self.chunk.min(buf.len()).min(self.bytes.len());
+            buf[..size].copy_from_slice(&self.bytes[..size]);
+            self.bytes = &self.bytes[size..];
+            return Ok(size);
+        }
+    }
+
+    fn read_all(bytes: &[u8], chunk: usize) -> Vec<Result<CollectorCommand, DecodeError>> {
+        let mut reader = FrameReader::new(Trickle { bytes, chunk });
+        let mut frames = Vec::new();
+        while let Some(frame) = reader.read_frame().unwrap() {
+            frames.push(frame.map(|(_, command)| command));
+        }
+
+        return frames;
+    }
+
+    #[tokio::test]
+    async fn test_codec_over_partial_writes() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut frames = FramedRead::new(server, CollectorCodec::new());
+
+        let bytes = encoded(&commands());
+        let writer = tokio::spawn(async move {
+            let mut client = client;
+            for chunk in bytes.chunks(5) {
+                client.write_all(chunk).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+
+        let mut received = Vec::new();
+        while let Some(frame) = frames.next().await {
+            received.push(frame.unwrap().unwrap().1);
+        }
+        writer.await.unwrap();
+        assert_eq!(received, commands());
+
+        let mut sink = FramedWrite::new(Vec::new(), CollectorCodec::new());
+        for command in commands() {
+            sink.send(command).await.unwrap();
+        }
+        let frames: Vec<_> = FramedRead::new(&sink.get_ref()[..], CollectorCodec::new()).collect().await;
+        assert_eq!(frames.len(), 2);
+    }
+
+    #[test]
+    fn test_frame_reader_skips_bad_frames() {
+        let mut bytes = encoded(&commands());
+        bytes[HEADER_SIZE] = b'[';
+
+        let frames = read_all(&bytes, 3);
+        assert!(matches!(frames[0], Err(DecodeError::CrcMismatch { .. })));
+        assert_eq!(frames[1], Ok(commands()[1].clone()));
+        assert_eq!(frames.len(), 2);
+
+        let mut reader = FrameReader::new(&bytes[..bytes.len() - 1]);
+        reader.read_frame().unwrap().unwrap().unwrap_err();
+        assert!(matches!(reader.read_frame(), Err(CodecError::Decode(DecodeError::Truncated { .. }))));
+    }
+
+    #[test]
+    fn test_max_payload_size() {
+        let bytes = encoded(&commands());
+        let mut reader = FrameReader::new(&bytes[..]).with_max_payload_size(10);
+        assert!(matches!(reader.read_frame(), Err(CodecError::Decode(DecodeError::PayloadTooLarge { max: 10, .. }))));
+
+        let mut codec = CollectorCodec::new().with_max_payload_size(10);
+        assert!(codec.encode(commands()[0].clone(), &mut BytesMut::new()).is_err());
+
+        let mut garbage = BytesMut::from(&b"not a frame at all"[..]);
+        assert!(matches!(CollectorCodec::new().decode(&mut garbage), Err(CodecError::Decode(DecodeError::BadMagic(_)))));
+    }
+
+    #[test]
+    fn test_codec_follows_peer_encoding() {
+        let mut collector = CollectorCodec::new().with_encoding(PayloadEncoding::Postcard);
+        let mut buffer = BytesMut::new();
+        collector.encode(commands()[1].clone(), &mut buffer).unwrap();
+
+        let mut server = CollectorCodec::new();
+        assert_eq!(server.decode(&mut buffer).unwrap().unwrap().unwrap().1, commands()[1]);
+        assert_eq!(server.encoding(), PayloadEncoding::Postcard);
+
+        server.encode(commands()[0].clone(), &mut buffer).unwrap();
+        assert_eq!(buffer.len(), encode(&commands()[0], PayloadEncoding::Postcard).len());
+    }
+
+    #[test]
+    fn test_signed_frames_between_codecs() {
+        let credentials = Credentials { collector_id: 2, key: Key::generate() };
+        let mut keys = KeyRing::new();
+        keys.insert(2, credentials.key.clone());
+        let mut collector = ServerCodec::new().with_credentials(credentials);
+        let mut server = CollectorCodec::new().with_keys(Arc::new(keys));
+
+        let mut buffer = BytesMut::from(&encoded(&commands()[..1])[..]);
+        collector.encode(commands()[1].clone(), &mut buffer).unwrap();
+        assert_eq!(server.peer(), None);
+        assert_eq!(server.decode(&mut buffer).unwrap().unwrap(), Err(DecodeError::Unsigned));
+        assert_eq!(server.decode(&mut buffer).unwrap().unwrap().unwrap().1, commands()[1]);
+        assert_eq!(server.peer(), Some(2));
+
+        server.encode(ServerCommand::NoWork, &mut buffer).unwrap();
+        assert_eq!(collector.decode(&mut buffer).unwrap().unwrap().unwrap().1, ServerCommand::NoWork);
+        // Unsigned replies are refused as well.
+        CollectorCodec::new().encode(ServerCommand::NoWork, &mut buffer).unwrap();
+        assert_eq!(collector.decode(&mut buffer).unwrap().unwrap(), Err(DecodeError::Unsigned));
+    }
+
+    proptest! {
+        #[test]
+        fn test_decoding_arbitrary_bytes_never_panics(bytes in proptest::collection::vec(any::<u8>(), 0..512), chunk in 1usize..64) {
+            let _ = crate::decode(&bytes);
+
+            let mut reader = FrameReader::new(Trickle { bytes: &bytes, chunk });
+            while let Ok(Some(_)) = reader.read_frame() {}
+
+            let mut codec = CollectorCodec::new();
+            let mut buffer = BytesMut::from(&bytes[..]);
+            while let Ok(Some(_)) = codec.decode(&mut buffer) {}
+            let _ = codec.decode_eof(&mut buffer);
+        }
+
+        #[test]
+        fn test_corrupted_frames_never_panic(index in 0usize..1024, byte in any::<u8>(), chunk in 1usize..64) {
+            let mut bytes = encoded(&commands());
+            let index = index % bytes.len();
+            bytes[index] = byte;
+
+            let mut reader = FrameReader::new(Trickle { bytes: &bytes, chunk });
+            while let Ok(Some(_)) = reader.read_frame() {}
+        }
+
+        #[test]
+        fn test_any_chunking_decodes_every_frame(chunk in 1usize..128) {
+            let frames = read_all(&encoded(&commands()), chunk);
+            prop_assert_eq!(frames, commands().into_iter().map(Ok).collect::<Vec<_>>());
+        }
+    }
+}
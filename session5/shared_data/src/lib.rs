@@ -1,9 +1,32 @@
+#![allow(clippy::needless_return)]
+
+mod auth;
+mod codec;
+
 use serde::{Deserialize, Serialize};
 use std::time::SystemTime;
+use thiserror::Error;
+
+use auth::{SIGNATURE_SIZE, SIGNED_FLAG};
 
-pub const DATA_COLLECTOR_ADDRESS: &'static str = "127.0.0.1:9004";
+pub use auth::{write_private, Credentials, Key, KeyRing, REPLAY_WINDOW};
+pub use codec::{CodecError, CollectorCodec, Frame, FrameCodec, FrameReader, ServerCodec, MAX_PAYLOAD_SIZE};
+
+pub const DATA_COLLECTOR_ADDRESS: &str = "127.0.0.1:9004";
+/// Longest interval between samples a collector accepts, one day.
+pub const MAX_INTERVAL_SECONDS: u64 = 24 * 60 * 60;
 const MAGIC_NUMBER: u16 = 1234;
-const VERSION_NUMBER: u16 = 1;
+pub const VERSION_V1: u16 = 1;
+pub const VERSION_V2: u16 = 2;
+pub const VERSION_V3: u16 = 3;
+/// Versions of the commands the server sends to collectors start here.
+pub const SERVER_VERSION_V1: u16 = 128;
+/// The high byte of the version field holds the payload encoding, so JSON frames from before
+/// encodings existed are still read.
+const ENCODING_SHIFT: u16 = 8;
+/// Magic number, version, timestamp and payload size.
+const HEADER_SIZE: usize = 12;
+const CRC_SIZE: usize = 4;
 
 fn unix_now() -> u32 {
     let time = SystemTime::now()
@@ -14,6 +37,87 @@ fn unix_now() -> u32 {
     return time as u32;
 }
 
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum DecodeError {
+    #[error("Bad magic number {0}, this is not a collector frame")]
+    BadMagic(u16),
+    #[error("Unsupported protocol version {0}")]
+    UnsupportedVersion(u16),
+    #[error("CRC mismatch, the frame says {expected:#010x} but the payload has {computed:#010x}")]
+    CrcMismatch { expected: u32, computed: u32 },
+    #[error("Truncated frame, {expected} bytes expected but only {available} available")]
+    Truncated { expected: usize, available: usize },
+    #[error("Cannot decode the payload: {0}")]
+    BadPayload(String),
+    #[error("The payload of {size} bytes is larger than the maximum of {max}")]
+    PayloadTooLarge { size: usize, max: usize },
+    #[error("Unsupported payload encoding {0}")]
+    UnsupportedEncoding(u8),
+    #[error("The frame is not signed")]
+    Unsigned,
+    #[error("Collector {0:032x} is not known")]
+    UnknownCollector(u128),
+    #[error("The signature does not match the frame")]
+    BadSignature,
+    #[error("The frame was sent at {sent_at}, too far from now to be trusted")]
+    Stale { sent_at: u32 },
+    #[error("The frame with nonce {nonce} was received before")]
+    Replayed { nonce: u64 },
+}
+
+/// How the command inside a frame is serialized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PayloadEncoding {
+    /// Readable, and what every collector understands.
+    #[default]
+    Json,
+    /// Compact binary, see <https://postcard.jamesmunns.com/wire-format>.
+    Postcard,
+}
+
+impl PayloadEncoding {
+    fn id(self) -> u8 {
+        return match self {
+            PayloadEncoding::Json => 0,
+            PayloadEncoding::Postcard => 1,
+        };
+    }
+
+    fn from_id(id: u8) -> Result<Self, DecodeError> {
+        return match id {
+            0 => Ok(PayloadEncoding::Json),
+            1 => Ok(PayloadEncoding::Postcard),
+            id => Err(DecodeError::UnsupportedEncoding(id)),
+        };
+    }
+
+    fn serialize<T: Serialize>(self, command: &T) -> Vec<u8> {
+        return match self {
+            PayloadEncoding::Json => serde_json::to_vec(command).unwrap(),
+            PayloadEncoding::Postcard => postcard::to_stdvec(command).unwrap(),
+        };
+    }
+
+    fn deserialize<'a, T: Deserialize<'a>>(self, payload: &'a [u8]) -> Result<T, DecodeError> {
+        return match self {
+            PayloadEncoding::Json => serde_json::from_slice(payload).map_err(|error| DecodeError::BadPayload(error.to_string())),
+            PayloadEncoding::Postcard => postcard::from_bytes(payload).map_err(|error| DecodeError::BadPayload(error.to_string())),
+        };
+    }
+}
+
+impl std::str::FromStr for PayloadEncoding {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        return match name.to_lowercase().as_str() {
+            "json" => Ok(PayloadEncoding::Json),
+            "postcard" => Ok(PayloadEncoding::Postcard),
+            _ => Err(format!("{name} is not an encoding, use json or postcard")),
+        };
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum CollectorCommandV1 {
     SubmitData {
@@ -24,69 +128,400 @@ pub enum CollectorCommandV1 {
     },
 }
 
-pub fn encode_v1(command: &CollectorCommandV1) -> Vec<u8> {
-    let json = serde_json::to_string(command).unwrap();
-    let json_bytes = json.as_bytes();
-    let crc = crc32fast::hash(json_bytes);
-    let payload_size = json_bytes.len() as u32;
+/// The second command set. Collectors that still speak V1 are converted with `From`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum CollectorCommandV2 {
+    SubmitData {
+        collector_id: u128,
+        total_memory: u64,
+        used_memory: u64,
+        /// Zero when sent by a V1 collector.
+        total_swap: u64,
+        used_swap: u64,
+        average_cpu_usage: f32,
+        /// Usage of each CPU, empty when sent by a V1 collector.
+        cpu_usage: Vec<f32>,
+    },
+}
+
+impl From<CollectorCommandV1> for CollectorCommandV2 {
+    fn from(command: CollectorCommandV1) -> Self {
+        return match command {
+            CollectorCommandV1::SubmitData { collector_id, total_memory, used_memory, average_cpu_usage } => {
+                CollectorCommandV2::SubmitData {
+                    collector_id,
+                    total_memory,
+                    used_memory,
+                    total_swap: 0,
+                    used_swap: 0,
+                    average_cpu_usage,
+                    cpu_usage: Vec::new(),
+                }
+            }
+        };
+    }
+}
+
+/// One reading of a collector.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Sample {
+    pub collector_id: u128,
+    pub total_memory: u64,
+    pub used_memory: u64,
+    pub total_swap: u64,
+    pub used_swap: u64,
+    pub average_cpu_usage: f32,
+    pub cpu_usage: Vec<f32>,
+}
+
+/// The third command set, which the server replies to with `ServerCommand`s.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum CollectorCommandV3 {
+    /// Acknowledged with an `Ack` of the same `seq` once stored. `seq` is 0 for samples
+    /// converted from older command sets, which are never acknowledged.
+    SubmitData { seq: u64, sample: Sample },
+    /// Answered with a `Task` or `NoWork`.
+    RequestWork { collector_id: u128 },
+    Ping { nonce: u64 },
+    Pong { nonce: u64 },
+}
+
+impl From<CollectorCommandV2> for Sample {
+    fn from(command: CollectorCommandV2) -> Self {
+        return match command {
+            CollectorCommandV2::SubmitData {
+                collector_id,
+                total_memory,
+                used_memory,
+                total_swap,
+                used_swap,
+                average_cpu_usage,
+                cpu_usage,
+            } => Sample { collector_id, total_memory, used_memory, total_swap, used_swap, average_cpu_usage, cpu_usage },
+        };
+    }
+}
+
+impl From<CollectorCommandV2> for CollectorCommandV3 {
+    fn from(command: CollectorCommandV2) -> Self {
+        return CollectorCommandV3::SubmitData { seq: 0, sample: command.into() };
+    }
+}
+
+/// Work the server hands out in reply to `RequestWork`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Task {
+    /// Submit a sample right away instead of at the next interval.
+    SampleNow,
+}
+
+/// Commands from the server to a collector, in the same frames as collector commands.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum ServerCommand {
+    /// The sample with this `seq` is stored and can be deleted by the collector.
+    Ack { seq: u64 },
+    Task { task_id: u64, task: Task },
+    NoWork,
+    Ping { nonce: u64 },
+    Pong { nonce: u64 },
+    /// Sample every `seconds` from now on, at most `MAX_INTERVAL_SECONDS`.
+    SetInterval { seconds: u64 },
+}
+
+/// A decoded command in the version it was sent with.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CollectorCommand {
+    V1(CollectorCommandV1),
+    V2(CollectorCommandV2),
+    V3(CollectorCommandV3),
+}
+
+impl CollectorCommand {
+    pub fn version(&self) -> u16 {
+        return match self {
+            CollectorCommand::V1(_) => VERSION_V1,
+            CollectorCommand::V2(_) => VERSION_V2,
+            CollectorCommand::V3(_) => VERSION_V3,
+        };
+    }
+
+    /// The command in the newest command set.
+    pub fn into_latest(self) -> CollectorCommandV3 {
+        return match self {
+            CollectorCommand::V1(command) => CollectorCommandV2::from(command).into(),
+            CollectorCommand::V2(command) => command.into(),
+            CollectorCommand::V3(command) => command,
+        };
+    }
+}
+
+/// Anything sent in frames, in either direction.
+pub trait WireMessage: Sized {
+    /// The whole frame.
+    fn encode(&self, encoding: PayloadEncoding) -> Vec<u8>;
+
+    fn decode_payload(version: u16, encoding: PayloadEncoding, payload: &[u8]) -> Result<Self, DecodeError>;
+}
+
+impl WireMessage for CollectorCommand {
+    fn encode(&self, encoding: PayloadEncoding) -> Vec<u8> {
+        return match self {
+            CollectorCommand::V1(command) => encode_frame(VERSION_V1, command, encoding),
+            CollectorCommand::V2(command) => encode_frame(VERSION_V2, command, encoding),
+            CollectorCommand::V3(command) => encode_frame(VERSION_V3, command, encoding),
+        };
+    }
+
+    fn decode_payload(version: u16, encoding: PayloadEncoding, payload: &[u8]) -> Result<Self, DecodeError> {
+        return match version {
+            VERSION_V1 => Ok(CollectorCommand::V1(encoding.deserialize(payload)?)),
+            VERSION_V2 => Ok(CollectorCommand::V2(encoding.deserialize(payload)?)),
+            VERSION_V3 => Ok(CollectorCommand::V3(encoding.deserialize(payload)?)),
+            version => Err(DecodeError::UnsupportedVersion(version)),
+        };
+    }
+}
+
+impl WireMessage for ServerCommand {
+    fn encode(&self, encoding: PayloadEncoding) -> Vec<u8> {
+        return encode_frame(SERVER_VERSION_V1, self, encoding);
+    }
+
+    fn decode_payload(version: u16, encoding: PayloadEncoding, payload: &[u8]) -> Result<Self, DecodeError> {
+        return match version {
+            SERVER_VERSION_V1 => encoding.deserialize(payload),
+            version => Err(DecodeError::UnsupportedVersion(version)),
+        };
+    }
+}
+
+fn encode_frame<T: Serialize>(version: u16, command: &T, encoding: PayloadEncoding) -> Vec<u8> {
+    let payload = encoding.serialize(command);
+    let crc = crc32fast::hash(&payload);
+    let payload_size = payload.len() as u32;
     let timestamp = unix_now();
+    let version = (encoding.id() as u16) << ENCODING_SHIFT | version;
 
-    let mut result = Vec::with_capacity(140);
+    let mut result = Vec::with_capacity(HEADER_SIZE + payload.len() + CRC_SIZE);
     result.extend_from_slice(&MAGIC_NUMBER.to_be_bytes());
-    result.extend_from_slice(&VERSION_NUMBER.to_be_bytes());
+    result.extend_from_slice(&version.to_be_bytes());
     result.extend_from_slice(&timestamp.to_be_bytes());
     result.extend_from_slice(&payload_size.to_be_bytes());
-    result.extend_from_slice(json_bytes);
+    result.extend_from_slice(&payload);
     result.extend_from_slice(&crc.to_be_bytes());
 
     return result;
 }
 
-pub fn decode_v1(bytes: &[u8]) -> (u32, CollectorCommandV1) {
-    let magic_number = u16::from_be_bytes([bytes[0], bytes[1]]);
-    let version_number = u16::from_be_bytes([bytes[2], bytes[3]]);
-    let timestamp = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
-    let payload_size = u32::from_be_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]);
-    let payload = &bytes[12..12 + payload_size as usize];
-    let crc = u32::from_be_bytes([
-        bytes[12 + payload_size as usize],
-        bytes[13 + payload_size as usize],
-        bytes[14 + payload_size as usize],
-        bytes[15 + payload_size as usize],
-    ]);
+pub fn encode_v1(command: &CollectorCommandV1) -> Vec<u8> {
+    return encode_frame(VERSION_V1, command, PayloadEncoding::Json);
+}
+
+pub fn encode_v2(command: &CollectorCommandV2) -> Vec<u8> {
+    return encode_frame(VERSION_V2, command, PayloadEncoding::Json);
+}
+
+/// Encodes a command in its own version with the given payload encoding.
+pub fn encode<T: WireMessage>(message: &T, encoding: PayloadEncoding) -> Vec<u8> {
+    return message.encode(encoding);
+}
+
+/// The fixed-size start of every frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FrameHeader {
+    version: u16,
+    encoding: u8,
+    signed: bool,
+    timestamp: u32,
+    payload_size: usize,
+}
+
+impl FrameHeader {
+    /// `Ok(None)` until all of the header is available. Only the magic number is checked, so
+    /// frames of unsupported versions can still be skipped.
+    pub(crate) fn parse(bytes: &[u8]) -> Result<Option<FrameHeader>, DecodeError> {
+        let Some(header) = bytes.get(..HEADER_SIZE) else {
+            return Ok(None);
+        };
+
+        let magic_number = u16::from_be_bytes([header[0], header[1]]);
+        if magic_number != MAGIC_NUMBER {
+            return Err(DecodeError::BadMagic(magic_number));
+        }
+
+        return Ok(Some(FrameHeader {
+            version: u16::from_be_bytes([0, header[3]]),
+            encoding: header[2] & !SIGNED_FLAG,
+            signed: header[2] & SIGNED_FLAG != 0,
+            timestamp: u32::from_be_bytes([header[4], header[5], header[6], header[7]]),
+            payload_size: u32::from_be_bytes([header[8], header[9], header[10], header[11]]) as usize,
+        }));
+    }
+
+    /// Including the signature of a signed frame.
+    pub(crate) fn frame_size(&self) -> usize {
+        let signature_size = if self.signed { SIGNATURE_SIZE } else { 0 };
+        return HEADER_SIZE + self.payload_size + CRC_SIZE + signature_size;
+    }
+
+    pub(crate) fn is_signed(&self) -> bool {
+        return self.signed;
+    }
+
+    pub(crate) fn encoding(&self) -> Result<PayloadEncoding, DecodeError> {
+        return PayloadEncoding::from_id(self.encoding);
+    }
+
+    pub(crate) fn check_size(&self, max_payload_size: usize) -> Result<(), DecodeError> {
+        if self.payload_size > max_payload_size {
+            return Err(DecodeError::PayloadTooLarge { size: self.payload_size, max: max_payload_size });
+        }
+
+        return Ok(());
+    }
+
+    /// Checks the CRC of the complete frame and decodes its message.
+    pub(crate) fn decode<T: WireMessage>(&self, frame: &[u8]) -> Result<(u32, T), DecodeError> {
+        if frame.len() < self.frame_size() {
+            return Err(DecodeError::Truncated { expected: self.frame_size(), available: frame.len() });
+        }
+
+        let payload = &frame[HEADER_SIZE..HEADER_SIZE + self.payload_size];
+        let crc_bytes = &frame[HEADER_SIZE + self.payload_size..HEADER_SIZE + self.payload_size + CRC_SIZE];
+        let crc = u32::from_be_bytes([crc_bytes[0], crc_bytes[1], crc_bytes[2], crc_bytes[3]]);
+
+        let computed_crc = crc32fast::hash(payload);
+        if crc != computed_crc {
+            return Err(DecodeError::CrcMismatch { expected: crc, computed: computed_crc });
+        }
+
+        let message = T::decode_payload(self.version, self.encoding()?, payload)?;
 
-    // Verify the magic number
-    assert_eq!(magic_number, MAGIC_NUMBER);
+        return Ok((self.timestamp, message));
+    }
+}
 
-    // Verify the version number
-    assert_eq!(version_number, VERSION_NUMBER);
+/// Decodes a frame of any supported version, returning its timestamp and command. Bytes after
+/// the frame are ignored, see `CollectorCodec` and `FrameReader` for streams of frames.
+pub fn decode(bytes: &[u8]) -> Result<(u32, CollectorCommand), DecodeError> {
+    return decode_message(bytes);
+}
 
-    // Verify the CRC
-    let computed_crc = crc32fast::hash(payload);
-    assert_eq!(crc, computed_crc);
+/// `decode` for either direction.
+pub fn decode_message<T: WireMessage>(bytes: &[u8]) -> Result<(u32, T), DecodeError> {
+    let Some(header) = FrameHeader::parse(bytes)? else {
+        return Err(DecodeError::Truncated { expected: HEADER_SIZE, available: bytes.len() });
+    };
+
+    return header.decode(bytes);
+}
 
-    // Decode the payload
-    return (timestamp, serde_json::from_slice(payload).unwrap());
+/// Decodes a V1 frame only, other versions are `UnsupportedVersion`.
+pub fn decode_v1(bytes: &[u8]) -> Result<(u32, CollectorCommandV1), DecodeError> {
+    return match decode(bytes)? {
+        (timestamp, CollectorCommand::V1(command)) => Ok((timestamp, command)),
+        (_, command) => Err(DecodeError::UnsupportedVersion(command.version())),
+    };
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[test]
-    fn test_encode_decode() {
-        let command = CollectorCommandV1::SubmitData {
+    fn command() -> CollectorCommandV1 {
+        return CollectorCommandV1::SubmitData {
             collector_id: 1234,
             total_memory: 100,
             used_memory: 50,
             average_cpu_usage: 0.5,
         };
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let command = command();
 
         let encoded = encode_v1(&command);
 
-        let (timestamp, decoded) = decode_v1(&encoded);
+        let (timestamp, decoded) = decode_v1(&encoded).unwrap();
 
         assert_eq!(decoded, command);
         assert!(timestamp > 0);
     }
+
+    #[test]
+    fn test_decode_dispatches_on_version() {
+        let (_, decoded) = decode(&encode_v1(&command())).unwrap();
+        assert_eq!(decoded, CollectorCommand::V1(command()));
+        assert_eq!(decoded.into_latest(), CollectorCommandV3::from(CollectorCommandV2::from(command())));
+
+        let v2 = CollectorCommandV2::SubmitData {
+            collector_id: 1234,
+            total_memory: 100,
+            used_memory: 50,
+            total_swap: 10,
+            used_swap: 1,
+            average_cpu_usage: 0.5,
+            cpu_usage: vec![0.25, 0.75],
+        };
+        let encoded = encode_v2(&v2);
+        assert_eq!(decode(&encoded).unwrap().1, CollectorCommand::V2(v2));
+        assert_eq!(decode_v1(&encoded), Err(DecodeError::UnsupportedVersion(VERSION_V2)));
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let encoded = encode_v1(&command());
+        let with = |index: usize, byte: u8| {
+            let mut bytes = encoded.clone();
+            bytes[index] = byte;
+            return bytes;
+        };
+
+        assert_eq!(decode(&with(0, 0)), Err(DecodeError::BadMagic(210)));
+        assert_eq!(decode(&with(3, 9)), Err(DecodeError::UnsupportedVersion(9)));
+        assert!(matches!(decode(&with(HEADER_SIZE, b'[')), Err(DecodeError::CrcMismatch { .. })));
+        assert_eq!(decode(&encoded[..5]), Err(DecodeError::Truncated { expected: HEADER_SIZE, available: 5 }));
+        assert_eq!(
+            decode(&encoded[..encoded.len() - 1]),
+            Err(DecodeError::Truncated { expected: encoded.len(), available: encoded.len() - 1 })
+        );
+
+        assert_eq!(decode(&with(2, 7)), Err(DecodeError::UnsupportedEncoding(7)));
+
+        let mut not_a_command = encode_frame(VERSION_V1, &"hello", PayloadEncoding::Json);
+        assert!(matches!(decode(&not_a_command), Err(DecodeError::BadPayload(_))));
+        not_a_command.truncate(0);
+        assert!(matches!(decode(&not_a_command), Err(DecodeError::Truncated { .. })));
+    }
+
+    #[test]
+    fn test_server_commands_share_the_framing() {
+        let submit = CollectorCommand::V3(CollectorCommandV3::SubmitData { seq: 42, sample: CollectorCommandV2::from(command()).into() });
+        let encoded = encode(&submit, PayloadEncoding::Postcard);
+        assert_eq!(decode(&encoded).unwrap().1, submit);
+
+        for reply in [ServerCommand::Ack { seq: 42 }, ServerCommand::Task { task_id: 1, task: Task::SampleNow }, ServerCommand::SetInterval { seconds: 5 }] {
+            let encoded = encode(&reply, PayloadEncoding::Json);
+            assert_eq!(decode_message::<ServerCommand>(&encoded).unwrap().1, reply);
+            assert_eq!(decode(&encoded), Err(DecodeError::UnsupportedVersion(SERVER_VERSION_V1)));
+        }
+        assert_eq!(decode_message::<ServerCommand>(&encode_v1(&command())), Err(DecodeError::UnsupportedVersion(VERSION_V1)));
+    }
+
+    #[test]
+    fn test_postcard_encoding() {
+        let v1 = CollectorCommand::V1(command());
+        let json = encode(&v1, PayloadEncoding::Json);
+        let postcard = encode(&v1, PayloadEncoding::Postcard);
+
+        assert_eq!(json.len(), encode_v1(&command()).len());
+        assert!(postcard.len() < json.len() / 2);
+        assert_eq!(decode(&postcard).unwrap().1, v1);
+        assert_eq!("Postcard".parse(), Ok(PayloadEncoding::Postcard));
+        assert!("xml".parse::<PayloadEncoding>().is_err());
+
+        let not_a_command = encode_frame(VERSION_V1, &"hello", PayloadEncoding::Postcard);
+        assert!(matches!(decode(&not_a_command), Err(DecodeError::BadPayload(_))));
+    }
 }